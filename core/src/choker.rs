@@ -0,0 +1,211 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{collections::HashSet, net::SocketAddr, time::Duration};
+
+/// How often the peers to unchoke are picked again
+pub const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+/// Rounds an optimistic unchoke lasts, 30 seconds with the default interval
+const OPTIMISTIC_ROUNDS: u64 = 3;
+/// Peers connected for less than this are new, and more likely to be unchoked optimistically
+const NEW_PEER: Duration = Duration::from_secs(60);
+/// How much more likely new peers are to be picked as optimistic unchoke
+const NEW_PEER_WEIGHT: u32 = 3;
+
+/// What the choker knows about a connected peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChokeCandidate {
+    pub addr: SocketAddr,
+    /// Whether the peer wants something from us
+    pub interested: bool,
+    /// Payload bytes per second the peer sends us
+    pub download_rate: u64,
+    /// Payload bytes per second we send the peer
+    pub upload_rate: u64,
+    pub connected_for: Duration,
+}
+
+/// Decides which peers are allowed to download from us, every [`CHOKE_INTERVAL`] for each torrent
+pub trait Choker: Send {
+    /// Returns the peers to unchoke, every other one is choked.
+    ///
+    /// `seeding` is set once we have nothing left to download from anyone.
+    fn unchoke(&mut self, peers: &[ChokeCandidate], seeding: bool) -> HashSet<SocketAddr>;
+}
+
+/// The standard tit-for-tat choker.
+///
+/// The interested peers we download the fastest from are unchoked, or the ones we upload the
+/// fastest to while seeding. One more peer is unchoked at random and kept for 30 seconds, so new
+/// peers get a chance to show how fast they are.
+#[derive(Debug)]
+pub struct TitForTat {
+    slots: usize,
+    round: u64,
+    optimistic: Option<SocketAddr>,
+    rng: StdRng,
+}
+
+impl TitForTat {
+    /// Creates a choker unchoking the `slots` best peers, plus the optimistic one
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots,
+            round: 0,
+            optimistic: None,
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    /// Picks a peer among `candidates` at random, new ones being more likely
+    fn pick_optimistic(&mut self, candidates: &[&ChokeCandidate]) -> Option<SocketAddr> {
+        let weight = |peer: &ChokeCandidate| {
+            if peer.connected_for < NEW_PEER {
+                NEW_PEER_WEIGHT
+            } else {
+                1
+            }
+        };
+        let total: u32 = candidates.iter().map(|peer| weight(peer)).sum();
+        if total == 0 {
+            return None;
+        }
+
+        let mut target = self.rng.gen_range(0..total);
+        for peer in candidates {
+            if target < weight(peer) {
+                return Some(peer.addr);
+            }
+            target -= weight(peer);
+        }
+        None
+    }
+}
+
+impl Choker for TitForTat {
+    fn unchoke(&mut self, peers: &[ChokeCandidate], seeding: bool) -> HashSet<SocketAddr> {
+        let rotate = self.round.is_multiple_of(OPTIMISTIC_ROUNDS);
+        self.round += 1;
+
+        let mut interested: Vec<&ChokeCandidate> =
+            peers.iter().filter(|peer| peer.interested).collect();
+        interested.sort_by_key(|peer| {
+            std::cmp::Reverse(if seeding {
+                peer.upload_rate
+            } else {
+                peer.download_rate
+            })
+        });
+
+        let split = self.slots.min(interested.len());
+        let (best, rest) = interested.split_at(split);
+        let mut unchoked: HashSet<SocketAddr> = best.iter().map(|peer| peer.addr).collect();
+
+        // The optimistic unchoke is replaced when its time is up, or if it doesn't need to be optimistic anymore
+        let kept = self
+            .optimistic
+            .filter(|addr| !rotate && rest.iter().any(|peer| peer.addr == *addr));
+        self.optimistic = kept.or_else(|| self.pick_optimistic(rest));
+        unchoked.extend(self.optimistic);
+
+        unchoked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16, interested: bool, download_rate: u64, upload_rate: u64) -> ChokeCandidate {
+        ChokeCandidate {
+            addr: SocketAddr::from(([10, 0, 0, 1], port)),
+            interested,
+            download_rate,
+            upload_rate,
+            connected_for: NEW_PEER * 2,
+        }
+    }
+
+    fn unchoked(choker: &mut TitForTat, peers: &[ChokeCandidate], seeding: bool) -> Vec<u16> {
+        let mut ports: Vec<u16> = choker
+            .unchoke(peers, seeding)
+            .into_iter()
+            .map(|addr| addr.port())
+            .collect();
+        ports.sort();
+        ports
+    }
+
+    #[test]
+    fn fastest_peers_and_one_optimistic() {
+        let peers = vec![
+            peer(1, true, 100, 0),
+            peer(2, true, 500, 10),
+            peer(3, false, 900, 20),
+            peer(4, true, 300, 30),
+            peer(5, true, 0, 40),
+        ];
+        let mut choker = TitForTat::new(2);
+
+        // 3 isn't interested, so it stays choked however fast it is
+        let ports = unchoked(&mut choker, &peers, false);
+        let optimistic = choker.optimistic.unwrap().port();
+        assert!([1, 5].contains(&optimistic));
+        let mut expected = vec![2, 4, optimistic];
+        expected.sort();
+        assert_eq!(ports, expected);
+
+        // While seeding it's about how fast we upload
+        let ports = unchoked(&mut choker, &peers, true);
+        assert!(ports.contains(&4) && ports.contains(&5));
+        assert_eq!(ports.len(), 3);
+        assert!(!ports.contains(&3));
+    }
+
+    #[test]
+    fn nobody_interested() {
+        let peers = vec![peer(1, false, 100, 0), peer(2, false, 0, 0)];
+        let mut choker = TitForTat::new(4);
+        assert!(choker.unchoke(&peers, false).is_empty());
+        assert!(choker.unchoke(&[], true).is_empty());
+    }
+
+    #[test]
+    fn optimistic_unchoke_rotates() {
+        let peers: Vec<ChokeCandidate> = (0..10).map(|port| peer(port, true, 0, 0)).collect();
+        let mut choker = TitForTat::new(0);
+
+        let first = choker.unchoke(&peers, false);
+        assert_eq!(first.len(), 1);
+        for _ in 1..OPTIMISTIC_ROUNDS {
+            assert_eq!(choker.unchoke(&peers, false), first);
+        }
+
+        // Chosen again among everyone once its time is up, until it disconnects
+        choker.unchoke(&peers, false);
+        let optimistic = choker.optimistic.unwrap();
+        let remaining: Vec<ChokeCandidate> = peers
+            .into_iter()
+            .filter(|peer| peer.addr != optimistic)
+            .collect();
+        let next = choker.unchoke(&remaining, false);
+        assert_eq!(next.len(), 1);
+        assert!(!next.contains(&optimistic));
+    }
+
+    #[test]
+    fn new_peers_are_favoured() {
+        let mut new = peer(1, true, 0, 0);
+        new.connected_for = Duration::from_secs(5);
+        let peers = [new, peer(2, true, 0, 0)];
+        let mut choker = TitForTat::new(0);
+
+        let mut picked_new = 0;
+        for _ in 0..1000 {
+            choker.round = 0;
+            if choker.unchoke(&peers, false).contains(&peers[0].addr) {
+                picked_new += 1;
+            }
+        }
+        // Three times as likely, so 750 on average
+        assert!((650..850).contains(&picked_new), "{}", picked_new);
+    }
+}
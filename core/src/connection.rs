@@ -1,47 +1,1105 @@
-use crate::{utp::UtpStream, Handshake, Status, Wire};
-use color_eyre::eyre::Result;
+use crate::{
+    limit::RateLimits,
+    pipeline::{Block, Pipeline},
+    stats::TransferStats,
+    utp::{UtpSocket, UtpStream},
+    EncryptionPolicy, ExtendedHandshake, Handshake, Message, MseStream, PeerInfo, Status, Wire,
+};
+use bitvec::{order::Msb0, slice::BitSlice, vec::BitVec};
+use bytes::{BufMut, Bytes, BytesMut};
+use color_eyre::eyre::{eyre, Result};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    future::pending,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+use thiserror::Error;
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    net::{TcpStream, ToSocketAddrs},
+    net::{lookup_host, TcpStream, ToSocketAddrs},
+    time::{sleep_until, Instant},
 };
+use tracing::debug;
+
+/// Fast extension messages, see [BEP 6](https://www.bittorrent.org/beps/bep_0006.html)
+const HAVE_ALL: u8 = 0x0E;
+const HAVE_NONE: u8 = 0x0F;
+const REJECT_REQUEST: u8 = 0x10;
+/// Most pieces a torrent can have, its metadata holds a hash for each of them and is at most 16 MiB
+const MAX_PIECE_COUNT: usize = (16 << 20) / 20;
+/// Reserved handshake bits we set: the extension protocol and the fast extension
+const RESERVED: [u8; 8] = [0, 0, 0, 0, 0, 0x10, 0, 0x04];
+
+#[derive(Debug, Error)]
+pub enum ConnectionError {
+    /// The remote peer didn't send anything for too long
+    #[error("Peer was silent for more than {0:?}")]
+    Timeout(Duration),
+    /// A bitfield was received after other messages
+    #[error("Peer sent a bitfield after the first message")]
+    UnexpectedBitfield,
+    /// The bitfield has the wrong length or spare bits set
+    #[error("Peer sent an invalid bitfield")]
+    InvalidBitfield,
+    #[error("Peer sent an invalid piece index {0}")]
+    InvalidPieceIndex(u32),
+}
+
+/// Something the remote peer told us
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// The peer choked us, our outstanding requests were cancelled
+    Choked {
+        dropped: Vec<Block>,
+    },
+    Unchoked,
+    Interested,
+    NotInterested,
+    /// The peer has a new piece
+    Have(u32),
+    /// The peer told us all the pieces it has, available through [`Connection::bitfield`]
+    Bitfield,
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// A block we requested
+    Block {
+        index: u32,
+        begin: u32,
+        data: Bytes,
+    },
+    /// The peer won't answer one of our requests
+    Rejected(Block),
+    /// Requests that went unanswered for too long and were cancelled
+    TimedOut(Vec<Block>),
+    Port(u16),
+    Extended {
+        id: u8,
+        payload: Bytes,
+    },
+}
 
 pub struct Connection<S> {
     status: Status,
     wire: Wire<S>,
     fast: bool,
+    extension_protocol: bool,
+    /// Message ids the peer assigned to the extensions it supports
+    extensions: BTreeMap<String, u8>,
+    metadata_size: Option<u32>,
+    /// Client name and version from the extended handshake
+    client: Option<String>,
+    /// The peer won't download anything from us
+    upload_only: bool,
+    /// Bytes of blocks we didn't ask for, or cancelled
+    wasted_bytes: u64,
+    /// Cancels for the requests the peer dropped or that timed out, still to be sent
+    cancels: VecDeque<Block>,
+    /// The event the cancels go with, held back until they're sent so it isn't lost if
+    /// [`Connection::next_event`] is dropped halfway
+    pending: Option<ConnectionEvent>,
+    encrypted: bool,
+    peer_id: [u8; 20],
+    bitfield: BitVec<u8, Msb0>,
+    piece_count: Option<usize>,
+    /// A have all was received before the number of pieces was known
+    has_all: bool,
+    /// Bitfields are only allowed as the first message
+    received_message: bool,
+    pipeline: Pipeline,
+    keep_alive_interval: Duration,
+    timeout: Duration,
+    last_sent: Instant,
+    last_received: Instant,
+    stats: Arc<TransferStats>,
+    limits: Arc<RateLimits>,
 }
 
-pub struct ConnectionBuilder;
+pub struct ConnectionBuilder {
+    encryption: EncryptionPolicy,
+    keep_alive_interval: Duration,
+    timeout: Duration,
+}
 
 impl ConnectionBuilder {
     pub const fn new() -> Self {
-        ConnectionBuilder {}
+        ConnectionBuilder {
+            encryption: EncryptionPolicy::PreferEncrypted,
+            keep_alive_interval: Duration::from_secs(120),
+            timeout: Duration::from_secs(180),
+        }
+    }
+
+    /// Sets how message stream encryption is negotiated
+    pub fn encryption(&mut self, policy: EncryptionPolicy) -> &mut Self {
+        self.encryption = policy;
+        self
+    }
+
+    /// Sets how long to wait before sending a keep-alive when we have nothing else to say
+    pub fn keep_alive_interval(&mut self, interval: Duration) -> &mut Self {
+        self.keep_alive_interval = interval;
+        self
+    }
+
+    /// Sets how long a peer can stay silent before it's dropped
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
     }
 
     pub async fn connect_tcp<A: ToSocketAddrs>(
+        &self,
         addr: A,
         info_hash: [u8; 20],
         peer_id: [u8; 20],
-    ) -> Result<Connection<TcpStream>> {
-        let handshake = Handshake::new([0, 0, 0, 0, 0, 0x10, 0, 0], info_hash, peer_id);
+    ) -> Result<Connection<MseStream<TcpStream>>> {
+        let handshake = Handshake::new(RESERVED, info_hash, peer_id);
+        let addr = lookup_host(addr)
+            .await?
+            .next()
+            .ok_or_else(|| eyre!("Couldn't resolve peer address"))?;
+
         let stream = TcpStream::connect(addr).await?;
+        let stream = match MseStream::initiate(stream, info_hash, self.encryption).await {
+            Ok(stream) => stream,
+            // Peers that don't support encryption usually just drop the connection, so we try again in plaintext
+            Err(error) if self.encryption == EncryptionPolicy::PreferEncrypted => {
+                debug!("Encrypted handshake with {} failed: {}", addr, error);
+                MseStream::plaintext(TcpStream::connect(addr).await?)
+            }
+            Err(error) => return Err(error.into()),
+        };
+        let encrypted = stream.is_encrypted();
+        let (peer_info, wire) = Wire::handshake(handshake, stream).await?;
+
+        Ok(self.connection(peer_info, wire, encrypted))
+    }
+
+    pub async fn connect_utp(
+        &self,
+        socket: &UtpSocket,
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+    ) -> Result<Connection<MseStream<UtpStream>>> {
+        let handshake = Handshake::new(RESERVED, info_hash, peer_id);
+
+        let stream = socket.connect(addr).await?;
+        let stream = match MseStream::initiate(stream, info_hash, self.encryption).await {
+            Ok(stream) => stream,
+            Err(error) if self.encryption == EncryptionPolicy::PreferEncrypted => {
+                debug!("Encrypted handshake with {} failed: {}", addr, error);
+                MseStream::plaintext(socket.connect(addr).await?)
+            }
+            Err(error) => return Err(error.into()),
+        };
+        let encrypted = stream.is_encrypted();
         let (peer_info, wire) = Wire::handshake(handshake, stream).await?;
 
-        Ok(Connection {
+        Ok(self.connection(peer_info, wire, encrypted))
+    }
+
+    /// Handshakes an incoming connection, either tcp or utp, for one of the torrents in `info_hashes`
+    pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: S,
+        info_hashes: &[[u8; 20]],
+        peer_id: [u8; 20],
+    ) -> Result<([u8; 20], Connection<MseStream<S>>)> {
+        let (stream, skey) = MseStream::accept(stream, info_hashes, self.encryption).await?;
+        let encrypted = stream.is_encrypted();
+        // An encrypted handshake already named the torrent, the plaintext one must be for the same
+        let info_hashes = match &skey {
+            Some(skey) => std::slice::from_ref(skey),
+            None => info_hashes,
+        };
+        let (peer_info, info_hash, wire) =
+            Wire::accept(RESERVED, peer_id, info_hashes, stream).await?;
+
+        Ok((info_hash, self.connection(peer_info, wire, encrypted)))
+    }
+
+    fn connection<S>(
+        &self,
+        peer_info: PeerInfo,
+        mut wire: Wire<S>,
+        encrypted: bool,
+    ) -> Connection<S>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let now = Instant::now();
+        let stats = Arc::new(TransferStats::new());
+        wire.add_stats(stats.clone());
+        let limits = Arc::new(RateLimits::default());
+        wire.add_limits(limits.clone());
+
+        Connection {
             status: Status::new(),
             wire,
             fast: peer_info.fast_extension,
-        })
+            extension_protocol: peer_info.extension_protocol,
+            extensions: BTreeMap::new(),
+            metadata_size: None,
+            upload_only: false,
+            wasted_bytes: 0,
+            cancels: VecDeque::new(),
+            pending: None,
+            client: None,
+            encrypted,
+            peer_id: peer_info.peer_id,
+            bitfield: BitVec::EMPTY,
+            piece_count: None,
+            has_all: false,
+            received_message: false,
+            pipeline: Pipeline::new(),
+            keep_alive_interval: self.keep_alive_interval,
+            timeout: self.timeout,
+            last_sent: now,
+            last_received: now,
+            stats,
+            limits,
+        }
     }
+}
 
-    pub async fn connect_utp<A: ToSocketAddrs>(
-        addr: A,
-        info_hash: [u8; 20],
-        peer_id: [u8; 20],
-    ) -> Result<Connection<UtpStream>> {
-        let handshake = Handshake::new([0, 0, 0, 0, 0, 0x10, 0, 0], info_hash, peer_id);
-        todo!()
+impl Default for ConnectionBuilder {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {}
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    pub const fn status(&self) -> &Status {
+        &self.status
+    }
+
+    pub const fn peer_id(&self) -> [u8; 20] {
+        self.peer_id
+    }
+
+    /// Whether the peer supports the fast extension
+    pub const fn is_fast(&self) -> bool {
+        self.fast
+    }
+
+    /// Whether the peer supports the extension protocol
+    pub const fn is_extended(&self) -> bool {
+        self.extension_protocol
+    }
+
+    /// The id the peer wants for messages of an extension, `None` if it doesn't support it
+    pub fn extension(&self, name: &str) -> Option<u8> {
+        self.extensions.get(name).copied()
+    }
+
+    /// Name and version of the client, as told by the peer in its extended handshake
+    pub fn client(&self) -> Option<&str> {
+        self.client.as_deref()
+    }
+
+    /// Whether the peer said it won't download anything, because it has every piece it wants
+    pub const fn is_upload_only(&self) -> bool {
+        self.upload_only
+    }
+
+    /// Bytes of the blocks that were dropped because they weren't requested, or were cancelled
+    pub const fn wasted_bytes(&self) -> u64 {
+        self.wasted_bytes
+    }
+
+    /// Whether message stream encryption was negotiated
+    pub const fn is_encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    /// Bytes sent and received over this connection
+    pub const fn stats(&self) -> &Arc<TransferStats> {
+        &self.stats
+    }
+
+    /// Counts the traffic of this connection in `stats` too, for totals across connections
+    pub fn add_stats(&mut self, stats: Arc<TransferStats>) {
+        self.wire.add_stats(stats);
+    }
+
+    /// Limits of this connection alone, unlimited until they are changed
+    pub const fn limits(&self) -> &Arc<RateLimits> {
+        &self.limits
+    }
+
+    /// Throttles this connection with `limits` too, for limits shared with other connections
+    pub fn add_limits(&mut self, limits: Arc<RateLimits>) {
+        self.wire.add_limits(limits);
+    }
+
+    /// Size of the info dictionary, as told by the peer in its extended handshake
+    pub const fn metadata_size(&self) -> Option<u32> {
+        self.metadata_size
+    }
+
+    /// The pieces the remote peer has
+    pub fn bitfield(&self) -> &BitSlice<u8, Msb0> {
+        &self.bitfield
+    }
+
+    pub fn has_piece(&self, index: u32) -> bool {
+        self.has_all || self.bitfield.get(index as usize).is_some_and(|bit| *bit)
+    }
+
+    /// Sets the number of pieces in the torrent, validating what the peer sent so far.
+    ///
+    /// Until this is called the peer bitfield grows with every piece it announces.
+    pub fn set_piece_count(&mut self, piece_count: usize) -> Result<()> {
+        if self.bitfield[piece_count.min(self.bitfield.len())..].any() {
+            return Err(ConnectionError::InvalidBitfield.into());
+        }
+
+        self.bitfield.resize(piece_count, self.has_all);
+        self.piece_count = Some(piece_count);
+        self.has_all = false;
+
+        Ok(())
+    }
+
+    pub async fn choke(&mut self) -> Result<()> {
+        if !self.status.am_choking {
+            self.status.am_choking = true;
+            self.send(Message::choke()).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn unchoke(&mut self) -> Result<()> {
+        if self.status.am_choking {
+            self.status.am_choking = false;
+            self.send(Message::unchoke()).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn interested(&mut self) -> Result<()> {
+        if !self.status.am_interested {
+            self.status.am_interested = true;
+            self.send(Message::interested()).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn not_interested(&mut self) -> Result<()> {
+        if self.status.am_interested {
+            self.status.am_interested = false;
+            self.send(Message::not_interested()).await?;
+        }
+        Ok(())
+    }
+
+    /// Tells the peer we have a new piece
+    pub async fn have(&mut self, index: u32) -> Result<()> {
+        self.send(Message::have(index)).await
+    }
+
+    /// Sends our own bitfield, this must be the first message after the handshake
+    pub async fn send_bitfield(&mut self, bitfield: BitVec<u8, Msb0>) -> Result<()> {
+        self.send(Message::bitfield(bitfield)).await
+    }
+
+    /// Tells the peer which pieces we have, this must be the first message after the handshake.
+    ///
+    /// Fast peers get a have all or have none when it fits, and must be told even when we have
+    /// nothing, others only get a bitfield if there's something in it.
+    pub async fn send_pieces(&mut self, bitfield: BitVec<u8, Msb0>) -> Result<()> {
+        if self.fast {
+            if bitfield.not_any() {
+                return self.send(Message::uknown(HAVE_NONE, Bytes::new())).await;
+            }
+            if bitfield.all() {
+                return self.send(Message::uknown(HAVE_ALL, Bytes::new())).await;
+            }
+        } else if bitfield.not_any() {
+            return Ok(());
+        }
+        self.send_bitfield(bitfield).await
+    }
+
+    /// Tells the peer which extensions we support, does nothing if it doesn't support the extension protocol
+    pub async fn send_extended_handshake(&mut self, handshake: &ExtendedHandshake) -> Result<()> {
+        if self.extension_protocol {
+            let payload = Bytes::from(bde::to_bytes(handshake)?);
+            self.send(Message::Extended { id: 0, payload }).await?;
+        }
+        Ok(())
+    }
+
+    /// The requests we have in flight with this peer
+    pub const fn pipeline(&self) -> &Pipeline {
+        &self.pipeline
+    }
+
+    /// How many more blocks can be requested without overflowing the pipeline
+    pub fn request_slots(&self) -> usize {
+        if self.status.peer_choking {
+            0
+        } else {
+            self.pipeline.slots()
+        }
+    }
+
+    pub async fn request(&mut self, block: Block) -> Result<()> {
+        self.pipeline.on_request(block, Instant::now());
+        self.send(Message::request(block.index, block.begin, block.length))
+            .await
+    }
+
+    pub async fn cancel(&mut self, block: Block) -> Result<()> {
+        if self.pipeline.remove(&block) {
+            self.send(Message::cancel(block.index, block.begin, block.length))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Tells the peer one of its requests won't be served, only peers with the fast extension expect it
+    pub async fn reject(&mut self, block: Block) -> Result<()> {
+        if !self.fast {
+            return Ok(());
+        }
+        let mut payload = BytesMut::with_capacity(12);
+        payload.put_u32(block.index);
+        payload.put_u32(block.begin);
+        payload.put_u32(block.length);
+        self.send(Message::uknown(REJECT_REQUEST, payload.freeze()))
+            .await
+    }
+
+    /// Sends the queued cancels, each one is only dropped from the queue once it's written
+    async fn send_cancels(&mut self) -> Result<()> {
+        if self.cancels.is_empty() {
+            return Ok(());
+        }
+        while let Some(block) = self.cancels.front().copied() {
+            self.wire
+                .write_message(Message::cancel(block.index, block.begin, block.length))
+                .await?;
+            self.cancels.pop_front();
+        }
+        self.wire.flush().await?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// Sends a message and flushes it right away
+    pub async fn send(&mut self, message: Message) -> Result<()> {
+        self.wire.write_message(message).await?;
+        self.wire.flush().await?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// Waits for the next event from the peer, returns `None` once the connection is closed.
+    ///
+    /// Keep-alives are sent while waiting and the peer is dropped if it stays silent past the timeout.
+    /// Requests that go unanswered for too long are cancelled.
+    pub async fn next_event(&mut self) -> Result<Option<ConnectionEvent>> {
+        loop {
+            self.send_cancels().await?;
+            if let Some(event) = self.pending.take() {
+                return Ok(Some(event));
+            }
+
+            let keep_alive = self.last_sent + self.keep_alive_interval;
+            let deadline = self.last_received + self.timeout;
+            let request_deadline = self.pipeline.next_timeout();
+
+            let message = tokio::select! {
+                message = self.wire.read_message() => message?,
+                _ = sleep_until(keep_alive) => {
+                    self.send(Message::keep_alive()).await?;
+                    continue;
+                }
+                _ = sleep_until(deadline) => {
+                    return Err(ConnectionError::Timeout(self.timeout).into());
+                }
+                _ = async {
+                    match request_deadline {
+                        Some(request_deadline) => sleep_until(request_deadline).await,
+                        None => pending().await,
+                    }
+                } => {
+                    let expired = self.pipeline.timed_out(Instant::now());
+                    self.cancels.extend(&expired);
+                    self.pending = Some(ConnectionEvent::TimedOut(expired));
+                    continue;
+                }
+            };
+
+            let Some(message) = message else {
+                return Ok(None);
+            };
+            self.last_received = Instant::now();
+
+            if let Some(event) = self.handle_message(message)? {
+                if let ConnectionEvent::Choked { dropped } = &event {
+                    self.cancels.extend(dropped);
+                    self.pending = Some(event);
+                    continue;
+                }
+                return Ok(Some(event));
+            }
+        }
+    }
+
+    fn handle_message(&mut self, message: Message) -> Result<Option<ConnectionEvent>> {
+        let first_message = !self.received_message;
+        if !matches!(message, Message::KeepAlive) {
+            self.received_message = true;
+        }
+
+        let event = match message {
+            Message::KeepAlive => return Ok(None),
+            Message::Choke => {
+                self.status.peer_choking = true;
+                ConnectionEvent::Choked {
+                    dropped: self.pipeline.clear(),
+                }
+            }
+            Message::Unchoke => {
+                self.status.peer_choking = false;
+                ConnectionEvent::Unchoked
+            }
+            Message::Interested => {
+                self.status.peer_interested = true;
+                ConnectionEvent::Interested
+            }
+            Message::NotInterested => {
+                self.status.peer_interested = false;
+                ConnectionEvent::NotInterested
+            }
+            Message::Have(index) => {
+                if self.has_piece(index) {
+                    return Ok(None);
+                }
+                self.set_piece(index)?;
+                ConnectionEvent::Have(index)
+            }
+            Message::Bitfield(mut bitfield) => {
+                if !first_message {
+                    return Err(ConnectionError::UnexpectedBitfield.into());
+                }
+                if let Some(piece_count) = self.piece_count {
+                    // The bitfield is padded to a whole byte and the spare bits must be cleared
+                    if bitfield.len() != piece_count.div_ceil(8) * 8
+                        || bitfield[piece_count..].any()
+                    {
+                        return Err(ConnectionError::InvalidBitfield.into());
+                    }
+                    bitfield.truncate(piece_count);
+                }
+                self.bitfield = bitfield;
+                ConnectionEvent::Bitfield
+            }
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => ConnectionEvent::Request {
+                index,
+                begin,
+                length,
+            },
+            Message::Piece(piece) => {
+                let block = Block::new(piece.index(), piece.begin(), piece.block().len() as u32);
+                if !self.pipeline.on_block(&block, Instant::now()) {
+                    // Never requested or already cancelled, someone else sent it
+                    self.wasted_bytes += block.length as u64;
+                    return Ok(None);
+                }
+                ConnectionEvent::Block {
+                    index: piece.index(),
+                    begin: piece.begin(),
+                    data: piece.into_block(),
+                }
+            }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => ConnectionEvent::Cancel {
+                index,
+                begin,
+                length,
+            },
+            Message::Port(port) => ConnectionEvent::Port(port),
+            Message::Extended { id, payload } => {
+                if id == 0 {
+                    match bde::from_bytes::<ExtendedHandshake>(&payload) {
+                        Ok(handshake) => {
+                            if let Some(reqq) = handshake.reqq {
+                                self.pipeline.set_max_depth(reqq as usize);
+                            }
+                            // An id of 0 means the extension was disabled
+                            self.extensions = handshake
+                                .messages
+                                .into_iter()
+                                .filter_map(|(name, id)| Some((name, u8::try_from(id).ok()?)))
+                                .filter(|(_, id)| *id != 0)
+                                .collect();
+                            self.metadata_size = handshake.metadata_size;
+                            if handshake.version.is_some() {
+                                self.client = handshake.version;
+                            }
+                            self.upload_only = handshake.upload_only.is_some_and(|flag| flag != 0);
+                        }
+                        Err(error) => debug!("Invalid extended handshake: {}", error),
+                    }
+                }
+                ConnectionEvent::Extended { id, payload }
+            }
+            Message::Unknown { id, payload } if self.fast => match id {
+                HAVE_ALL | HAVE_NONE => {
+                    if !first_message {
+                        return Err(ConnectionError::UnexpectedBitfield.into());
+                    }
+                    let has_all = id == HAVE_ALL;
+                    match self.piece_count {
+                        Some(piece_count) => self.bitfield = BitVec::repeat(has_all, piece_count),
+                        None => self.has_all = has_all,
+                    }
+                    ConnectionEvent::Bitfield
+                }
+                REJECT_REQUEST if payload.len() == 12 => {
+                    let block = Block::new(
+                        u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]),
+                        u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]),
+                        u32::from_be_bytes([payload[8], payload[9], payload[10], payload[11]]),
+                    );
+                    // Rejects for blocks we didn't ask this peer for are ignored
+                    if !self.pipeline.remove(&block) {
+                        return Ok(None);
+                    }
+                    ConnectionEvent::Rejected(block)
+                }
+                _ => {
+                    debug!("Ignoring fast extension message {}", id);
+                    return Ok(None);
+                }
+            },
+            Message::Unknown { id, .. } => {
+                debug!("Ignoring unknown message {}", id);
+                return Ok(None);
+            }
+        };
+
+        Ok(Some(event))
+    }
+
+    fn set_piece(&mut self, index: u32) -> Result<()> {
+        let index = index as usize;
+
+        match self.piece_count {
+            Some(piece_count) if index >= piece_count => {
+                return Err(ConnectionError::InvalidPieceIndex(index as u32).into())
+            }
+            Some(_) => {}
+            // Until the piece count is known only the bitfield's size is bounded
+            None if index >= MAX_PIECE_COUNT => {
+                return Err(ConnectionError::InvalidPieceIndex(index as u32).into())
+            }
+            None if index >= self.bitfield.len() => self.bitfield.resize(index + 1, false),
+            None => {}
+        }
+
+        self.bitfield.set(index, true);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::BLOCK_SIZE;
+    use tokio::{
+        io::{duplex, DuplexStream},
+        time::timeout,
+    };
+
+    async fn pair(
+        builder: &ConnectionBuilder,
+    ) -> (Connection<DuplexStream>, Connection<DuplexStream>) {
+        let (a, b) = duplex(1 << 16);
+        let info_hash = [1; 20];
+        let info_hashes = [info_hash];
+
+        let (outgoing, incoming) = tokio::join!(
+            Wire::handshake(
+                Handshake::new([0, 0, 0, 0, 0, 0x04, 0, 0x05], info_hash, [2; 20]),
+                a
+            ),
+            Wire::accept([0, 0, 0, 0, 0, 0x10, 0, 0x04], [3; 20], &info_hashes, b)
+        );
+        let (peer_info, outgoing) = outgoing.unwrap();
+        let (remote_info, _, incoming) = incoming.unwrap();
+
+        (
+            builder.connection(peer_info, outgoing, false),
+            builder.connection(remote_info, incoming, false),
+        )
+    }
+
+    #[tokio::test]
+    async fn tracks_peer_state() {
+        let (mut a, mut b) = pair(&ConnectionBuilder::new()).await;
+        b.set_piece_count(10).unwrap();
+
+        let mut bitfield = BitVec::repeat(false, 16);
+        bitfield.set(1, true);
+        a.send_bitfield(bitfield).await.unwrap();
+        a.have(7).await.unwrap();
+        a.have(7).await.unwrap();
+        a.unchoke().await.unwrap();
+        a.interested().await.unwrap();
+        // Blocks that weren't requested are dropped
+        a.send(Message::piece(1, 0, Bytes::from_static(b"unwanted")))
+            .await
+            .unwrap();
+        b.request(Block::new(7, 0, 4)).await.unwrap();
+        a.send(Message::piece(7, 0, Bytes::from_static(b"data")))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            b.next_event().await.unwrap(),
+            Some(ConnectionEvent::Bitfield)
+        );
+        assert_eq!(
+            b.next_event().await.unwrap(),
+            Some(ConnectionEvent::Have(7))
+        );
+        // The duplicate have is swallowed
+        assert_eq!(
+            b.next_event().await.unwrap(),
+            Some(ConnectionEvent::Unchoked)
+        );
+        assert_eq!(
+            b.next_event().await.unwrap(),
+            Some(ConnectionEvent::Interested)
+        );
+        assert_eq!(
+            b.next_event().await.unwrap(),
+            Some(ConnectionEvent::Block {
+                index: 7,
+                begin: 0,
+                data: Bytes::from_static(b"data")
+            })
+        );
+        assert_eq!(b.wasted_bytes(), 8);
+
+        assert_eq!(b.bitfield().len(), 10);
+        assert!(b.has_piece(1) && b.has_piece(7) && !b.has_piece(2));
+        assert!(!b.status().peer_choking && b.status().peer_interested);
+        assert!(!a.status().am_choking && a.status().am_interested);
+
+        drop(a);
+        assert_eq!(b.next_event().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn have_all_before_piece_count() {
+        let (mut a, mut b) = pair(&ConnectionBuilder::new()).await;
+        assert!(a.is_fast() && b.is_fast());
+
+        a.send_pieces(BitVec::repeat(true, 20)).await.unwrap();
+        assert_eq!(
+            b.next_event().await.unwrap(),
+            Some(ConnectionEvent::Bitfield)
+        );
+        assert!(b.has_piece(1000));
+
+        b.set_piece_count(20).unwrap();
+        assert_eq!(b.bitfield().count_ones(), 20);
+
+        // Fast peers are told even when we have nothing
+        b.send_pieces(BitVec::EMPTY).await.unwrap();
+        assert_eq!(
+            a.next_event().await.unwrap(),
+            Some(ConnectionEvent::Bitfield)
+        );
+        assert!(!a.has_piece(0));
+    }
+
+    #[tokio::test]
+    async fn encrypted_handshake_must_match() {
+        let (a, b) = duplex(1 << 16);
+        let (skey, other) = ([1; 20], [2; 20]);
+
+        let initiate = async {
+            let stream = MseStream::initiate(a, skey, EncryptionPolicy::RequireEncrypted)
+                .await
+                .unwrap();
+            let handshake = Handshake::new(RESERVED, other, [2; 20]);
+            Wire::handshake(handshake, stream).await
+        };
+        let builder = ConnectionBuilder::new();
+        let info_hashes = [skey, other];
+        let accept = builder.accept(b, &info_hashes, [3; 20]);
+        let (_, accepted) = tokio::join!(initiate, accept);
+        assert!(accepted.is_err());
+    }
+
+    #[tokio::test]
+    async fn have_before_piece_count() {
+        let (mut a, mut b) = pair(&ConnectionBuilder::new()).await;
+
+        a.have(100).await.unwrap();
+        assert_eq!(
+            b.next_event().await.unwrap(),
+            Some(ConnectionEvent::Have(100))
+        );
+        assert_eq!(b.bitfield().len(), 101);
+
+        a.have(u32::MAX).await.unwrap();
+        assert!(b.next_event().await.is_err());
+        assert_eq!(b.bitfield().len(), 101);
+    }
+
+    #[tokio::test]
+    async fn extended_handshake() {
+        let (mut a, mut b) = pair(&ConnectionBuilder::new()).await;
+        // Only the side that accepted the connection advertised the extension protocol
+        assert!(a.is_extended() && !b.is_extended());
+
+        let handshake = ExtendedHandshake {
+            messages: [("ut_metadata".to_string(), 3), ("ut_pex".to_string(), 0)].into(),
+            port: None,
+            version: None,
+            yourip: None,
+            reqq: Some(5),
+            metadata_size: Some(1234),
+            upload_only: Some(1),
+        };
+        a.send_extended_handshake(&handshake).await.unwrap();
+        b.send_extended_handshake(&handshake).await.unwrap();
+        a.send(Message::keep_alive()).await.unwrap();
+
+        assert!(matches!(
+            b.next_event().await.unwrap(),
+            Some(ConnectionEvent::Extended { id: 0, .. })
+        ));
+        assert_eq!(b.extension("ut_metadata"), Some(3));
+        // Disabled extensions are dropped
+        assert_eq!(b.extension("ut_pex"), None);
+        assert_eq!(b.metadata_size(), Some(1234));
+        assert!(b.is_upload_only());
+
+        // Nothing was sent to the peer that can't understand it
+        drop(b);
+        assert_eq!(a.next_event().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_protocol_violations() {
+        let (mut a, mut b) = pair(&ConnectionBuilder::new()).await;
+        b.set_piece_count(10).unwrap();
+
+        a.have(10).await.unwrap();
+        let error = b.next_event().await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(ConnectionError::InvalidPieceIndex(10))
+        ));
+
+        a.send_bitfield(BitVec::repeat(true, 16)).await.unwrap();
+        let error = b.next_event().await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(ConnectionError::UnexpectedBitfield)
+        ));
+
+        // Spare bits must be cleared
+        let (mut a, mut b) = pair(&ConnectionBuilder::new()).await;
+        a.send_bitfield(BitVec::repeat(true, 16)).await.unwrap();
+        assert_eq!(
+            b.next_event().await.unwrap(),
+            Some(ConnectionEvent::Bitfield)
+        );
+        assert!(b.set_piece_count(10).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancels_dropped_requests() {
+        let (mut a, mut b) = pair(&ConnectionBuilder::new()).await;
+        let blocks: Vec<Block> = Block::split(0, BLOCK_SIZE * 4).collect();
+
+        assert_eq!(a.request_slots(), 0);
+        b.unchoke().await.unwrap();
+        assert_eq!(
+            a.next_event().await.unwrap(),
+            Some(ConnectionEvent::Unchoked)
+        );
+        assert!(a.request_slots() > 0);
+
+        for block in &blocks[..3] {
+            a.request(*block).await.unwrap();
+        }
+        for block in &blocks[..3] {
+            assert_eq!(
+                b.next_event().await.unwrap(),
+                Some(ConnectionEvent::Request {
+                    index: block.index,
+                    begin: block.begin,
+                    length: block.length
+                })
+            );
+        }
+
+        b.send(Message::piece(
+            0,
+            0,
+            Bytes::from(vec![0; BLOCK_SIZE as usize]),
+        ))
+        .await
+        .unwrap();
+        b.choke().await.unwrap();
+        assert!(matches!(
+            a.next_event().await.unwrap(),
+            Some(ConnectionEvent::Block {
+                index: 0,
+                begin: 0,
+                ..
+            })
+        ));
+        assert_eq!(
+            a.next_event().await.unwrap(),
+            Some(ConnectionEvent::Choked {
+                dropped: blocks[1..3].to_vec()
+            })
+        );
+        for block in &blocks[1..3] {
+            assert_eq!(
+                b.next_event().await.unwrap(),
+                Some(ConnectionEvent::Cancel {
+                    index: block.index,
+                    begin: block.begin,
+                    length: block.length
+                })
+            );
+        }
+
+        // Requests that are never answered are cancelled as well
+        b.unchoke().await.unwrap();
+        assert_eq!(
+            a.next_event().await.unwrap(),
+            Some(ConnectionEvent::Unchoked)
+        );
+        a.request(blocks[3]).await.unwrap();
+        let start = Instant::now();
+        assert_eq!(
+            a.next_event().await.unwrap(),
+            Some(ConnectionEvent::TimedOut(vec![blocks[3]]))
+        );
+        assert!(start.elapsed() >= Duration::from_secs(20));
+        assert_eq!(a.pipeline().outstanding().count(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn events_survive_cancellation() {
+        let (mut a, mut b) = pair(&ConnectionBuilder::new()).await;
+        let blocks: Vec<Block> = Block::split(0, BLOCK_SIZE * 3).collect();
+        b.unchoke().await.unwrap();
+        a.next_event().await.unwrap();
+        for block in &blocks {
+            a.request(*block).await.unwrap();
+        }
+
+        // Only the first cancel fits in the upload bucket, the caller gives up while the next waits
+        a.limits().upload.set_rate(1);
+        b.choke().await.unwrap();
+        assert!(timeout(Duration::from_secs(1), a.next_event())
+            .await
+            .is_err());
+
+        a.limits().upload.set_rate(0);
+        assert_eq!(
+            a.next_event().await.unwrap(),
+            Some(ConnectionEvent::Choked {
+                dropped: blocks.clone()
+            })
+        );
+        for _ in &blocks {
+            assert!(matches!(
+                b.next_event().await.unwrap(),
+                Some(ConnectionEvent::Request { .. })
+            ));
+        }
+        for block in &blocks {
+            assert_eq!(
+                b.next_event().await.unwrap(),
+                Some(ConnectionEvent::Cancel {
+                    index: block.index,
+                    begin: block.begin,
+                    length: block.length
+                })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_requests() {
+        let (mut a, mut b) = pair(&ConnectionBuilder::new()).await;
+        let block = Block::new(3, BLOCK_SIZE, BLOCK_SIZE);
+
+        b.unchoke().await.unwrap();
+        a.next_event().await.unwrap();
+        a.request(block).await.unwrap();
+        assert!(matches!(
+            b.next_event().await.unwrap(),
+            Some(ConnectionEvent::Request { .. })
+        ));
+
+        // Only requests we actually sent can be rejected
+        b.reject(Block::new(4, 0, BLOCK_SIZE)).await.unwrap();
+        b.reject(block).await.unwrap();
+        assert_eq!(
+            a.next_event().await.unwrap(),
+            Some(ConnectionEvent::Rejected(block))
+        );
+        assert_eq!(a.pipeline().outstanding().count(), 0);
+        b.reject(block).await.unwrap();
+        b.have(1).await.unwrap();
+        assert_eq!(
+            a.next_event().await.unwrap(),
+            Some(ConnectionEvent::Have(1))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keep_alive_and_timeout() {
+        let (mut a, mut b) = pair(&ConnectionBuilder::new()).await;
+        let start = Instant::now();
+
+        let error = b.next_event().await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(ConnectionError::Timeout(_))
+        ));
+        assert_eq!(start.elapsed(), Duration::from_secs(180));
+
+        // Only one keep-alive was due before the timeout
+        assert!(matches!(
+            a.wire.read_message().await.unwrap(),
+            Some(Message::KeepAlive)
+        ));
+        drop(b);
+        assert!(a.wire.read_message().await.unwrap().is_none());
+    }
+}
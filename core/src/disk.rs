@@ -0,0 +1,598 @@
+//! Disk I/O shared by every torrent, with a write-back cache and a read cache
+
+use bytes::{Bytes, BytesMut};
+use color_eyre::eyre::Result;
+use indexmap::IndexMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{sync::Semaphore, task::spawn_blocking};
+
+use crate::{pipeline::Block, storage::Storage};
+
+const DEFAULT_WRITE_CACHE_SIZE: usize = 16 * 1024 * 1024;
+const DEFAULT_READ_CACHE_SIZE: usize = 32 * 1024 * 1024;
+
+/// Counters describing how the disk is keeping up
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DiskMetrics {
+    /// Reads served from the read or write cache
+    pub read_hits: u64,
+    /// Reads that went to storage
+    pub read_misses: u64,
+    /// Blocks handed to [`DiskHandle::write`]
+    pub blocks_written: u64,
+    /// Writes issued to storage after merging contiguous blocks
+    pub writes: u64,
+    /// Times a writer had to wait for the write cache to make room
+    pub write_stalls: u64,
+    pub write_cache_bytes: usize,
+    pub read_cache_bytes: usize,
+    /// Jobs queued or running on the blocking thread pool
+    pub queue_depth: usize,
+}
+
+/// The disk I/O subsystem.
+///
+/// Blocks are kept in a bounded write-back cache until their piece is hashed or the cache fills up,
+/// at which point contiguous blocks are merged into a single write. Writers wait while the cache is
+/// full, which slows down the peers feeding them. Blocks read for uploads go through an LRU cache.
+/// Storage is only ever touched from the blocking thread pool.
+#[derive(Clone)]
+pub struct DiskIo {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    write_cache_size: usize,
+    read_cache_size: usize,
+    /// One permit per byte of write cache
+    write_permits: Semaphore,
+    cache: Mutex<Cache>,
+    next_torrent: AtomicUsize,
+    queue_depth: AtomicUsize,
+    read_hits: AtomicU64,
+    read_misses: AtomicU64,
+    blocks_written: AtomicU64,
+    writes: AtomicU64,
+    write_stalls: AtomicU64,
+}
+
+#[derive(Default)]
+struct Cache {
+    /// Pieces with blocks waiting to be written, by torrent and piece
+    writes: HashMap<(usize, u32), PendingPiece>,
+    write_bytes: usize,
+    /// Least recently used first
+    reads: IndexMap<(usize, Block), Bytes>,
+    read_bytes: usize,
+}
+
+struct PendingPiece {
+    storage: Arc<dyn Storage>,
+    /// By offset in the piece
+    blocks: BTreeMap<u32, Bytes>,
+}
+
+impl PendingPiece {
+    fn bytes(&self) -> usize {
+        self.blocks.values().map(Bytes::len).sum()
+    }
+}
+
+impl DiskIo {
+    pub fn new() -> Self {
+        Self::builder().build()
+    }
+
+    pub fn builder() -> DiskIoBuilder {
+        DiskIoBuilder::new()
+    }
+
+    /// Registers the storage of a torrent
+    pub fn open(&self, storage: Arc<dyn Storage>) -> DiskHandle {
+        DiskHandle {
+            io: self.clone(),
+            id: self.inner.next_torrent.fetch_add(1, Ordering::Relaxed),
+            storage,
+        }
+    }
+
+    pub fn metrics(&self) -> DiskMetrics {
+        let inner = &self.inner;
+        let cache = inner.cache.lock().expect("Poisoned lock");
+
+        DiskMetrics {
+            read_hits: inner.read_hits.load(Ordering::Relaxed),
+            read_misses: inner.read_misses.load(Ordering::Relaxed),
+            blocks_written: inner.blocks_written.load(Ordering::Relaxed),
+            writes: inner.writes.load(Ordering::Relaxed),
+            write_stalls: inner.write_stalls.load(Ordering::Relaxed),
+            write_cache_bytes: cache.write_bytes,
+            read_cache_bytes: cache.read_bytes,
+            queue_depth: inner.queue_depth.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Runs a job on the blocking thread pool, counting it in the queue depth
+    async fn run<T, F>(&self, job: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T> + Send + 'static,
+    {
+        self.inner.queue_depth.fetch_add(1, Ordering::Relaxed);
+        let result = spawn_blocking(job).await;
+        self.inner.queue_depth.fetch_sub(1, Ordering::Relaxed);
+        result?
+    }
+}
+
+impl Default for DiskIo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct DiskIoBuilder {
+    write_cache_size: usize,
+    read_cache_size: usize,
+}
+
+impl DiskIoBuilder {
+    pub const fn new() -> Self {
+        Self {
+            write_cache_size: DEFAULT_WRITE_CACHE_SIZE,
+            read_cache_size: DEFAULT_READ_CACHE_SIZE,
+        }
+    }
+
+    /// Sets how many bytes can wait to be written before writers are stalled
+    pub fn write_cache_size(&mut self, size: usize) -> &mut Self {
+        self.write_cache_size = size;
+        self
+    }
+
+    /// Sets how many bytes of recently read blocks are kept around, zero disables the read cache
+    pub fn read_cache_size(&mut self, size: usize) -> &mut Self {
+        self.read_cache_size = size;
+        self
+    }
+
+    pub fn build(&self) -> DiskIo {
+        // Semaphores can't hand out more than a u32 worth of permits at once
+        let write_cache_size = self.write_cache_size.clamp(1, u32::MAX as usize);
+
+        DiskIo {
+            inner: Arc::new(Inner {
+                write_cache_size,
+                read_cache_size: self.read_cache_size,
+                write_permits: Semaphore::new(write_cache_size),
+                cache: Mutex::new(Cache::default()),
+                next_torrent: AtomicUsize::new(0),
+                queue_depth: AtomicUsize::new(0),
+                read_hits: AtomicU64::new(0),
+                read_misses: AtomicU64::new(0),
+                blocks_written: AtomicU64::new(0),
+                writes: AtomicU64::new(0),
+                write_stalls: AtomicU64::new(0),
+            }),
+        }
+    }
+}
+
+impl Default for DiskIoBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The storage of a single torrent, accessed through a [`DiskIo`]
+#[derive(Clone)]
+pub struct DiskHandle {
+    io: DiskIo,
+    id: usize,
+    storage: Arc<dyn Storage>,
+}
+
+impl DiskHandle {
+    pub fn storage(&self) -> &Arc<dyn Storage> {
+        &self.storage
+    }
+
+    pub fn io(&self) -> &DiskIo {
+        &self.io
+    }
+
+    /// Queues a block to be written, waiting for room in the write cache if it's full
+    pub async fn write(&self, index: u32, begin: u32, data: Bytes) -> Result<()> {
+        self.storage
+            .layout()
+            .slices(index, begin, data.len() as u32)?;
+
+        let inner = &self.io.inner;
+        let permits = inner.permits(data.len()) as u32;
+        let permit = match inner.write_permits.try_acquire_many(permits) {
+            Ok(permit) => permit,
+            Err(_) => {
+                inner.write_stalls.fetch_add(1, Ordering::Relaxed);
+                self.evict().await?;
+                inner.write_permits.acquire_many(permits).await?
+            }
+        };
+        // Given back once the block leaves the cache
+        permit.forget();
+
+        let mut cache = inner.cache.lock().expect("Poisoned lock");
+        let pending = cache
+            .writes
+            .entry((self.id, index))
+            .or_insert_with(|| PendingPiece {
+                storage: self.storage.clone(),
+                blocks: BTreeMap::new(),
+            });
+        let replaced = pending.blocks.insert(begin, data.clone());
+        cache.write_bytes += data.len();
+        if let Some(replaced) = replaced {
+            cache.write_bytes -= replaced.len();
+            inner
+                .write_permits
+                .add_permits(inner.permits(replaced.len()));
+        }
+        inner.blocks_written.fetch_add(1, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// Reads a block, from the caches if possible
+    pub async fn read(&self, index: u32, begin: u32, length: u32) -> Result<Bytes> {
+        let inner = &self.io.inner;
+        let key = (self.id, Block::new(index, begin, length));
+
+        {
+            let mut cache = inner.cache.lock().expect("Poisoned lock");
+            let pending = cache
+                .writes
+                .get(&(self.id, index))
+                .and_then(|pending| pending.blocks.get(&begin))
+                .filter(|data| data.len() >= length as usize)
+                .map(|data| data.slice(..length as usize));
+            if let Some(data) = pending {
+                inner.read_hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(data);
+            }
+            if let Some(data) = cache.reads.shift_remove(&key) {
+                // Back in at the most recently used end
+                cache.reads.insert(key, data.clone());
+                inner.read_hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(data);
+            }
+        }
+
+        inner.read_misses.fetch_add(1, Ordering::Relaxed);
+        let storage = self.storage.clone();
+        let data = self
+            .io
+            .run(move || Ok(storage.read_block(index, begin, length)?))
+            .await?;
+
+        if inner.read_cache_size >= data.len() {
+            let mut cache = inner.cache.lock().expect("Poisoned lock");
+            if let Some(previous) = cache.reads.insert(key, data.clone()) {
+                cache.read_bytes -= previous.len();
+            }
+            cache.read_bytes += data.len();
+            while cache.read_bytes > inner.read_cache_size {
+                let Some((_, evicted)) = cache.reads.shift_remove_index(0) else {
+                    break;
+                };
+                cache.read_bytes -= evicted.len();
+            }
+        }
+
+        Ok(data)
+    }
+
+    /// Writes out the cached blocks of a piece and hashes it
+    pub async fn hash_piece(&self, index: u32) -> Result<[u8; 20]> {
+        let (inner, id, storage) = (self.io.inner.clone(), self.id, self.storage.clone());
+        self.io
+            .run(move || {
+                inner.flush_piece(id, index, &*storage)?;
+                Ok(storage.hash_piece(index)?)
+            })
+            .await
+    }
+
+    /// Writes out every cached block of the torrent and flushes the storage
+    pub async fn flush(&self) -> Result<()> {
+        let (inner, id, storage) = (self.io.inner.clone(), self.id, self.storage.clone());
+        self.io
+            .run(move || {
+                for index in inner.cached_pieces(id) {
+                    inner.flush_piece(id, index, &*storage)?;
+                }
+                Ok(storage.flush()?)
+            })
+            .await
+    }
+
+    /// Drops everything cached for the torrent without writing it
+    pub fn discard(&self) {
+        let inner = &self.io.inner;
+        let mut cache = inner.cache.lock().expect("Poisoned lock");
+        let cache = &mut *cache;
+
+        let (mut released, mut permits) = (0, 0);
+        cache.writes.retain(|(id, _), pending| {
+            if *id == self.id {
+                released += pending.bytes();
+                permits += pending
+                    .blocks
+                    .values()
+                    .map(|data| inner.permits(data.len()))
+                    .sum::<usize>();
+            }
+            *id != self.id
+        });
+        cache.write_bytes -= released;
+        inner.write_permits.add_permits(permits);
+
+        let mut evicted = 0;
+        cache.reads.retain(|(id, _), data| {
+            if *id == self.id {
+                evicted += data.len();
+            }
+            *id != self.id
+        });
+        cache.read_bytes -= evicted;
+    }
+
+    /// Makes room in the write cache by writing out the piece with the most cached bytes,
+    /// whichever torrent it belongs to
+    async fn evict(&self) -> Result<()> {
+        let largest = {
+            let cache = self.io.inner.cache.lock().expect("Poisoned lock");
+            cache
+                .writes
+                .iter()
+                .max_by_key(|(_, pending)| pending.bytes())
+                .map(|((id, index), pending)| (*id, *index, pending.storage.clone()))
+        };
+        let Some((id, index, storage)) = largest else {
+            // Everything is being written already
+            return Ok(());
+        };
+
+        let inner = self.io.inner.clone();
+        self.io
+            .run(move || inner.flush_piece(id, index, &*storage))
+            .await
+    }
+}
+
+impl Inner {
+    /// Write cache permits taken by a block, bigger blocks than the cache only take all of them
+    fn permits(&self, length: usize) -> usize {
+        length.clamp(1, self.write_cache_size)
+    }
+
+    fn cached_pieces(&self, id: usize) -> Vec<u32> {
+        let cache = self.cache.lock().expect("Poisoned lock");
+        cache
+            .writes
+            .keys()
+            .filter(|(torrent, _)| *torrent == id)
+            .map(|(_, index)| *index)
+            .collect()
+    }
+
+    /// Writes the cached blocks of a piece, merging contiguous ones. Must run on a blocking thread.
+    ///
+    /// Blocks stay in the cache until they are written, so anything missing from it is already on
+    /// storage even when another flush of the same piece is still running.
+    fn flush_piece(&self, id: usize, index: u32, storage: &dyn Storage) -> Result<()> {
+        let blocks = {
+            let cache = self.cache.lock().expect("Poisoned lock");
+            match cache.writes.get(&(id, index)) {
+                Some(pending) => pending.blocks.clone(),
+                None => return Ok(()),
+            }
+        };
+
+        let mut runs: Vec<(u32, Vec<&Bytes>)> = Vec::new();
+        for (begin, data) in &blocks {
+            match runs.last_mut() {
+                Some((start, run))
+                    if *start as usize + run.iter().map(|data| data.len()).sum::<usize>()
+                        == *begin as usize =>
+                {
+                    run.push(data)
+                }
+                _ => runs.push((*begin, vec![data])),
+            }
+        }
+
+        for (begin, run) in runs {
+            if let [data] = run[..] {
+                storage.write_block(index, begin, data)?;
+            } else {
+                let mut merged = BytesMut::with_capacity(run.iter().map(|data| data.len()).sum());
+                run.iter().for_each(|data| merged.extend_from_slice(data));
+                storage.write_block(index, begin, &merged)?;
+            }
+            self.writes.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let mut cache = self.cache.lock().expect("Poisoned lock");
+        let cache = &mut *cache;
+        let Some(pending) = cache.writes.get_mut(&(id, index)) else {
+            return Ok(());
+        };
+        let (mut released, mut permits) = (0, 0);
+        for (begin, data) in &blocks {
+            // Only if it wasn't replaced by a newer block in the meantime
+            if pending
+                .blocks
+                .get(begin)
+                .is_some_and(|cached| cached.as_ptr() == data.as_ptr())
+            {
+                pending.blocks.remove(begin);
+                released += data.len();
+                permits += self.permits(data.len());
+            }
+        }
+        if pending.blocks.is_empty() {
+            cache.writes.remove(&(id, index));
+        }
+        cache.write_bytes -= released;
+        self.write_permits.add_permits(permits);
+
+        // Whatever was read before this write is stale now
+        let mut evicted = 0;
+        cache.reads.retain(|(torrent, block), data| {
+            let stale = *torrent == id && block.index == index;
+            if stale {
+                evicted += data.len();
+            }
+            !stale
+        });
+        cache.read_bytes -= evicted;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Layout, StorageError};
+    use std::path::Path;
+
+    /// Keeps the data in memory and counts writes
+    struct VecStorage {
+        layout: Layout,
+        data: Mutex<Vec<u8>>,
+        writes: AtomicUsize,
+    }
+
+    impl VecStorage {
+        fn new(length: u64, piece_length: u32) -> Arc<Self> {
+            Arc::new(Self {
+                layout: Layout::from_files(vec![("file".into(), length)], piece_length).unwrap(),
+                data: Mutex::new(vec![0; length as usize]),
+                writes: AtomicUsize::new(0),
+            })
+        }
+    }
+
+    impl Storage for VecStorage {
+        fn layout(&self) -> &Layout {
+            &self.layout
+        }
+
+        fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Bytes, StorageError> {
+            let start = (self.layout.piece_offset(index) + begin as u64) as usize;
+            let data = self.data.lock().unwrap();
+            Ok(Bytes::copy_from_slice(
+                &data[start..start + length as usize],
+            ))
+        }
+
+        fn write_block(&self, index: u32, begin: u32, data: &[u8]) -> Result<(), StorageError> {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            let start = (self.layout.piece_offset(index) + begin as u64) as usize;
+            self.data.lock().unwrap()[start..start + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn flush(&self) -> Result<(), StorageError> {
+            Ok(())
+        }
+
+        fn move_to(&self, _root: &Path) -> Result<(), StorageError> {
+            Ok(())
+        }
+
+        fn delete(&self) -> Result<(), StorageError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn merges_contiguous_blocks() {
+        let storage = VecStorage::new(64, 32);
+        let disk = DiskIo::new().open(storage.clone());
+
+        // Out of order with a hole in the second piece
+        disk.write(0, 16, Bytes::from(vec![2; 16])).await.unwrap();
+        disk.write(0, 0, Bytes::from(vec![1; 16])).await.unwrap();
+        disk.write(1, 0, Bytes::from(vec![3; 8])).await.unwrap();
+        disk.write(1, 16, Bytes::from(vec![4; 8])).await.unwrap();
+        assert_eq!(storage.writes.load(Ordering::Relaxed), 0);
+        assert_eq!(disk.io().metrics().write_cache_bytes, 48);
+
+        // Unwritten blocks are served from the cache
+        assert_eq!(disk.read(0, 16, 16).await.unwrap(), vec![2; 16]);
+
+        disk.hash_piece(0).await.unwrap();
+        assert_eq!(storage.writes.load(Ordering::Relaxed), 1);
+        disk.flush().await.unwrap();
+        assert_eq!(storage.writes.load(Ordering::Relaxed), 3);
+
+        let metrics = disk.io().metrics();
+        assert_eq!(metrics.blocks_written, 4);
+        assert_eq!(metrics.writes, 3);
+        assert_eq!(metrics.write_cache_bytes, 0);
+        assert_eq!(metrics.queue_depth, 0);
+        assert_eq!(storage.read_block(1, 0, 24).unwrap()[..8], [3; 8]);
+    }
+
+    #[tokio::test]
+    async fn full_cache_stalls_writers() {
+        let storage = VecStorage::new(64, 16);
+        let disk = DiskIo::builder()
+            .write_cache_size(32)
+            .build()
+            .open(storage.clone());
+
+        for index in 0..4 {
+            disk.write(index, 0, Bytes::from(vec![index as u8; 16]))
+                .await
+                .unwrap();
+        }
+
+        let metrics = disk.io().metrics();
+        assert_eq!(metrics.write_stalls, 2);
+        assert!(metrics.write_cache_bytes <= 32);
+        disk.flush().await.unwrap();
+        for index in 0..4 {
+            assert_eq!(
+                storage.read_block(index, 0, 16).unwrap(),
+                vec![index as u8; 16]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn read_cache() {
+        let storage = VecStorage::new(64, 16);
+        storage.write_block(0, 0, &[5; 16]).unwrap();
+        let disk = DiskIo::builder().read_cache_size(32).build().open(storage);
+
+        for index in [0, 0, 1, 2, 0, 1] {
+            disk.read(index, 0, 16).await.unwrap();
+        }
+        let metrics = disk.io().metrics();
+        assert_eq!((metrics.read_hits, metrics.read_misses), (1, 5));
+        assert_eq!(metrics.read_cache_bytes, 32);
+
+        // Writing a piece invalidates what was read of it
+        disk.write(0, 0, Bytes::from(vec![6; 16])).await.unwrap();
+        disk.flush().await.unwrap();
+        assert_eq!(disk.read(0, 0, 16).await.unwrap(), vec![6; 16]);
+    }
+}
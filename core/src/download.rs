@@ -0,0 +1,791 @@
+use bitvec::{order::Msb0, slice::BitSlice, vec::BitVec};
+use bytes::Bytes;
+use color_eyre::eyre::{eyre, Result};
+use futures::future::try_join_all;
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::available_parallelism,
+};
+use tokio::task::spawn_blocking;
+use tracing::debug;
+
+use crate::{
+    disk::{DiskHandle, DiskIo},
+    meta_info::Info,
+    picker::{PiecePicker, PRIORITY_DEFAULT, PRIORITY_MAX, PRIORITY_SKIP},
+    pipeline::Block,
+    resume::{ResumeData, UnfinishedPiece},
+    storage::Storage,
+};
+
+/// What happened after a block was stored
+#[derive(Debug, PartialEq, Eq)]
+pub enum BlockOutcome {
+    /// The piece is still missing some blocks
+    Stored,
+    /// The block was already downloaded or never requested
+    Duplicate,
+    /// The block doesn't line up with the blocks of its piece, so it was dropped without being written
+    Invalid,
+    /// The piece was completed and its hash matches, it should be announced to every peer
+    Verified(u32),
+    /// The piece was completed but its hash doesn't match, so it was thrown away
+    HashFailed {
+        index: u32,
+        /// Peers that sent at least one block of the piece
+        contributors: Vec<SocketAddr>,
+    },
+}
+
+/// How much a file is wanted, pieces get the highest priority among the files they overlap
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FilePriority {
+    /// Not downloaded at all
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FilePriority {
+    /// The piece priority used by the [`PiecePicker`]
+    pub const fn piece_priority(self) -> u8 {
+        match self {
+            Self::Skip => PRIORITY_SKIP,
+            Self::Low => 1,
+            Self::Normal => PRIORITY_DEFAULT,
+            Self::High => PRIORITY_MAX,
+        }
+    }
+}
+
+impl From<u8> for FilePriority {
+    /// Maps a piece priority back to the closest file priority
+    fn from(priority: u8) -> Self {
+        match priority {
+            PRIORITY_SKIP => Self::Skip,
+            1..=3 => Self::Low,
+            PRIORITY_DEFAULT => Self::Normal,
+            _ => Self::High,
+        }
+    }
+}
+
+/// How far a [`verify`] has got
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VerifyProgress {
+    pub checked: usize,
+    pub valid: usize,
+    pub total: usize,
+}
+
+/// Hashes every piece of the data in `storage`, returning which ones are valid.
+///
+/// Pieces are checked in parallel on the blocking thread pool, one worker per core.
+/// Missing or short files simply make their pieces invalid. `progress` is called after every piece.
+pub async fn verify<F>(
+    info: &Info,
+    storage: Arc<dyn Storage>,
+    progress: F,
+) -> Result<BitVec<u8, Msb0>>
+where
+    F: Fn(VerifyProgress) + Send + Sync + 'static,
+{
+    let hashes: Arc<Vec<[u8; 20]>> = Arc::new(info.piece_hashes().collect());
+    let total = hashes.len();
+    let workers = available_parallelism()
+        .map_or(1, usize::from)
+        .min(total)
+        .max(1);
+
+    let next = Arc::new(AtomicUsize::new(0));
+    let checked = Arc::new(AtomicUsize::new(0));
+    let valid = Arc::new(AtomicUsize::new(0));
+    let progress = Arc::new(progress);
+
+    let workers = (0..workers).map(|_| {
+        let (hashes, storage, next, checked, valid, progress) = (
+            hashes.clone(),
+            storage.clone(),
+            next.clone(),
+            checked.clone(),
+            valid.clone(),
+            progress.clone(),
+        );
+
+        spawn_blocking(move || {
+            let mut pieces = Vec::new();
+
+            loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(expected) = hashes.get(index) else {
+                    break;
+                };
+
+                let is_valid = match storage.hash_piece(index as u32) {
+                    Ok(hash) => hash == *expected,
+                    Err(error) => {
+                        debug!("Couldn't hash piece {}: {}", index, error);
+                        false
+                    }
+                };
+                if is_valid {
+                    pieces.push(index);
+                    valid.fetch_add(1, Ordering::Relaxed);
+                }
+
+                progress(VerifyProgress {
+                    checked: checked.fetch_add(1, Ordering::Relaxed) + 1,
+                    valid: valid.load(Ordering::Relaxed),
+                    total,
+                });
+            }
+
+            pieces
+        })
+    });
+
+    let mut bitfield = BitVec::repeat(false, total);
+    for index in try_join_all(workers).await?.into_iter().flatten() {
+        bitfield.set(index, true);
+    }
+
+    Ok(bitfield)
+}
+
+/// Download state of a torrent: what to pick next, where to store it and whether it's valid
+pub struct Download {
+    picker: PiecePicker,
+    disk: DiskHandle,
+    hashes: Vec<[u8; 20]>,
+    /// Peers that sent blocks for each piece being downloaded
+    contributors: HashMap<u32, HashSet<SocketAddr>>,
+    /// How many failed pieces each peer contributed to
+    hash_failures: HashMap<SocketAddr, u32>,
+    file_priorities: Vec<FilePriority>,
+    downloaded_bytes: u64,
+    uploaded_bytes: u64,
+    failed_bytes: u64,
+}
+
+impl Download {
+    /// Creates a download with its own [`DiskIo`]
+    pub fn new(info: &Info, storage: Arc<dyn Storage>) -> Self {
+        Self::with_disk(info, DiskIo::new().open(storage))
+    }
+
+    /// Creates a download going through a shared [`DiskIo`]
+    pub fn with_disk(info: &Info, disk: DiskHandle) -> Self {
+        let storage = disk.storage().clone();
+        let picker = PiecePicker::new(info.piece_length as u32, storage.layout().total_length());
+
+        Self {
+            picker,
+            disk,
+            hashes: info.piece_hashes().collect(),
+            contributors: HashMap::new(),
+            hash_failures: HashMap::new(),
+            file_priorities: vec![FilePriority::Normal; storage.layout().files().len()],
+            downloaded_bytes: 0,
+            uploaded_bytes: 0,
+            failed_bytes: 0,
+        }
+    }
+
+    pub const fn picker(&self) -> &PiecePicker {
+        &self.picker
+    }
+
+    pub fn picker_mut(&mut self) -> &mut PiecePicker {
+        &mut self.picker
+    }
+
+    pub fn storage(&self) -> &Arc<dyn Storage> {
+        self.disk.storage()
+    }
+
+    pub const fn disk(&self) -> &DiskHandle {
+        &self.disk
+    }
+
+    /// Writes out every cached block, should be done before taking [`Self::resume_data`]
+    pub async fn flush(&self) -> Result<()> {
+        self.disk.flush().await
+    }
+
+    /// Forgets the pieces the storage evicted, so they aren't served anymore and can be downloaded
+    /// again. Should be done before relying on the verified pieces of the picker.
+    pub fn forget_evicted(&mut self) {
+        for index in self.storage().take_evicted() {
+            self.picker.piece_evicted(index);
+        }
+    }
+
+    /// Number of failed pieces a peer sent data for
+    pub fn hash_failures(&self, peer: &SocketAddr) -> u32 {
+        self.hash_failures.get(peer).copied().unwrap_or_default()
+    }
+
+    /// Bytes of pieces that passed the hash check
+    pub const fn downloaded_bytes(&self) -> u64 {
+        self.downloaded_bytes
+    }
+
+    pub const fn uploaded_bytes(&self) -> u64 {
+        self.uploaded_bytes
+    }
+
+    pub fn record_upload(&mut self, bytes: u64) {
+        self.uploaded_bytes += bytes;
+    }
+
+    /// Priority of a file, `None` if the torrent has no such file
+    pub fn file_priority(&self, file: usize) -> Option<FilePriority> {
+        self.file_priorities.get(file).copied()
+    }
+
+    /// Sets the priority of a file, each piece gets the highest priority among the files it overlaps.
+    ///
+    /// Skipped files aren't created, the bytes they share with pieces of wanted files are kept
+    /// aside by the storage instead.
+    pub fn set_file_priority(&mut self, file: usize, priority: FilePriority) -> Result<()> {
+        let storage = self.disk.storage();
+        let current = self
+            .file_priorities
+            .get_mut(file)
+            .ok_or_else(|| eyre!("No file {} in the torrent", file))?;
+        let previous = std::mem::replace(current, priority);
+        if (previous == FilePriority::Skip) != (priority == FilePriority::Skip) {
+            storage.set_skipped(file, priority == FilePriority::Skip)?;
+        }
+
+        let layout = storage.layout();
+        for index in layout.file_pieces(file) {
+            let priority = layout
+                .slices(index, 0, layout.piece_length(index))?
+                .iter()
+                .map(|slice| self.file_priorities[slice.file])
+                .max()
+                .unwrap_or(FilePriority::Skip);
+            self.picker.set_priority(index, priority.piece_priority());
+        }
+
+        Ok(())
+    }
+
+    /// Bytes of each file that were downloaded and verified
+    pub fn file_progress(&self) -> Vec<u64> {
+        let layout = self.disk.storage().layout();
+        layout
+            .files()
+            .iter()
+            .enumerate()
+            .map(|(file, entry)| {
+                layout
+                    .file_pieces(file)
+                    .filter(|index| self.picker.has_piece(*index))
+                    .map(|index| {
+                        let begin = layout.piece_offset(index);
+                        let end = begin + layout.piece_length(index) as u64;
+                        end.min(entry.offset + entry.length) - begin.max(entry.offset)
+                    })
+                    .sum()
+            })
+            .collect()
+    }
+
+    /// Files overlapping a piece that have every one of their pieces, to check after it's verified
+    pub fn completed_files(&self, index: u32) -> Vec<usize> {
+        let layout = self.disk.storage().layout();
+        (0..layout.files().len())
+            .filter(|file| {
+                let pieces = layout.file_pieces(*file);
+                pieces.contains(&index)
+                    && pieces.into_iter().all(|piece| self.picker.has_piece(piece))
+            })
+            .collect()
+    }
+
+    /// Bytes thrown away because their piece failed the hash check
+    pub const fn failed_bytes(&self) -> u64 {
+        self.failed_bytes
+    }
+
+    /// Marks the pieces in `bitfield` as already downloaded, usually after a [`verify`]
+    pub fn set_verified(&mut self, bitfield: &BitSlice<u8, Msb0>) {
+        for index in bitfield.iter_ones() {
+            self.picker.piece_verified(index as u32);
+        }
+    }
+
+    /// Checks the data already in storage and resumes from the valid pieces
+    pub async fn recheck<F>(&mut self, info: &Info, progress: F) -> Result<()>
+    where
+        F: Fn(VerifyProgress) + Send + Sync + 'static,
+    {
+        let bitfield = verify(info, self.disk.storage().clone(), progress).await?;
+        self.set_verified(&bitfield);
+        Ok(())
+    }
+
+    /// Snapshot of the state needed to resume without a recheck
+    pub fn resume_data(&self, info_hash: [u8; 20]) -> Result<ResumeData> {
+        let storage = self.disk.storage();
+        let files = (0..storage.layout().files().len())
+            .map(|file| Ok(storage.file_metadata(file)?.unwrap_or_default()))
+            .collect::<Result<_>>()?;
+
+        Ok(ResumeData {
+            info_hash: info_hash.to_vec(),
+            pieces: self.picker.bitfield().to_bitvec().into_vec(),
+            unfinished: self
+                .picker
+                .unfinished()
+                .into_iter()
+                .map(|(piece, blocks)| UnfinishedPiece {
+                    piece,
+                    blocks: blocks.into_vec(),
+                })
+                .collect(),
+            file_priorities: self
+                .file_priorities
+                .iter()
+                .map(|priority| priority.piece_priority())
+                .collect(),
+            uploaded: self.uploaded_bytes,
+            downloaded: self.downloaded_bytes,
+            files,
+        })
+    }
+
+    /// Restores a previous session.
+    ///
+    /// Returns false without changing anything if the data doesn't belong to this torrent or the files
+    /// were modified since it was saved, in which case a [`Self::recheck`] is needed.
+    pub fn restore(&mut self, info_hash: [u8; 20], data: &ResumeData) -> Result<bool> {
+        let storage = self.disk.storage();
+        let layout = storage.layout();
+        let piece_count = self.picker.piece_count();
+
+        if data.info_hash != info_hash
+            || data.pieces.len() != piece_count.div_ceil(8)
+            || data.files.len() != layout.files().len()
+            || data
+                .unfinished
+                .iter()
+                .any(|piece| piece.piece as usize >= piece_count)
+        {
+            return Ok(false);
+        }
+        for (file, saved) in data.files.iter().enumerate() {
+            if storage.file_metadata(file)?.unwrap_or_default() != *saved {
+                return Ok(false);
+            }
+        }
+
+        let mut pieces = BitVec::<u8, Msb0>::from_slice(&data.pieces);
+        pieces.truncate(piece_count);
+        self.set_verified(&pieces);
+        for piece in &data.unfinished {
+            let blocks = BitVec::<u8, Msb0>::from_slice(&piece.blocks);
+            self.picker.set_unfinished(piece.piece, &blocks);
+        }
+        if data.file_priorities.len() == self.file_priorities.len() {
+            for (file, priority) in data.file_priorities.iter().enumerate() {
+                self.set_file_priority(file, FilePriority::from(*priority))?;
+            }
+        }
+        self.uploaded_bytes = data.uploaded;
+        self.downloaded_bytes = data.downloaded;
+
+        Ok(true)
+    }
+
+    /// Stores a block received from `peer`, verifying its piece once it's complete.
+    ///
+    /// The block goes through the write cache of the [`DiskIo`], waiting if it's full. This holds
+    /// `self` while the block is written and hashed, [`Torrent`](crate::Torrent) calls the steps
+    /// one by one instead so the download isn't locked meanwhile.
+    pub async fn block_received(
+        &mut self,
+        peer: SocketAddr,
+        index: u32,
+        begin: u32,
+        data: Bytes,
+    ) -> Result<BlockOutcome> {
+        let block = Block::new(index, begin, data.len() as u32);
+        if let Some(outcome) = self.check_block(&block) {
+            return Ok(outcome);
+        }
+
+        self.disk.write(index, begin, data).await?;
+        let Some(contributors) = self.block_written(peer, &block) else {
+            return Ok(BlockOutcome::Stored);
+        };
+
+        let valid = self.verify_piece(index).await?;
+        Ok(self.piece_checked(index, valid, contributors))
+    }
+
+    /// First step of [`Self::block_received`], returns the outcome if the block shouldn't be written
+    pub fn check_block(&mut self, block: &Block) -> Option<BlockOutcome> {
+        if !self.picker.is_whole_block(block) {
+            self.picker.add_wasted(block.length as u64);
+            return Some(BlockOutcome::Invalid);
+        }
+        if !self.picker.is_block_missing(block) {
+            // Still recorded so it's counted as wasted
+            self.picker.block_received(block);
+            return Some(BlockOutcome::Duplicate);
+        }
+        None
+    }
+
+    /// Second step, once the block was written: returns the peers that sent the piece when it's
+    /// complete and has to be hashed
+    pub fn block_written(
+        &mut self,
+        peer: SocketAddr,
+        block: &Block,
+    ) -> Option<HashSet<SocketAddr>> {
+        self.contributors
+            .entry(block.index)
+            .or_default()
+            .insert(peer);
+        if !self.picker.block_received(block) {
+            return None;
+        }
+        Some(self.contributors.remove(&block.index).unwrap_or_default())
+    }
+
+    /// Last step, records whether the completed piece matched its hash
+    pub fn piece_checked(
+        &mut self,
+        index: u32,
+        valid: bool,
+        contributors: HashSet<SocketAddr>,
+    ) -> BlockOutcome {
+        if valid {
+            self.picker.piece_verified(index);
+            self.downloaded_bytes += self.picker.piece_length(index) as u64;
+            BlockOutcome::Verified(index)
+        } else {
+            self.picker.piece_failed(index);
+            self.failed_bytes += self.picker.piece_length(index) as u64;
+            for peer in &contributors {
+                *self.hash_failures.entry(*peer).or_default() += 1;
+            }
+            BlockOutcome::HashFailed {
+                index,
+                contributors: contributors.into_iter().collect(),
+            }
+        }
+    }
+
+    /// Hashes a piece and compares it with the one in the torrent
+    pub async fn verify_piece(&self, index: u32) -> Result<bool> {
+        let expected = *self
+            .hashes
+            .get(index as usize)
+            .ok_or_else(|| eyre!("Missing hash for piece {}", index))?;
+        let hash = self.disk.hash_piece(index).await?;
+
+        Ok(hash == expected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        meta_info::{File, FileKind},
+        pipeline::BLOCK_SIZE,
+        storage::{FileStorage, Layout, MemoryStorage},
+    };
+    use bitvec::{order::Msb0, vec::BitVec};
+    use std::{env::temp_dir, fs};
+
+    const PIECE_LENGTH: usize = BLOCK_SIZE as usize * 2;
+
+    fn torrent(data: &[u8]) -> Info {
+        let name = format!("leech-download-{}", rand::random::<u64>());
+        Info::from_data(&name, PIECE_LENGTH, data)
+    }
+
+    fn block(data: &[u8], block: &Block) -> Bytes {
+        let start = block.index as usize * PIECE_LENGTH + block.begin as usize;
+        Bytes::copy_from_slice(&data[start..start + block.length as usize])
+    }
+
+    #[tokio::test]
+    async fn corrupted_blocks_are_redownloaded() {
+        let data: Vec<u8> = (0..PIECE_LENGTH + BLOCK_SIZE as usize)
+            .map(|_| rand::random())
+            .collect();
+        let info = torrent(&data);
+        let storage = Arc::new(MemoryStorage::new(Layout::new(&info).unwrap()));
+        let mut download = Download::new(&info, storage);
+
+        let honest: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let malicious: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let all: BitVec<u8, Msb0> = BitVec::repeat(true, 2);
+        download.picker_mut().peer_bitfield(&all);
+
+        let mut blocks = download.picker_mut().pick(&all, 10);
+        blocks.sort_by_key(|block| (block.index, block.begin));
+        assert_eq!(blocks.len(), 3);
+
+        let outcome = download
+            .block_received(honest, 0, 0, block(&data, &blocks[0]))
+            .await
+            .unwrap();
+        assert_eq!(outcome, BlockOutcome::Stored);
+
+        let outcome = download
+            .block_received(
+                malicious,
+                0,
+                BLOCK_SIZE,
+                Bytes::from(vec![0; BLOCK_SIZE as usize]),
+            )
+            .await
+            .unwrap();
+        let BlockOutcome::HashFailed {
+            index: 0,
+            mut contributors,
+        } = outcome
+        else {
+            panic!("Corrupted piece was accepted: {:?}", outcome);
+        };
+        contributors.sort();
+        assert_eq!(contributors, vec![honest, malicious]);
+        assert_eq!(download.hash_failures(&malicious), 1);
+        assert_eq!(download.failed_bytes(), PIECE_LENGTH as u64);
+
+        // The last piece is unaffected
+        let outcome = download
+            .block_received(honest, 1, 0, block(&data, &blocks[2]))
+            .await
+            .unwrap();
+        assert_eq!(outcome, BlockOutcome::Verified(1));
+
+        // The failed piece is picked again
+        let mut retry = download.picker_mut().pick(&all, 10);
+        retry.sort_by_key(|block| (block.index, block.begin));
+        assert_eq!(retry, &blocks[..2]);
+        for block in &retry {
+            download
+                .block_received(honest, block.index, block.begin, self::block(&data, block))
+                .await
+                .unwrap();
+        }
+        assert!(download.picker().is_complete());
+        assert_eq!(download.downloaded_bytes(), data.len() as u64);
+        assert_eq!(download.hash_failures(&honest), 1);
+
+        let outcome = download
+            .block_received(malicious, 1, 0, block(&data, &blocks[2]))
+            .await
+            .unwrap();
+        assert_eq!(outcome, BlockOutcome::Duplicate);
+        assert_eq!(download.picker().wasted_bytes(), BLOCK_SIZE as u64);
+    }
+
+    #[tokio::test]
+    async fn unaligned_blocks_are_dropped() {
+        let data: Vec<u8> = (0..PIECE_LENGTH).map(|_| rand::random()).collect();
+        let info = torrent(&data);
+        let storage = Arc::new(MemoryStorage::new(Layout::new(&info).unwrap()));
+        let mut download = Download::new(&info, storage);
+
+        let honest: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let malicious: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let all: BitVec<u8, Msb0> = BitVec::repeat(true, 1);
+        download.picker_mut().peer_bitfield(&all);
+        let blocks = download.picker_mut().pick(&all, 10);
+        assert_eq!(blocks.len(), 2);
+
+        // Neither takes the place of the first block
+        for (begin, length) in [(1, 5), (0, 5), (0, BLOCK_SIZE + 1)] {
+            let outcome = download
+                .block_received(malicious, 0, begin, Bytes::from(vec![0; length as usize]))
+                .await
+                .unwrap();
+            assert_eq!(outcome, BlockOutcome::Invalid);
+        }
+        assert_eq!(download.picker().wasted_bytes(), 10 + BLOCK_SIZE as u64 + 1);
+
+        for block in &blocks {
+            download
+                .block_received(honest, block.index, block.begin, self::block(&data, block))
+                .await
+                .unwrap();
+        }
+        assert!(download.picker().is_complete());
+        assert_eq!(download.hash_failures(&honest), 0);
+    }
+
+    #[tokio::test]
+    async fn recheck_existing_data() {
+        let data: Vec<u8> = (0..PIECE_LENGTH * 5 + 10).map(|_| rand::random()).collect();
+        let info = torrent(&data);
+        let storage = Arc::new(MemoryStorage::new(Layout::new(&info).unwrap()));
+
+        // Pieces 0, 1, 3 and the last short one are on disk but 3 is corrupted
+        for index in [0, 1, 3, 5] {
+            let start = index * PIECE_LENGTH;
+            let piece = &data[start..data.len().min(start + PIECE_LENGTH)];
+            storage.write_block(index as u32, 0, piece).unwrap();
+        }
+        storage.write_block(3, 100, &[0; 10]).unwrap();
+
+        let reports = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut download = Download::new(&info, storage);
+        download
+            .recheck(&info, {
+                let reports = reports.clone();
+                move |progress| reports.lock().unwrap().push(progress)
+            })
+            .await
+            .unwrap();
+
+        let have: Vec<usize> = download.picker().bitfield().iter_ones().collect();
+        assert_eq!(have, vec![0, 1, 5]);
+        // Nothing was actually downloaded
+        assert_eq!(download.downloaded_bytes(), 0);
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 6);
+        assert!(reports.contains(&VerifyProgress {
+            checked: 6,
+            valid: 3,
+            total: 6
+        }));
+    }
+
+    #[tokio::test]
+    async fn resume_without_recheck() {
+        let data: Vec<u8> = (0..PIECE_LENGTH * 3).map(|_| rand::random()).collect();
+        let info = torrent(&data);
+        let info_hash = [9; 20];
+        let storage = Arc::new(FileStorage::new(temp_dir(), Layout::new(&info).unwrap()));
+        let peer: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let all: BitVec<u8, Msb0> = BitVec::repeat(true, 3);
+
+        let mut download = Download::new(&info, storage.clone());
+        download.picker_mut().peer_bitfield(&all);
+        download.set_file_priority(0, FilePriority::High).unwrap();
+        download.record_upload(42);
+
+        // One whole piece and half of another
+        let mut blocks = download.picker_mut().pick(&all, 6);
+        blocks.sort_by_key(|block| (block.index, block.begin));
+        for block in &blocks[..3] {
+            download
+                .block_received(peer, block.index, block.begin, self::block(&data, block))
+                .await
+                .unwrap();
+        }
+        download.flush().await.unwrap();
+        let resume = download.resume_data(info_hash).unwrap();
+
+        let mut restored = Download::new(&info, storage.clone());
+        assert!(!restored.restore([0; 20], &resume).unwrap());
+        assert!(restored.restore(info_hash, &resume).unwrap());
+        assert_eq!(restored.picker().bitfield(), download.picker().bitfield());
+        assert_eq!(restored.uploaded_bytes(), 42);
+        assert_eq!(restored.downloaded_bytes(), PIECE_LENGTH as u64);
+        assert_eq!(restored.file_priority(0), Some(FilePriority::High));
+        assert_eq!(restored.file_priority(9), None);
+        assert_eq!(restored.picker().priority(2), PRIORITY_MAX);
+
+        // Only the missing block of the unfinished piece is picked
+        restored.picker_mut().peer_bitfield(&all);
+        let missing = restored.picker_mut().pick(&bitfield_of(&[1]), 6);
+        assert_eq!(missing, &blocks[3..4]);
+
+        // Modified files mean the resume data can't be trusted
+        storage
+            .write_block(2, 0, &data[PIECE_LENGTH * 2..])
+            .unwrap();
+        let mut modified = Download::new(&info, storage.clone());
+        assert!(!modified.restore(info_hash, &resume).unwrap());
+        assert!(modified.picker().bitfield().not_any());
+
+        storage.delete().unwrap();
+    }
+
+    #[tokio::test]
+    async fn skipped_files_are_not_downloaded() {
+        // The debug symbols start halfway through the second piece and fill the last two
+        let data: Vec<u8> = (0..PIECE_LENGTH * 4).map(|_| rand::random()).collect();
+        let mut info = torrent(&data);
+        info.files = FileKind::MultiFile {
+            files: vec![
+                File {
+                    length: PIECE_LENGTH as u64 * 3 / 2,
+                    md5sum: None,
+                    path: vec!["program".to_string()],
+                },
+                File {
+                    length: PIECE_LENGTH as u64 * 5 / 2,
+                    md5sum: None,
+                    path: vec!["program.debug".to_string()],
+                },
+            ],
+        };
+        let root = temp_dir().join(format!("leech-download-{}", rand::random::<u64>()));
+        let storage = Arc::new(FileStorage::new(&root, Layout::new(&info).unwrap()));
+        let mut download = Download::new(&info, storage.clone());
+        download.set_file_priority(1, FilePriority::Skip).unwrap();
+        assert!(download.set_file_priority(2, FilePriority::Skip).is_err());
+
+        let peer: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let all: BitVec<u8, Msb0> = BitVec::repeat(true, 4);
+        download.picker_mut().peer_bitfield(&all);
+        let blocks = download.picker_mut().pick(&all, 10);
+        assert!(blocks.iter().all(|block| block.index < 2));
+        assert_eq!(blocks.len(), 4);
+
+        for block in &blocks {
+            download
+                .block_received(peer, block.index, block.begin, self::block(&data, block))
+                .await
+                .unwrap();
+        }
+        download.flush().await.unwrap();
+        assert!(download.picker().is_complete());
+        assert!(!storage.path(1).exists());
+        assert_eq!(download.completed_files(1), vec![0]);
+        assert_eq!(
+            download.file_progress(),
+            vec![PIECE_LENGTH as u64 * 3 / 2, PIECE_LENGTH as u64 / 2]
+        );
+        assert_eq!(download.completed_files(3), Vec::<usize>::new());
+
+        // Wanting the file again moves its bytes out of the part file
+        download.set_file_priority(1, FilePriority::Low).unwrap();
+        assert_eq!(download.picker().priority(3), 1);
+        assert_eq!(
+            fs::read(storage.path(1)).unwrap(),
+            &data[PIECE_LENGTH * 3 / 2..PIECE_LENGTH * 2]
+        );
+
+        storage.delete().unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    fn bitfield_of(pieces: &[usize]) -> BitVec<u8, Msb0> {
+        let mut bitfield = BitVec::repeat(false, 3);
+        for piece in pieces {
+            bitfield.set(*piece, true);
+        }
+        bitfield
+    }
+}
@@ -0,0 +1,225 @@
+//! Bandwidth limits, applied by the [`Wire`](crate::Wire) to every message it sends or receives
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{Mutex as QueueMutex, Notify},
+    time::{sleep, Instant},
+};
+
+use crate::{stats::split, Message};
+
+/// Throttles bytes to a number per second with a token bucket, 0 means unlimited.
+///
+/// The bucket holds up to a second worth of bytes. Connections waiting for it are served in
+/// the order they asked, so they all get their share.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+    /// Only the first in line waits for the bucket to refill
+    queue: QueueMutex<()>,
+    /// Wakes up the waiting connection when the rate is changed
+    changed: Notify,
+    /// Whether the protocol messages count against the limit, not only the piece data
+    include_overhead: AtomicBool,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: u64,
+    /// Goes below zero when a message bigger than what's left is let through
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    /// Takes `bytes` from the bucket, or tells how long to wait before trying again
+    fn take(&mut self, bytes: u64, now: Instant) -> Option<Duration> {
+        if self.rate == 0 {
+            return None;
+        }
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last = now;
+
+        if self.tokens >= 0.0 {
+            self.tokens -= bytes as f64;
+            None
+        } else {
+            Some(Duration::from_secs_f64(-self.tokens / self.rate as f64))
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                rate,
+                tokens: rate as f64,
+                last: Instant::now(),
+            }),
+            queue: QueueMutex::new(()),
+            changed: Notify::new(),
+            include_overhead: AtomicBool::new(true),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(0)
+    }
+
+    /// Bytes per second, 0 if unlimited
+    pub fn rate(&self) -> u64 {
+        self.bucket.lock().expect("Poisoned lock").rate
+    }
+
+    /// Changes the limit, connections already waiting pick it up right away
+    pub fn set_rate(&self, rate: u64) {
+        let mut bucket = self.bucket.lock().expect("Poisoned lock");
+        bucket.rate = rate;
+        bucket.tokens = bucket.tokens.min(rate as f64);
+        drop(bucket);
+        self.changed.notify_waiters();
+    }
+
+    pub fn include_overhead(&self) -> bool {
+        self.include_overhead.load(Ordering::Relaxed)
+    }
+
+    /// Sets whether protocol messages are throttled too, otherwise only piece data is
+    pub fn set_include_overhead(&self, include: bool) {
+        self.include_overhead.store(include, Ordering::Relaxed);
+    }
+
+    /// Waits until `bytes` can go through
+    pub async fn acquire(&self, bytes: u64) {
+        if bytes == 0 || self.rate() == 0 {
+            return;
+        }
+
+        let _turn = self.queue.lock().await;
+        loop {
+            let changed = self.changed.notified();
+            let wait = self
+                .bucket
+                .lock()
+                .expect("Poisoned lock")
+                .take(bytes, Instant::now());
+            match wait {
+                Some(wait) => tokio::select! {
+                    _ = sleep(wait) => {}
+                    _ = changed => {}
+                },
+                None => return,
+            }
+        }
+    }
+
+    /// Waits until a message can go through, counting only its piece data unless the overhead is included
+    pub async fn acquire_message(&self, message: &Message) {
+        let (payload, protocol) = split(message);
+        self.acquire_transfer(payload, protocol).await;
+    }
+
+    /// Same as [`Self::acquire_message`] for a message that is already gone
+    pub async fn acquire_transfer(&self, payload: u64, protocol: u64) {
+        if self.include_overhead() {
+            self.acquire(payload + protocol).await;
+        } else {
+            self.acquire(payload).await;
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::unlimited()
+    }
+}
+
+/// Upload and download limits of a peer, a torrent or a whole session
+#[derive(Debug, Default)]
+pub struct RateLimits {
+    pub upload: RateLimiter,
+    pub download: RateLimiter,
+}
+
+impl RateLimits {
+    pub fn new(upload: u64, download: u64) -> Self {
+        Self {
+            upload: RateLimiter::new(upload),
+            download: RateLimiter::new(download),
+        }
+    }
+
+    pub fn set_include_overhead(&self, include: bool) {
+        self.upload.set_include_overhead(include);
+        self.download.set_include_overhead(include);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use std::sync::Arc;
+
+    #[tokio::test(start_paused = true)]
+    async fn throttles_to_the_rate() {
+        let start = Instant::now();
+        let limiter = RateLimiter::new(10_000);
+
+        // The first second worth of bytes goes through right away
+        limiter.acquire(6_000).await;
+        limiter.acquire(6_000).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // Then it's paid back at the rate
+        limiter.acquire(1_000).await;
+        assert_eq!(start.elapsed().as_millis(), 200);
+
+        limiter.set_rate(0);
+        limiter.acquire(1_000_000).await;
+        assert_eq!(start.elapsed().as_millis(), 200);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waiting_connections_see_new_rates() {
+        let limiter = Arc::new(RateLimiter::new(1_000));
+        limiter.acquire(11_000).await;
+
+        // Waits for 10 seconds at first, 1 once the rate goes up
+        let start = Instant::now();
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire(1).await }
+        });
+        tokio::task::yield_now().await;
+        limiter.set_rate(10_000);
+        waiting.await.unwrap();
+        assert_eq!(start.elapsed().as_secs(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn overhead_can_be_left_out() {
+        let start = Instant::now();
+        let limiter = RateLimiter::new(100);
+        limiter.set_include_overhead(false);
+
+        for _ in 0..100 {
+            limiter.acquire_message(&Message::have(1)).await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        let piece = Message::piece(0, 0, Bytes::from_static(&[0; 150]));
+        limiter.acquire_message(&piece).await;
+        limiter.acquire_message(&piece).await;
+        assert_eq!(start.elapsed().as_millis(), 500);
+    }
+}
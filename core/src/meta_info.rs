@@ -1,13 +1,12 @@
 use color_eyre::eyre::Result;
 use serde::{Deserialize, Serialize};
 use sha1::{digest::FixedOutput, Digest, Sha1};
-use std::convert::TryInto;
 use url::Url;
 
 // pub use bento;
 
 /// Dictionary containg information about the torrent
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct MetaInfo {
     /// The announce url of the tracker.
     /// According to the specification this is always set.
@@ -41,7 +40,7 @@ pub struct MetaInfo {
     pub url_list: Option<Vec<Url>>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Info {
     /// The name of the file or directory to store multiple files, respecting this field is not mandatory
     pub name: String,
@@ -62,7 +61,7 @@ pub struct Info {
 }
 
 /// A dictionary containing information about the file(s) of the torrent
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(deny_unknown_fields, untagged)]
 pub enum FileKind {
     // Information about multiple files
@@ -82,7 +81,7 @@ pub enum FileKind {
 }
 
 /// Dictionary containing information about a file
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct File {
     /// Length of the file in bytes
     pub length: u64,
@@ -102,11 +101,16 @@ impl MetaInfo {
 
         let mut hasher = Sha1::new();
         hasher.update(&info);
-        let info_hash: [u8; 20] = hasher.finalize_fixed().try_into().unwrap(); // NOTE: This shouldn't fail in theory but unless const generics is stabilized it has to stay that way
+        let info_hash: [u8; 20] = hasher.finalize_fixed().into();
 
         Ok(info_hash)
     }
 
+    /// Returns the number of pieces in the torrent
+    pub fn piece_count(&self) -> usize {
+        self.info.pieces.len() / 20
+    }
+
     pub fn length(&self) -> u64 {
         match &self.info.files {
             FileKind::SingleFile { length, .. } => *length, // TODO: probably a better way to do this
@@ -116,3 +120,39 @@ impl MetaInfo {
         }
     }
 }
+
+impl Info {
+    /// Returns the expected SHA-1 hash of every piece
+    pub fn piece_hashes(&self) -> impl Iterator<Item = [u8; 20]> + '_ {
+        self.pieces
+            .chunks_exact(20)
+            .map(|hash| hash.try_into().expect("Chunks are 20 bytes"))
+    }
+
+    /// Returns the expected SHA-1 hash of a piece
+    pub fn piece_hash(&self, index: u32) -> Option<[u8; 20]> {
+        let start = index as usize * 20;
+        self.pieces.get(start..start + 20)?.try_into().ok()
+    }
+}
+
+#[cfg(test)]
+impl Info {
+    /// A single file torrent holding `data`, for tests
+    pub(crate) fn from_data(name: &str, piece_length: usize, data: &[u8]) -> Self {
+        Self {
+            name: name.to_string(),
+            piece_length: piece_length as u64,
+            pieces: data
+                .chunks(piece_length)
+                .flat_map(|piece| Sha1::digest(piece).to_vec())
+                .collect(),
+            private: None,
+            source: None,
+            files: FileKind::SingleFile {
+                length: data.len() as u64,
+                md5sum: None,
+            },
+        }
+    }
+}
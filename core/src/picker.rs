@@ -0,0 +1,962 @@
+use bitvec::{order::Msb0, slice::BitSlice, vec::BitVec};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use std::collections::HashMap;
+use tokio::time::Instant;
+
+use crate::pipeline::{Block, BLOCK_SIZE};
+
+/// Pieces with this priority are never downloaded
+pub const PRIORITY_SKIP: u8 = 0;
+pub const PRIORITY_DEFAULT: u8 = 4;
+pub const PRIORITY_MAX: u8 = 7;
+
+/// Pieces picked in order after the position of a reader, unless changed with
+/// [`PiecePicker::set_read_ahead`]
+pub const DEFAULT_READ_AHEAD: u32 = 4;
+
+/// Until we have this many pieces they are picked at random.
+///
+/// Rare pieces take longer to download so it's better to get something to share quickly first.
+const RANDOM_PIECES: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockState {
+    Open,
+    /// Requested from this many peers, more than one only in endgame mode
+    Requested(u16),
+    Received,
+}
+
+/// A piece we started downloading
+#[derive(Debug)]
+struct PartialPiece {
+    blocks: Vec<BlockState>,
+}
+
+impl PartialPiece {
+    fn count(&self, state: BlockState) -> usize {
+        self.blocks.iter().filter(|block| **block == state).count()
+    }
+}
+
+/// Decides which blocks to request next.
+///
+/// Pieces are picked rarest first with random tie-breaking, higher priorities always go first and
+/// pieces that were already started are finished before new ones. This does no I/O at all:
+/// availability and progress are fed in by the torrent.
+///
+/// For streaming, pieces with a deadline and the ones in the read-ahead window override all of
+/// that and are picked in order of urgency.
+#[derive(Debug)]
+pub struct PiecePicker {
+    piece_length: u32,
+    total_length: u64,
+    /// How many connected peers have each piece
+    availability: Vec<u32>,
+    priorities: Vec<u8>,
+    /// Every piece by priority then availability, ties in random order. Kept sorted as the
+    /// availability changes so picking doesn't have to sort all the pieces each time.
+    order: Vec<u32>,
+    /// Where each piece is in `order`
+    positions: Vec<usize>,
+    /// A priority changed, `order` has to be sorted again before it's used
+    order_dirty: bool,
+    /// Verified pieces
+    have: BitVec<u8, Msb0>,
+    partial: HashMap<u32, PartialPiece>,
+    /// Pick pieces in order instead of rarest first
+    sequential: bool,
+    deadlines: HashMap<u32, Instant>,
+    /// First piece of the read-ahead window, only while something reads the torrent
+    read_position: Option<u32>,
+    read_ahead: u32,
+    rng: StdRng,
+    /// Bytes received for blocks we already had
+    wasted_bytes: u64,
+    /// Requests sent for blocks that were already requested from another peer
+    duplicate_requests: u64,
+}
+
+impl PiecePicker {
+    pub fn new(piece_length: u32, total_length: u64) -> Self {
+        let piece_count = total_length.div_ceil(piece_length as u64) as usize;
+        let mut rng = StdRng::from_entropy();
+        let mut order: Vec<u32> = (0..piece_count as u32).collect();
+        order.shuffle(&mut rng);
+        let mut positions = vec![0; piece_count];
+        for (position, index) in order.iter().enumerate() {
+            positions[*index as usize] = position;
+        }
+
+        Self {
+            piece_length,
+            total_length,
+            availability: vec![0; piece_count],
+            priorities: vec![PRIORITY_DEFAULT; piece_count],
+            order,
+            positions,
+            order_dirty: false,
+            have: BitVec::repeat(false, piece_count),
+            partial: HashMap::new(),
+            sequential: false,
+            deadlines: HashMap::new(),
+            read_position: None,
+            read_ahead: DEFAULT_READ_AHEAD,
+            rng,
+            wasted_bytes: 0,
+            duplicate_requests: 0,
+        }
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.availability.len()
+    }
+
+    /// Length of a piece, only the last one can be shorter and it's 0 past the last piece
+    pub fn piece_length(&self, index: u32) -> u32 {
+        let begin = index as u64 * self.piece_length as u64;
+        self.total_length
+            .saturating_sub(begin)
+            .min(self.piece_length as u64) as u32
+    }
+
+    /// The pieces we have verified
+    pub fn bitfield(&self) -> &BitSlice<u8, Msb0> {
+        &self.have
+    }
+
+    /// Whether a piece was downloaded and verified, `false` for indices past the last piece
+    pub fn has_piece(&self, index: u32) -> bool {
+        self.have.get(index as usize).is_some_and(|have| *have)
+    }
+
+    /// Whether every piece we want was downloaded
+    pub fn is_complete(&self) -> bool {
+        self.have
+            .iter()
+            .zip(&self.priorities)
+            .all(|(have, priority)| *have || *priority == PRIORITY_SKIP)
+    }
+
+    pub fn availability(&self, index: u32) -> u32 {
+        self.availability
+            .get(index as usize)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// How many copies of the torrent the connected peers have between them.
+    ///
+    /// The whole part is the availability of the rarest piece, the fraction is the share of
+    /// pieces that are more common than that.
+    pub fn distributed_copies(&self) -> f64 {
+        let Some(rarest) = self.availability.iter().min() else {
+            return 0.0;
+        };
+        let common = self
+            .availability
+            .iter()
+            .filter(|availability| *availability > rarest)
+            .count();
+        *rarest as f64 + common as f64 / self.availability.len() as f64
+    }
+
+    /// Priority of a piece, indices past the last piece are skipped
+    pub fn priority(&self, index: u32) -> u8 {
+        self.priorities
+            .get(index as usize)
+            .copied()
+            .unwrap_or(PRIORITY_SKIP)
+    }
+
+    /// Sets the priority of a piece, from [`PRIORITY_SKIP`] up to [`PRIORITY_MAX`]
+    pub fn set_priority(&mut self, index: u32, priority: u8) {
+        if let Some(current) = self.priorities.get_mut(index as usize) {
+            let priority = priority.min(PRIORITY_MAX);
+            self.order_dirty |= *current != priority;
+            *current = priority;
+        }
+    }
+
+    pub const fn is_sequential(&self) -> bool {
+        self.sequential
+    }
+
+    /// Picks pieces in order instead of rarest first, priorities still apply
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential = sequential;
+    }
+
+    /// Asks for a piece to be downloaded by `deadline`, before anything else.
+    ///
+    /// Pieces with earlier deadlines are picked first. The deadline is dropped once the piece is verified.
+    pub fn set_piece_deadline(&mut self, index: u32, deadline: Instant) {
+        if self.have.get(index as usize).is_some_and(|have| !*have) {
+            self.deadlines.insert(index, deadline);
+        }
+    }
+
+    pub fn clear_piece_deadline(&mut self, index: u32) {
+        self.deadlines.remove(&index);
+    }
+
+    pub fn piece_deadline(&self, index: u32) -> Option<Instant> {
+        self.deadlines.get(&index).copied()
+    }
+
+    /// Sets how many pieces from the read position are picked in order before anything else
+    pub fn set_read_ahead(&mut self, pieces: u32) {
+        self.read_ahead = pieces;
+    }
+
+    /// Moves the read-ahead window, usually to where a reader is in the torrent
+    pub fn set_read_position(&mut self, index: u32) {
+        self.read_position = Some(index);
+    }
+
+    pub const fn read_position(&self) -> Option<u32> {
+        self.read_position
+    }
+
+    /// Nothing reads the torrent anymore, the read-ahead window goes away
+    pub fn clear_read_position(&mut self) {
+        self.read_position = None;
+    }
+
+    /// A peer connected and sent its bitfield
+    pub fn peer_bitfield(&mut self, bitfield: &BitSlice<u8, Msb0>) {
+        for index in bitfield.iter_ones() {
+            self.add_availability(index);
+        }
+    }
+
+    /// A peer announced a new piece
+    pub fn peer_have(&mut self, index: u32) {
+        self.add_availability(index as usize);
+    }
+
+    /// A peer went away, its pieces are no longer available from it
+    pub fn peer_disconnected(&mut self, bitfield: &BitSlice<u8, Msb0>) {
+        for index in bitfield.iter_ones() {
+            self.remove_availability(index);
+        }
+    }
+
+    /// Picks up to `count` blocks to request from a peer that has `peer_has`, marking them as requested
+    pub fn pick(&mut self, peer_has: &BitSlice<u8, Msb0>, count: usize) -> Vec<Block> {
+        let mut blocks = Vec::with_capacity(count);
+        if count == 0 {
+            return blocks;
+        }
+
+        for index in self.urgent(peer_has) {
+            if !self.partial.contains_key(&index) {
+                self.start_piece(index);
+            }
+            self.pick_blocks(index, count, &mut blocks);
+            if blocks.len() == count {
+                return blocks;
+            }
+        }
+
+        // Partial pieces first, so they can be verified and shared as soon as possible
+        let mut partial: Vec<u32> = self
+            .partial
+            .iter()
+            .filter(|(index, piece)| {
+                self.wants(**index, peer_has) && piece.blocks.contains(&BlockState::Open)
+            })
+            .map(|(index, _)| *index)
+            .collect();
+        partial.sort_unstable_by_key(|index| {
+            (
+                PRIORITY_MAX - self.priorities[*index as usize],
+                self.partial[index].count(BlockState::Open),
+                *index,
+            )
+        });
+
+        for index in partial {
+            self.pick_blocks(index, count, &mut blocks);
+            if blocks.len() == count {
+                return blocks;
+            }
+        }
+
+        let candidates = if !self.sequential && self.have.count_ones() < RANDOM_PIECES {
+            let mut candidates: Vec<u32> = (0..self.piece_count() as u32)
+                .filter(|index| self.is_candidate(*index, peer_has))
+                .collect();
+            // The shuffle breaks ties since the sort is stable
+            candidates.shuffle(&mut self.rng);
+            candidates.sort_by_key(|index| PRIORITY_MAX - self.priorities[*index as usize]);
+            candidates
+        } else {
+            if self.order_dirty {
+                self.sort_order();
+            }
+            // Only as many pieces as it takes to fill the request, new pieces have no block taken
+            let mut needed = count - blocks.len();
+            let priorities = &self.priorities;
+            let candidates: Box<dyn Iterator<Item = u32>> = match self.sequential {
+                true => Box::new((PRIORITY_SKIP + 1..=PRIORITY_MAX).rev().flat_map(
+                    move |priority| {
+                        (0..priorities.len() as u32)
+                            .filter(move |index| priorities[*index as usize] == priority)
+                    },
+                )),
+                false => Box::new(self.order.iter().copied()),
+            };
+            candidates
+                .filter(|index| self.is_candidate(*index, peer_has))
+                .take_while(|index| {
+                    let more = needed > 0;
+                    needed = needed
+                        .saturating_sub(self.piece_length(*index).div_ceil(BLOCK_SIZE) as usize);
+                    more
+                })
+                .collect()
+        };
+
+        for index in candidates {
+            self.start_piece(index);
+            self.pick_blocks(index, count, &mut blocks);
+            if blocks.len() == count {
+                break;
+            }
+        }
+
+        blocks
+    }
+
+    /// Whether every block left to download was already requested.
+    ///
+    /// From here on the remaining blocks are requested from every peer that has them, see [`Self::pick_endgame`].
+    pub fn is_endgame(&self) -> bool {
+        let started = self
+            .have
+            .iter()
+            .zip(&self.priorities)
+            .enumerate()
+            .filter(|(_, (have, priority))| !**have && **priority != PRIORITY_SKIP)
+            .all(|(index, _)| self.partial.contains_key(&(index as u32)));
+
+        started
+            && self
+                .partial
+                .values()
+                .all(|piece| !piece.blocks.contains(&BlockState::Open))
+    }
+
+    /// Picks blocks that are already requested from other peers.
+    ///
+    /// `requested` tells whether this peer already has a request for the block.
+    /// Once any copy arrives the other requests should be cancelled.
+    pub fn pick_endgame(
+        &mut self,
+        peer_has: &BitSlice<u8, Msb0>,
+        count: usize,
+        requested: impl Fn(&Block) -> bool,
+    ) -> Vec<Block> {
+        let mut blocks = Vec::new();
+        let mut pieces: Vec<u32> = self
+            .partial
+            .keys()
+            .filter(|index| self.wants(**index, peer_has))
+            .copied()
+            .collect();
+        pieces.sort_unstable_by_key(|index| {
+            (PRIORITY_MAX - self.priorities[*index as usize], *index)
+        });
+
+        for index in pieces {
+            let piece_length = self.piece_length(index);
+            let piece = self
+                .partial
+                .get_mut(&index)
+                .expect("Partial piece should exist");
+
+            for (block_index, state) in piece.blocks.iter_mut().enumerate() {
+                if blocks.len() == count {
+                    return blocks;
+                }
+
+                let begin = block_index as u32 * BLOCK_SIZE;
+                let block = Block::new(index, begin, BLOCK_SIZE.min(piece_length - begin));
+                if let BlockState::Requested(peers) = state {
+                    if !requested(&block) {
+                        *peers += 1;
+                        self.duplicate_requests += 1;
+                        blocks.push(block);
+                    }
+                }
+            }
+        }
+
+        blocks
+    }
+
+    /// Whether a peer can ask us for a block: it's in a piece we have and not bigger than [`BLOCK_SIZE`]
+    pub fn is_valid_request(&self, block: &Block) -> bool {
+        (block.index as usize) < self.piece_count()
+            && self.has_piece(block.index)
+            && block.length > 0
+            && block.length <= BLOCK_SIZE
+            && block
+                .begin
+                .checked_add(block.length)
+                .is_some_and(|end| end <= self.piece_length(block.index))
+    }
+
+    /// Whether a block is exactly one of the blocks its piece is split into, see [`Block::split`]
+    pub fn is_whole_block(&self, block: &Block) -> bool {
+        self.block_slot(block).is_some()
+    }
+
+    /// Position of a block in its piece, `None` if it's misaligned or the wrong size
+    fn block_slot(&self, block: &Block) -> Option<usize> {
+        if block.index as usize >= self.piece_count() || !block.begin.is_multiple_of(BLOCK_SIZE) {
+            return None;
+        }
+        let piece_length = self.piece_length(block.index);
+        let expected = piece_length.checked_sub(block.begin)?.min(BLOCK_SIZE);
+        (expected > 0 && block.length == expected).then_some((block.begin / BLOCK_SIZE) as usize)
+    }
+
+    /// Whether a block belongs to a piece being downloaded and wasn't received yet
+    pub fn is_block_missing(&self, block: &Block) -> bool {
+        let Some(slot) = self.block_slot(block) else {
+            return false;
+        };
+        self.partial
+            .get(&block.index)
+            .and_then(|piece| piece.blocks.get(slot))
+            .is_some_and(|state| *state != BlockState::Received)
+    }
+
+    /// Marks a block as received, returns true when it was the last missing block of its piece.
+    ///
+    /// Blocks we already had are counted as wasted.
+    pub fn block_received(&mut self, block: &Block) -> bool {
+        let slot = self.block_slot(block);
+        let Some(state) = slot.and_then(|slot| {
+            self.partial
+                .get_mut(&block.index)
+                .and_then(|piece| piece.blocks.get_mut(slot))
+        }) else {
+            self.wasted_bytes += block.length as u64;
+            return false;
+        };
+        if *state == BlockState::Received {
+            self.wasted_bytes += block.length as u64;
+            return false;
+        }
+
+        *state = BlockState::Received;
+        let piece = &self.partial[&block.index];
+        piece.count(BlockState::Received) == piece.blocks.len()
+    }
+
+    /// Blocks received so far for every piece that was started but not verified
+    pub fn unfinished(&self) -> Vec<(u32, BitVec<u8, Msb0>)> {
+        let mut unfinished: Vec<(u32, BitVec<u8, Msb0>)> = self
+            .partial
+            .iter()
+            .map(|(index, piece)| {
+                let received = piece
+                    .blocks
+                    .iter()
+                    .map(|state| *state == BlockState::Received)
+                    .collect();
+                (*index, received)
+            })
+            .filter(|(_, received): &(u32, BitVec<u8, Msb0>)| received.any())
+            .collect();
+        unfinished.sort_unstable_by_key(|(index, _)| *index);
+        unfinished
+    }
+
+    /// Restores a piece that was partially downloaded, `received` has a bit for every block
+    pub fn set_unfinished(&mut self, index: u32, received: &BitSlice<u8, Msb0>) {
+        if self.have.get(index as usize).is_none_or(|have| *have) {
+            return;
+        }
+
+        let block_count = self.piece_length(index).div_ceil(BLOCK_SIZE) as usize;
+        let blocks = (0..block_count)
+            .map(|block| match received.get(block).is_some_and(|bit| *bit) {
+                true => BlockState::Received,
+                false => BlockState::Open,
+            })
+            .collect();
+        self.partial.insert(index, PartialPiece { blocks });
+    }
+
+    /// A request was cancelled, rejected or timed out so the block can be picked again
+    pub fn abort_block(&mut self, block: &Block) {
+        let Some(slot) = self.block_slot(block) else {
+            return;
+        };
+        if let Some(state) = self
+            .partial
+            .get_mut(&block.index)
+            .and_then(|piece| piece.blocks.get_mut(slot))
+        {
+            match state {
+                BlockState::Requested(1) => *state = BlockState::Open,
+                BlockState::Requested(peers) => *peers -= 1,
+                _ => {}
+            }
+        }
+    }
+
+    /// The piece passed the hash check
+    pub fn piece_verified(&mut self, index: u32) {
+        let Some(mut have) = self.have.get_mut(index as usize) else {
+            return;
+        };
+        *have = true;
+        self.partial.remove(&index);
+        self.deadlines.remove(&index);
+    }
+
+    /// The storage dropped a verified piece, it has to be downloaded again before it can be read
+    pub fn piece_evicted(&mut self, index: u32) {
+        if let Some(mut have) = self.have.get_mut(index as usize) {
+            *have = false;
+        }
+    }
+
+    /// The piece failed the hash check and has to be downloaded again
+    pub fn piece_failed(&mut self, index: u32) {
+        self.partial.remove(&index);
+    }
+
+    /// Bytes downloaded more than once, mostly because of endgame mode
+    pub const fn wasted_bytes(&self) -> u64 {
+        self.wasted_bytes
+    }
+
+    /// Counts bytes that were received but thrown away before reaching the picker
+    pub fn add_wasted(&mut self, bytes: u64) {
+        self.wasted_bytes += bytes;
+    }
+
+    pub const fn duplicate_requests(&self) -> u64 {
+        self.duplicate_requests
+    }
+
+    fn wants(&self, index: u32, peer_has: &BitSlice<u8, Msb0>) -> bool {
+        !self.has_piece(index)
+            && self.priority(index) != PRIORITY_SKIP
+            && peer_has.get(index as usize).is_some_and(|bit| *bit)
+    }
+
+    /// A piece nobody started yet that we could pick from the peer
+    fn is_candidate(&self, index: u32, peer_has: &BitSlice<u8, Msb0>) -> bool {
+        self.wants(index, peer_has) && !self.partial.contains_key(&index)
+    }
+
+    fn order_key(&self, index: u32) -> (u8, u32) {
+        (
+            PRIORITY_MAX - self.priorities[index as usize],
+            self.availability[index as usize],
+        )
+    }
+
+    /// Sorts `order` from scratch, the stable sort keeps ties in their random order
+    fn sort_order(&mut self) {
+        let mut order = std::mem::take(&mut self.order);
+        order.sort_by_key(|index| self.order_key(*index));
+        for (position, index) in order.iter().enumerate() {
+            self.positions[*index as usize] = position;
+        }
+        self.order = order;
+        self.order_dirty = false;
+    }
+
+    fn swap_order(&mut self, a: usize, b: usize) {
+        self.order.swap(a, b);
+        self.positions[self.order[a] as usize] = a;
+        self.positions[self.order[b] as usize] = b;
+    }
+
+    /// Moving a piece to the end of the pieces with the same key before its availability goes up
+    /// keeps `order` sorted
+    fn add_availability(&mut self, index: usize) {
+        if index >= self.piece_count() {
+            return;
+        }
+        if !self.order_dirty {
+            let key = self.order_key(index as u32);
+            let last = self
+                .order
+                .partition_point(|other| self.order_key(*other) <= key)
+                - 1;
+            self.swap_order(self.positions[index], last);
+        }
+        self.availability[index] += 1;
+    }
+
+    /// Same as [`Self::add_availability`], moving the piece to the start of its key instead
+    fn remove_availability(&mut self, index: usize) {
+        if self
+            .availability
+            .get(index)
+            .is_none_or(|availability| *availability == 0)
+        {
+            return;
+        }
+        if !self.order_dirty {
+            let key = self.order_key(index as u32);
+            let first = self
+                .order
+                .partition_point(|other| self.order_key(*other) < key);
+            self.swap_order(self.positions[index], first);
+        }
+        self.availability[index] -= 1;
+    }
+
+    /// Pieces with a deadline, earliest first, then the read-ahead window in order
+    fn urgent(&self, peer_has: &BitSlice<u8, Msb0>) -> Vec<u32> {
+        let mut deadlines: Vec<(Instant, u32)> = self
+            .deadlines
+            .iter()
+            .filter(|(index, _)| self.wants(**index, peer_has))
+            .map(|(index, deadline)| (*deadline, *index))
+            .collect();
+        deadlines.sort_unstable();
+        let window = match self.read_position {
+            Some(position) => {
+                position
+                    ..position
+                        .saturating_add(self.read_ahead)
+                        .min(self.piece_count() as u32)
+            }
+            None => 0..0,
+        };
+        let window = window
+            .filter(|index| self.wants(*index, peer_has) && !self.deadlines.contains_key(index));
+
+        deadlines
+            .into_iter()
+            .map(|(_, index)| index)
+            .chain(window)
+            .filter(|index| {
+                self.partial
+                    .get(index)
+                    .is_none_or(|piece| piece.blocks.contains(&BlockState::Open))
+            })
+            .collect()
+    }
+
+    fn start_piece(&mut self, index: u32) {
+        let block_count = self.piece_length(index).div_ceil(BLOCK_SIZE) as usize;
+        self.partial.insert(
+            index,
+            PartialPiece {
+                blocks: vec![BlockState::Open; block_count],
+            },
+        );
+    }
+
+    fn pick_blocks(&mut self, index: u32, count: usize, blocks: &mut Vec<Block>) {
+        let piece_length = self.piece_length(index);
+        let piece = self
+            .partial
+            .get_mut(&index)
+            .expect("Partial piece should exist");
+
+        for (block_index, state) in piece.blocks.iter_mut().enumerate() {
+            if blocks.len() == count {
+                break;
+            }
+            if *state == BlockState::Open {
+                *state = BlockState::Requested(1);
+                let begin = block_index as u32 * BLOCK_SIZE;
+                blocks.push(Block::new(
+                    index,
+                    begin,
+                    BLOCK_SIZE.min(piece_length - begin),
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn bitfield(pieces: &[usize], len: usize) -> BitVec<u8, Msb0> {
+        let mut bitfield = BitVec::repeat(false, len);
+        for piece in pieces {
+            bitfield.set(*piece, true);
+        }
+        bitfield
+    }
+
+    /// Downloads and verifies a piece so random picking is over
+    fn complete(picker: &mut PiecePicker, index: u32) {
+        picker.partial.remove(&index);
+        picker.piece_verified(index);
+    }
+
+    #[test]
+    fn piece_layout() {
+        let picker = PiecePicker::new(BLOCK_SIZE * 2, BLOCK_SIZE as u64 * 5 + 10);
+        assert_eq!(picker.piece_count(), 3);
+        assert_eq!(picker.piece_length(0), BLOCK_SIZE * 2);
+        assert_eq!(picker.piece_length(2), BLOCK_SIZE + 10);
+
+        assert!(picker.is_whole_block(&Block::new(0, BLOCK_SIZE, BLOCK_SIZE)));
+        assert!(picker.is_whole_block(&Block::new(2, BLOCK_SIZE, 10)));
+        assert!(!picker.is_whole_block(&Block::new(0, 1, BLOCK_SIZE)));
+        assert!(!picker.is_whole_block(&Block::new(0, 0, 5)));
+        assert!(!picker.is_whole_block(&Block::new(2, BLOCK_SIZE * 2, 0)));
+        assert!(!picker.is_whole_block(&Block::new(3, 0, BLOCK_SIZE)));
+    }
+
+    #[test]
+    fn valid_requests() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE * 2, BLOCK_SIZE as u64 * 5 + 10);
+        complete(&mut picker, 0);
+        complete(&mut picker, 2);
+
+        assert!(picker.is_valid_request(&Block::new(0, BLOCK_SIZE, BLOCK_SIZE)));
+        assert!(picker.is_valid_request(&Block::new(2, BLOCK_SIZE, 10)));
+        assert!(picker.is_valid_request(&Block::new(0, 100, 1000)));
+        // Pieces we don't have, too big or out of range
+        assert!(!picker.is_valid_request(&Block::new(1, 0, BLOCK_SIZE)));
+        assert!(!picker.is_valid_request(&Block::new(3, 0, BLOCK_SIZE)));
+        assert!(!picker.is_valid_request(&Block::new(0, 0, BLOCK_SIZE * 2)));
+        assert!(!picker.is_valid_request(&Block::new(2, BLOCK_SIZE, 11)));
+        assert!(!picker.is_valid_request(&Block::new(0, u32::MAX, 2)));
+        assert!(!picker.is_valid_request(&Block::new(0, 0, 0)));
+    }
+
+    #[test]
+    fn out_of_range_pieces() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE, BLOCK_SIZE as u64 * 3);
+        picker.set_priority(3, PRIORITY_MAX);
+        picker.set_piece_deadline(3, Instant::now());
+        picker.set_unfinished(3, &bitfield(&[0], 1));
+        picker.piece_verified(3);
+        picker.piece_failed(3);
+
+        assert!(!picker.has_piece(3));
+        assert_eq!(picker.piece_length(2), BLOCK_SIZE);
+        assert_eq!(picker.piece_length(3), 0);
+        assert_eq!(picker.piece_length(u32::MAX), 0);
+        assert_eq!(picker.availability(u32::MAX), 0);
+        assert_eq!(picker.priority(3), PRIORITY_SKIP);
+        assert_eq!(picker.piece_deadline(3), None);
+        assert!(picker.unfinished().is_empty());
+    }
+
+    #[test]
+    fn rarest_first() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE, BLOCK_SIZE as u64 * 10);
+        let all = BitVec::repeat(true, 10);
+        picker.peer_bitfield(&all);
+        picker.peer_bitfield(&bitfield(&[5, 6, 7, 8, 9], 10));
+        picker.peer_bitfield(&bitfield(&[6, 7, 8, 9], 10));
+        picker.peer_have(9);
+        for index in 0..4 {
+            complete(&mut picker, index);
+        }
+
+        // 4 is only on one peer, 5 on two and so on
+        let picked: Vec<u32> = picker
+            .pick(&all, 3)
+            .iter()
+            .map(|block| block.index)
+            .collect();
+        assert_eq!(&picked[..2], &[4, 5]);
+        assert!(picked[2] == 6 || picked[2] == 7 || picked[2] == 8);
+        assert_eq!(picker.distributed_copies(), 1.5);
+
+        picker.peer_disconnected(&all);
+        assert_eq!(picker.availability(4), 0);
+        assert_eq!(picker.availability(9), 3);
+    }
+
+    #[test]
+    fn order_follows_availability() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE, BLOCK_SIZE as u64 * 50);
+        let mut peers = Vec::new();
+        for round in 0..200 {
+            let peer: BitVec<u8, Msb0> = (0..50).map(|_| rand::random::<bool>()).collect();
+            picker.peer_bitfield(&peer);
+            peers.push(peer);
+            picker.peer_have(rand::random::<u32>() % 60);
+            if round % 3 == 0 {
+                let peer = peers.swap_remove(rand::random::<usize>() % peers.len());
+                picker.peer_disconnected(&peer);
+            }
+            if round % 50 == 0 {
+                picker.set_priority(rand::random::<u32>() % 50, rand::random::<u8>() % 8);
+            }
+            if picker.order_dirty {
+                picker.sort_order();
+            }
+
+            assert!(picker
+                .order
+                .windows(2)
+                .all(|pair| picker.order_key(pair[0]) <= picker.order_key(pair[1])));
+            for (position, index) in picker.order.iter().enumerate() {
+                assert_eq!(picker.positions[*index as usize], position);
+            }
+        }
+    }
+
+    #[test]
+    fn random_first_pieces() {
+        let all = BitVec::repeat(true, 64);
+        let mut first = Vec::new();
+
+        for _ in 0..10 {
+            let mut picker = PiecePicker::new(BLOCK_SIZE, BLOCK_SIZE as u64 * 64);
+            picker.peer_bitfield(&all);
+            picker.peer_have(0);
+            first.push(picker.pick(&all, 1)[0].index);
+        }
+
+        // Picking the rarest would always start with the pieces 1 to 63
+        first.dedup();
+        assert!(first.len() > 1);
+    }
+
+    #[test]
+    fn finishes_partial_pieces() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE * 4, BLOCK_SIZE as u64 * 16);
+        let all = BitVec::repeat(true, 4);
+        picker.peer_bitfield(&all);
+
+        let first = picker.pick(&all, 2);
+        assert_eq!(first.len(), 2);
+        assert_eq!(first[0].index, first[1].index);
+
+        // A different peer picks the rest of the same piece before anything else
+        let index = first[0].index;
+        let second = picker.pick(&all, 3);
+        assert!(second[..2].iter().all(|block| block.index == index));
+        assert_ne!(second[2].index, index);
+
+        // Aborted blocks are picked again
+        picker.abort_block(&first[1]);
+        assert_eq!(picker.pick(&all, 1), vec![first[1]]);
+
+        for block in first.iter().chain(&second[..2]) {
+            assert_eq!(picker.block_received(block), *block == second[1]);
+        }
+    }
+
+    #[test]
+    fn endgame() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE * 2, BLOCK_SIZE as u64 * 4);
+        let all = BitVec::repeat(true, 2);
+        picker.peer_bitfield(&all);
+        picker.peer_bitfield(&all);
+
+        let first = picker.pick(&all, 3);
+        assert!(!picker.is_endgame());
+        let second = picker.pick(&all, 3);
+        assert_eq!(second.len(), 1);
+        assert!(picker.is_endgame());
+        assert!(picker.pick(&all, 3).is_empty());
+
+        // The second peer gets everything it didn't request yet
+        let mut duplicates = picker.pick_endgame(&all, 10, |block| second.contains(block));
+        let mut first = first;
+        duplicates.sort_by_key(|block| (block.index, block.begin));
+        first.sort_by_key(|block| (block.index, block.begin));
+        assert_eq!(duplicates, first);
+        assert_eq!(picker.duplicate_requests(), 3);
+
+        // Rejects that don't line up with a block are ignored
+        let unaligned = Block::new(first[0].index, first[0].begin + 1, BLOCK_SIZE - 1);
+        picker.abort_block(&unaligned);
+        picker.abort_block(&unaligned);
+
+        // One of the copies timing out doesn't make the block available again
+        picker.abort_block(&first[0]);
+        assert!(picker.pick(&all, 1).is_empty());
+
+        assert!(!picker.block_received(&first[0]));
+        assert!(!picker.block_received(&first[0]));
+        assert_eq!(picker.wasted_bytes(), BLOCK_SIZE as u64);
+
+        picker.block_received(&first[1]);
+        picker.piece_verified(first[0].index);
+        picker.block_received(&first[1]);
+        assert_eq!(picker.wasted_bytes(), BLOCK_SIZE as u64 * 2);
+    }
+
+    #[test]
+    fn priorities() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE, BLOCK_SIZE as u64 * 6);
+        let all = BitVec::repeat(true, 6);
+        picker.peer_bitfield(&all);
+
+        picker.set_priority(3, PRIORITY_MAX);
+        picker.set_priority(1, 12);
+        assert_eq!(picker.priority(1), PRIORITY_MAX);
+        for index in [0, 2, 4, 5] {
+            picker.set_priority(index, PRIORITY_SKIP);
+        }
+
+        let mut picked: Vec<u32> = picker
+            .pick(&all, 10)
+            .iter()
+            .map(|block| block.index)
+            .collect();
+        picked.sort_unstable();
+        assert_eq!(picked, vec![1, 3]);
+
+        picker.piece_verified(1);
+        assert!(!picker.is_complete());
+        picker.piece_failed(3);
+        assert_eq!(
+            picker.pick(&bitfield(&[3], 6), 10),
+            vec![Block::new(3, 0, BLOCK_SIZE)]
+        );
+        picker.piece_verified(3);
+        assert!(picker.is_complete());
+    }
+
+    #[test]
+    fn streaming() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE, BLOCK_SIZE as u64 * 10);
+        let all = BitVec::repeat(true, 10);
+        picker.peer_bitfield(&all);
+        for index in 0..RANDOM_PIECES as u32 {
+            complete(&mut picker, index + 6);
+        }
+        // Rarest first would go for these
+        picker.peer_bitfield(&bitfield(&[0, 1, 2, 3, 4], 10));
+
+        picker.set_sequential(true);
+        assert_eq!(picker.pick(&all, 1), vec![Block::new(0, 0, BLOCK_SIZE)]);
+
+        // Deadlines beat priorities and the read-ahead window, earliest first
+        let now = Instant::now();
+        picker.set_priority(3, PRIORITY_MAX);
+        picker.set_piece_deadline(4, now + Duration::from_secs(2));
+        picker.set_piece_deadline(2, now + Duration::from_secs(1));
+        picker.set_read_ahead(2);
+        picker.set_read_position(4);
+        let picked: Vec<u32> = picker
+            .pick(&all, 4)
+            .iter()
+            .map(|block| block.index)
+            .collect();
+        assert_eq!(picked, vec![2, 4, 5, 3]);
+
+        picker.piece_verified(2);
+        assert_eq!(picker.piece_deadline(2), None);
+        assert_eq!(picker.piece_deadline(4), Some(now + Duration::from_secs(2)));
+    }
+}
@@ -0,0 +1,268 @@
+use indexmap::IndexMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Size of the blocks pieces are requested in
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+
+/// Queue depth used for peers that don't advertise `reqq`
+const DEFAULT_MAX_DEPTH: usize = 250;
+const MIN_DEPTH: usize = 2;
+const INITIAL_DEPTH: usize = 4;
+/// How much of the bandwidth delay product we keep queued on top of what's in flight
+const DEPTH_SLACK: f64 = 1.5;
+/// Requests are never considered lost before this
+const MIN_REQUEST_TIMEOUT: Duration = Duration::from_secs(20);
+/// Throughput is sampled over windows of this length
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A range of a piece
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Block {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+impl Block {
+    pub const fn new(index: u32, begin: u32, length: u32) -> Self {
+        Self {
+            index,
+            begin,
+            length,
+        }
+    }
+
+    /// Splits a piece into blocks of [`BLOCK_SIZE`], the last one can be shorter
+    pub fn split(index: u32, piece_length: u32) -> impl Iterator<Item = Block> {
+        (0..piece_length)
+            .step_by(BLOCK_SIZE as usize)
+            .map(move |begin| Block::new(index, begin, BLOCK_SIZE.min(piece_length - begin)))
+    }
+}
+
+/// Tracks the requests sent to a single peer.
+///
+/// The number of outstanding requests follows the bandwidth delay product of the connection:
+/// it grows with throughput and round trip time and is capped by the `reqq` the peer advertised.
+#[derive(Debug)]
+pub struct Pipeline {
+    /// Outstanding requests in the order they were sent
+    outstanding: IndexMap<Block, Instant>,
+    depth: usize,
+    max_depth: usize,
+    /// Smoothed time between a request and its block
+    rtt: Option<Duration>,
+    /// Smoothed bytes per second
+    throughput: Option<f64>,
+    sample_start: Option<Instant>,
+    sample_bytes: u64,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self {
+            outstanding: IndexMap::new(),
+            depth: INITIAL_DEPTH,
+            max_depth: DEFAULT_MAX_DEPTH,
+            rtt: None,
+            throughput: None,
+            sample_start: None,
+            sample_bytes: 0,
+        }
+    }
+
+    /// Caps the queue depth to the `reqq` advertised by the peer
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth.max(1);
+        self.depth = self.depth.min(self.max_depth);
+    }
+
+    pub const fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// How many more requests can be sent right now
+    pub fn slots(&self) -> usize {
+        self.depth.saturating_sub(self.outstanding.len())
+    }
+
+    pub fn outstanding(&self) -> impl Iterator<Item = &Block> {
+        self.outstanding.keys()
+    }
+
+    pub fn is_requested(&self, block: &Block) -> bool {
+        self.outstanding.contains_key(block)
+    }
+
+    pub fn on_request(&mut self, block: Block, now: Instant) {
+        self.outstanding.insert(block, now);
+        self.sample_start.get_or_insert(now);
+    }
+
+    /// Records a received block, returns false if it was never requested
+    pub fn on_block(&mut self, block: &Block, now: Instant) -> bool {
+        let Some(sent) = self.outstanding.shift_remove(block) else {
+            return false;
+        };
+
+        let sample = now.duration_since(sent);
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => rtt * 7 / 8 + sample / 8,
+            None => sample,
+        });
+
+        self.sample_bytes += block.length as u64;
+        let sample_start = *self.sample_start.get_or_insert(sent);
+        let elapsed = now.duration_since(sample_start);
+        if elapsed >= SAMPLE_INTERVAL {
+            let rate = self.sample_bytes as f64 / elapsed.as_secs_f64();
+            self.throughput = Some(match self.throughput {
+                Some(throughput) => throughput * 0.7 + rate * 0.3,
+                None => rate,
+            });
+            self.sample_start = Some(now);
+            self.sample_bytes = 0;
+        }
+
+        self.adapt();
+        true
+    }
+
+    /// Forgets a request, because it was rejected or cancelled
+    pub fn remove(&mut self, block: &Block) -> bool {
+        self.outstanding.shift_remove(block).is_some()
+    }
+
+    /// Drops every outstanding request, used when the peer chokes us
+    pub fn clear(&mut self) -> Vec<Block> {
+        self.sample_start = None;
+        self.sample_bytes = 0;
+        self.outstanding.drain(..).map(|(block, _)| block).collect()
+    }
+
+    /// How long a request can stay unanswered
+    pub fn request_timeout(&self) -> Duration {
+        self.rtt.map_or(MIN_REQUEST_TIMEOUT, |rtt| {
+            (rtt * 4).max(MIN_REQUEST_TIMEOUT)
+        })
+    }
+
+    /// When the oldest outstanding request times out
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.outstanding
+            .values()
+            .next()
+            .map(|sent| *sent + self.request_timeout())
+    }
+
+    /// Removes and returns the requests that went unanswered for too long.
+    ///
+    /// The queue depth is reset since the peer clearly can't keep up.
+    pub fn timed_out(&mut self, now: Instant) -> Vec<Block> {
+        let timeout = self.request_timeout();
+        let expired: Vec<Block> = self
+            .outstanding
+            .iter()
+            .take_while(|(_, sent)| now.duration_since(**sent) >= timeout)
+            .map(|(block, _)| *block)
+            .collect();
+
+        if !expired.is_empty() {
+            for block in &expired {
+                self.outstanding.shift_remove(block);
+            }
+            self.depth = MIN_DEPTH.min(self.max_depth);
+            self.throughput = None;
+        }
+
+        expired
+    }
+
+    fn adapt(&mut self) {
+        self.depth = match (self.throughput, self.rtt) {
+            (Some(throughput), Some(rtt)) => {
+                let bandwidth_delay = throughput * rtt.as_secs_f64() / BLOCK_SIZE as f64;
+                (bandwidth_delay * DEPTH_SLACK).ceil() as usize
+            }
+            // Grow by one block for every block received until there is a measurement
+            _ => self.depth + 1,
+        }
+        .clamp(MIN_DEPTH.min(self.max_depth), self.max_depth);
+    }
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_piece() {
+        let blocks: Vec<Block> = Block::split(3, BLOCK_SIZE * 2 + 100).collect();
+        assert_eq!(
+            blocks,
+            vec![
+                Block::new(3, 0, BLOCK_SIZE),
+                Block::new(3, BLOCK_SIZE, BLOCK_SIZE),
+                Block::new(3, BLOCK_SIZE * 2, 100)
+            ]
+        );
+    }
+
+    #[test]
+    fn depth_follows_bandwidth_delay_product() {
+        let mut pipeline = Pipeline::new();
+        let mut now = Instant::now();
+        assert_eq!(pipeline.slots(), INITIAL_DEPTH);
+
+        // A 1.6 MB/s peer with 100ms of latency: 10 blocks per 100ms
+        let mut blocks = Block::split(0, u32::MAX - BLOCK_SIZE);
+        for _ in 0..100 {
+            let block = blocks.next().unwrap();
+            pipeline.on_request(block, now);
+            now += Duration::from_millis(10);
+            pipeline.on_block(&block, now + Duration::from_millis(90));
+        }
+
+        assert!(pipeline.depth() >= 10 && pipeline.depth() <= 20);
+
+        pipeline.set_max_depth(5);
+        assert_eq!(pipeline.depth(), 5);
+    }
+
+    #[test]
+    fn unrequested_blocks_are_ignored() {
+        let mut pipeline = Pipeline::new();
+        let now = Instant::now();
+        pipeline.on_request(Block::new(0, 0, BLOCK_SIZE), now);
+
+        assert!(!pipeline.on_block(&Block::new(0, BLOCK_SIZE, BLOCK_SIZE), now));
+        assert!(pipeline.on_block(&Block::new(0, 0, BLOCK_SIZE), now));
+        assert!(!pipeline.on_block(&Block::new(0, 0, BLOCK_SIZE), now));
+    }
+
+    #[test]
+    fn timeouts_and_choke() {
+        let mut pipeline = Pipeline::new();
+        let now = Instant::now();
+
+        let blocks: Vec<Block> = Block::split(1, BLOCK_SIZE * 4).collect();
+        pipeline.on_request(blocks[0], now);
+        pipeline.on_request(blocks[1], now + Duration::from_secs(5));
+        pipeline.on_request(blocks[2], now + Duration::from_secs(30));
+        assert_eq!(pipeline.next_timeout(), Some(now + MIN_REQUEST_TIMEOUT));
+
+        let expired = pipeline.timed_out(now + Duration::from_secs(30));
+        assert_eq!(expired, &blocks[..2]);
+        assert_eq!(pipeline.depth(), MIN_DEPTH);
+
+        assert_eq!(pipeline.clear(), &blocks[2..3]);
+        assert_eq!(pipeline.slots(), MIN_DEPTH);
+    }
+}
@@ -2,13 +2,12 @@ use array_utils::ToArrayUnchecked;
 use bde::ByteString;
 use bytes::{Bytes, BytesMut};
 use color_eyre::eyre::{bail, eyre, Result};
-use indexmap::IndexMap;
 use nom::{
     bytes::complete::take, combinator::map_res, error::Error as NomError, multi::length_data,
     number::complete::be_u8, sequence::tuple, Finish,
 };
 use serde::{Deserialize, Serialize};
-use std::{collections::BTreeMap, net::IpAddr};
+use std::collections::BTreeMap;
 
 pub struct Handshake {
     pub reserved_bytes: [u8; 8],
@@ -84,4 +83,7 @@ pub struct ExtendedHandshake {
     pub reqq: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub metadata_size: Option<u32>,
+    /// Set to 1 by peers that won't download anything, see [BEP 21](https://www.bittorrent.org/beps/bep_0021.html)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload_only: Option<u8>,
 }
@@ -54,6 +54,24 @@ pub struct Piece {
     block: Bytes,
 }
 
+impl Piece {
+    pub const fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub const fn begin(&self) -> u32 {
+        self.begin
+    }
+
+    pub const fn block(&self) -> &Bytes {
+        &self.block
+    }
+
+    pub fn into_block(self) -> Bytes {
+        self.block
+    }
+}
+
 #[derive(Debug)]
 pub enum Message {
     KeepAlive,
@@ -146,9 +164,9 @@ impl Message {
             Message::Have(_) => 9,
             Message::Bitfield(bitfield) => 5 + bitfield.as_raw_slice().len(),
             Message::Request { .. } | Message::Cancel { .. } => 17,
-            Message::Piece(piece) => 9 + piece.block.len(),
+            Message::Piece(piece) => 13 + piece.block.len(),
             Message::Port(_) => 7,
-            Message::Extended { payload, .. } => payload.len() + 7,
+            Message::Extended { payload, .. } => payload.len() + 6,
             Message::Unknown { payload, .. } => payload.len() + 5,
         }
     }
@@ -0,0 +1,137 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use color_eyre::eyre::{bail, eyre, Result};
+use serde::{Deserialize, Serialize};
+
+/// Every piece of the metadata is this long, except for the last one
+pub const METADATA_PIECE_SIZE: usize = 16384;
+
+/// Messages of the metadata extension, see [BEP 9](https://www.bittorrent.org/beps/bep_0009.html)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request(u32),
+    Data {
+        piece: u32,
+        total_size: u32,
+        data: Bytes,
+    },
+    /// The peer doesn't have the piece or doesn't want to send it
+    Reject(u32),
+}
+
+#[derive(Deserialize, Serialize)]
+struct Header {
+    msg_type: u8,
+    piece: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_size: Option<u32>,
+}
+
+impl MetadataMessage {
+    pub fn to_bytes(&self) -> Result<Bytes> {
+        let (header, data) = match self {
+            MetadataMessage::Request(piece) => (
+                Header {
+                    msg_type: 0,
+                    piece: *piece,
+                    total_size: None,
+                },
+                None,
+            ),
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => (
+                Header {
+                    msg_type: 1,
+                    piece: *piece,
+                    total_size: Some(*total_size),
+                },
+                Some(data),
+            ),
+            MetadataMessage::Reject(piece) => (
+                Header {
+                    msg_type: 2,
+                    piece: *piece,
+                    total_size: None,
+                },
+                None,
+            ),
+        };
+
+        let mut bytes = BytesMut::from(&bde::to_bytes(&header)?[..]);
+        if let Some(data) = data {
+            bytes.put_slice(data);
+        }
+        Ok(bytes.freeze())
+    }
+
+    pub fn from_bytes(payload: &Bytes) -> Result<Self> {
+        // The data of a piece follows the dictionary, which has to be split off before decoding it
+        let end = bencode_end(payload).ok_or_else(|| eyre!("Invalid metadata message"))?;
+        let header: Header = bde::from_bytes(&payload[..end])?;
+
+        Ok(match header.msg_type {
+            0 => MetadataMessage::Request(header.piece),
+            1 => MetadataMessage::Data {
+                piece: header.piece,
+                total_size: header
+                    .total_size
+                    .ok_or_else(|| eyre!("Metadata piece without total size"))?,
+                data: payload.slice(end..),
+            },
+            2 => MetadataMessage::Reject(header.piece),
+            msg_type => bail!("Unknown metadata message type {}", msg_type),
+        })
+    }
+}
+
+/// Returns the length of the bencoded value at the start of `bytes`
+fn bencode_end(bytes: &[u8]) -> Option<usize> {
+    match bytes.first()? {
+        b'i' => Some(bytes.iter().position(|byte| *byte == b'e')? + 1),
+        b'l' | b'd' => {
+            let mut position = 1;
+            while *bytes.get(position)? != b'e' {
+                position += bencode_end(&bytes[position..])?;
+            }
+            Some(position + 1)
+        }
+        b'0'..=b'9' => {
+            let colon = bytes.iter().position(|byte| *byte == b':')?;
+            let length: usize = std::str::from_utf8(&bytes[..colon]).ok()?.parse().ok()?;
+            let end = colon.checked_add(length)?.checked_add(1)?;
+            (end <= bytes.len()).then_some(end)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for message in [
+            MetadataMessage::Request(3),
+            MetadataMessage::Reject(0),
+            MetadataMessage::Data {
+                piece: 1,
+                total_size: 20000,
+                data: Bytes::from_static(b"d4:name4:teste"),
+            },
+        ] {
+            let bytes = message.to_bytes().unwrap();
+            assert_eq!(MetadataMessage::from_bytes(&bytes).unwrap(), message);
+        }
+
+        let bytes = MetadataMessage::Request(2).to_bytes().unwrap();
+        assert_eq!(&bytes[..], b"d8:msg_typei0e5:piecei2ee");
+
+        assert!(MetadataMessage::from_bytes(&Bytes::from_static(b"d8:msg_typei1e")).is_err());
+        assert!(
+            MetadataMessage::from_bytes(&Bytes::from_static(b"d8:msg_typei7e5:piecei0ee")).is_err()
+        );
+    }
+}
@@ -1,7 +1,11 @@
 mod handshake;
 mod message;
+mod metadata;
+mod mse;
 mod wire;
 
 pub use handshake::{ExtendedHandshake, Handshake};
 pub use message::{Message, Piece};
-pub use wire::Wire;
+pub use metadata::{MetadataMessage, METADATA_PIECE_SIZE};
+pub use mse::{EncryptionPolicy, MseError, MseStream};
+pub use wire::{HandshakeError, PeerInfo, Wire};
@@ -0,0 +1,630 @@
+use bytes::{Buf, BytesMut};
+use num_bigint::BigUint;
+use sha1::{Digest, Sha1};
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// The 768 bit safe prime used for the Diffie-Hellman key exchange
+const DH_PRIME: [u8; 96] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xC9, 0x0F, 0xDA, 0xA2, 0x21, 0x68, 0xC2, 0x34,
+    0xC4, 0xC6, 0x62, 0x8B, 0x80, 0xDC, 0x1C, 0xD1, 0x29, 0x02, 0x4E, 0x08, 0x8A, 0x67, 0xCC, 0x74,
+    0x02, 0x0B, 0xBE, 0xA6, 0x3B, 0x13, 0x9B, 0x22, 0x51, 0x4A, 0x08, 0x79, 0x8E, 0x34, 0x04, 0xDD,
+    0xEF, 0x95, 0x19, 0xB3, 0xCD, 0x3A, 0x43, 0x1B, 0x30, 0x2B, 0x0A, 0x6D, 0xF2, 0x5F, 0x14, 0x37,
+    0x4F, 0xE1, 0x35, 0x6D, 0x6D, 0x51, 0xC2, 0x45, 0xE4, 0x85, 0xB5, 0x76, 0x62, 0x5E, 0x7E, 0xC6,
+    0xF4, 0x4C, 0x42, 0xE9, 0xA6, 0x3A, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63,
+];
+const DH_GENERATOR: u32 = 2;
+const DH_KEY_LEN: usize = 96;
+
+/// Verification constant, 8 zero bytes
+const VC: [u8; 8] = [0; 8];
+/// Maximum length of any of the random paddings
+const MAX_PAD_LEN: usize = 512;
+
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+/// The first 20 bytes of an unencrypted BitTorrent handshake
+const PLAINTEXT_HEADER: &[u8; 20] = b"\x13BitTorrent protocol";
+
+/// How a connection should negotiate Message Stream Encryption
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EncryptionPolicy {
+    /// Never encrypt, encrypted incoming connections are refused
+    PlaintextOnly,
+    /// Try to encrypt outgoing connections and accept both kinds of incoming connections
+    #[default]
+    PreferEncrypted,
+    /// Only ever use an RC4 encrypted payload stream
+    RequireEncrypted,
+}
+
+#[derive(Debug, Error)]
+pub enum MseError {
+    #[error("Io error during the encryption handshake")]
+    Io(#[from] io::Error),
+    /// The synchronization hash or verification constant wasn't found within the allowed padding
+    #[error("Couldn't synchronize with the remote peer")]
+    Sync,
+    /// The decrypted verification constant wasn't all zeros
+    #[error("Invalid verification constant")]
+    VerificationConstant,
+    /// The remote peer asked for a torrent we don't know about
+    #[error("Unknown info hash requested by peer")]
+    UnknownInfoHash,
+    /// The remote peer sent a padding longer than 512 bytes
+    #[error("Padding too long")]
+    PaddingTooLong,
+    /// No crypto method allowed by both sides
+    #[error("No common crypto method, provided {provided:#x} selected {selected:#x}")]
+    CryptoNegotiation { provided: u32, selected: u32 },
+    /// The policy forbids plaintext but the remote peer sent an unencrypted handshake
+    #[error("Plaintext connections are not allowed")]
+    PlaintextRejected,
+    /// The policy forbids encryption but the remote peer started an encrypted handshake
+    #[error("Encrypted connections are not allowed")]
+    EncryptionRejected,
+}
+
+/// RC4 stream cipher
+#[derive(Clone)]
+struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (i, s) in state.iter_mut().enumerate() {
+            *s = i as u8;
+        }
+
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+
+        Self { state, i: 0, j: 0 }
+    }
+
+    /// Creates the cipher used by mse, which discards the first 1024 bytes of the keystream
+    fn for_mse(key: &[u8]) -> Self {
+        let mut rc4 = Self::new(key);
+        rc4.apply(&mut [0; 1024]);
+        rc4
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state
+                [self.state[self.i as usize].wrapping_add(self.state[self.j as usize]) as usize];
+            *byte ^= k;
+        }
+    }
+}
+
+struct KeyPair {
+    private: BigUint,
+    public: [u8; DH_KEY_LEN],
+}
+
+impl KeyPair {
+    fn generate() -> Self {
+        let private = BigUint::from_bytes_be(&rand::random::<[u8; 20]>());
+        let public =
+            BigUint::from(DH_GENERATOR).modpow(&private, &BigUint::from_bytes_be(&DH_PRIME));
+
+        Self {
+            public: to_key_bytes(&public),
+            private,
+        }
+    }
+
+    fn shared_secret(&self, remote_public: &[u8]) -> [u8; DH_KEY_LEN] {
+        let remote_public = BigUint::from_bytes_be(remote_public);
+        to_key_bytes(&remote_public.modpow(&self.private, &BigUint::from_bytes_be(&DH_PRIME)))
+    }
+}
+
+/// Left pads a number to the 96 bytes expected on the wire
+fn to_key_bytes(number: &BigUint) -> [u8; DH_KEY_LEN] {
+    let bytes = number.to_bytes_be();
+    let mut key = [0u8; DH_KEY_LEN];
+    key[DH_KEY_LEN - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn random_padding() -> Vec<u8> {
+    let len = rand::random::<usize>() % (MAX_PAD_LEN + 1);
+    (0..len).map(|_| rand::random()).collect()
+}
+
+/// A stream that transparently encrypts and decrypts data once the mse handshake is done.
+///
+/// If plaintext was negotiated this is a thin passthrough.
+pub struct MseStream<S> {
+    inner: S,
+    read_cipher: Option<Rc4>,
+    write_cipher: Option<Rc4>,
+    /// Already decrypted data that has to be returned before reading from the inner stream
+    read_buffer: BytesMut,
+    /// Encrypted data that still has to be written to the inner stream
+    write_buffer: BytesMut,
+}
+
+impl<S> MseStream<S> {
+    /// Wraps a stream without doing any handshake
+    pub fn plaintext(inner: S) -> Self {
+        Self {
+            inner,
+            read_cipher: None,
+            write_cipher: None,
+            read_buffer: BytesMut::new(),
+            write_buffer: BytesMut::new(),
+        }
+    }
+
+    /// Whether the payload stream is RC4 encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.write_cipher.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> MseStream<S> {
+    /// Performs the outgoing side of the handshake.
+    ///
+    /// The info hash of the torrent is used as the shared secret SKEY.
+    pub async fn initiate(
+        mut stream: S,
+        info_hash: [u8; 20],
+        policy: EncryptionPolicy,
+    ) -> Result<Self, MseError> {
+        let provided = match policy {
+            EncryptionPolicy::PlaintextOnly => return Ok(Self::plaintext(stream)),
+            EncryptionPolicy::PreferEncrypted => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+            EncryptionPolicy::RequireEncrypted => CRYPTO_RC4,
+        };
+
+        // 1. A->B: Ya, PadA
+        let keys = KeyPair::generate();
+        let mut message = keys.public.to_vec();
+        message.extend(random_padding());
+        stream.write_all(&message).await?;
+
+        // 2. B->A: Yb, PadB
+        let mut raw = BytesMut::new();
+        fill(&mut stream, &mut raw, DH_KEY_LEN).await?;
+        let secret = keys.shared_secret(&raw.split_to(DH_KEY_LEN));
+
+        let mut encryptor = Rc4::for_mse(&hash(&[b"keyA", &secret, &info_hash]));
+        let mut decryptor = Rc4::for_mse(&hash(&[b"keyB", &secret, &info_hash]));
+
+        // 3. A->B: HASH('req1', S), HASH('req2', SKEY) xor HASH('req3', S), ENCRYPT(VC, crypto_provide, len(PadC), PadC, len(IA)), ENCRYPT(IA)
+        let mut message = hash(&[b"req1", &secret]).to_vec();
+        let req2 = hash(&[b"req2", &info_hash]);
+        let req3 = hash(&[b"req3", &secret]);
+        message.extend(req2.iter().zip(req3).map(|(a, b)| a ^ b));
+
+        let mut encrypted = VC.to_vec();
+        encrypted.extend(provided.to_be_bytes());
+        encrypted.extend(0u16.to_be_bytes()); // len(PadC)
+        encrypted.extend(0u16.to_be_bytes()); // len(IA), the bittorrent handshake is sent afterwards
+        encryptor.apply(&mut encrypted);
+        message.extend(encrypted);
+        stream.write_all(&message).await?;
+
+        // 4. B->A: ENCRYPT(VC, crypto_select, len(padD), padD), ENCRYPT2(Payload Stream)
+        // The encrypted VC is used to find the end of PadB
+        let mut vc = VC;
+        decryptor.clone().apply(&mut vc);
+        synchronize(&mut stream, &mut raw, &vc, MAX_PAD_LEN).await?;
+        decryptor.apply(&mut [0; 8]);
+
+        fill(&mut stream, &mut raw, 6).await?;
+        let mut header = raw.split_to(6);
+        decryptor.apply(&mut header);
+        let selected = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let pad_len = u16::from_be_bytes([header[4], header[5]]) as usize;
+
+        if pad_len > MAX_PAD_LEN {
+            return Err(MseError::PaddingTooLong);
+        }
+        fill(&mut stream, &mut raw, pad_len).await?;
+        decryptor.apply(&mut raw.split_to(pad_len));
+
+        if selected != CRYPTO_RC4 && selected != CRYPTO_PLAINTEXT || selected & provided == 0 {
+            return Err(MseError::CryptoNegotiation { provided, selected });
+        }
+
+        Ok(Self::established(
+            stream,
+            raw,
+            BytesMut::new(),
+            selected,
+            encryptor,
+            decryptor,
+        ))
+    }
+
+    /// Performs the incoming side of the handshake.
+    ///
+    /// Unencrypted handshakes are detected and, if the policy allows it, passed through untouched.
+    /// Returns the info hash the peer asked for if the connection is encrypted.
+    pub async fn accept(
+        mut stream: S,
+        info_hashes: &[[u8; 20]],
+        policy: EncryptionPolicy,
+    ) -> Result<(Self, Option<[u8; 20]>), MseError> {
+        let mut raw = BytesMut::new();
+        fill(&mut stream, &mut raw, PLAINTEXT_HEADER.len()).await?;
+
+        if raw[..] == PLAINTEXT_HEADER[..] {
+            if policy == EncryptionPolicy::RequireEncrypted {
+                return Err(MseError::PlaintextRejected);
+            }
+
+            // Give back what we read so the bittorrent handshake can be parsed as usual
+            let mut stream = Self::plaintext(stream);
+            stream.read_buffer = raw;
+            return Ok((stream, None));
+        }
+
+        if policy == EncryptionPolicy::PlaintextOnly {
+            return Err(MseError::EncryptionRejected);
+        }
+
+        // 1. A->B: Ya, PadA
+        fill(&mut stream, &mut raw, DH_KEY_LEN).await?;
+        let keys = KeyPair::generate();
+        let secret = keys.shared_secret(&raw.split_to(DH_KEY_LEN));
+
+        // 2. B->A: Yb, PadB
+        let mut message = keys.public.to_vec();
+        message.extend(random_padding());
+        stream.write_all(&message).await?;
+
+        // 3. A->B: HASH('req1', S), HASH('req2', SKEY) xor HASH('req3', S), ENCRYPT(VC, crypto_provide, len(PadC), PadC, len(IA)), ENCRYPT(IA)
+        synchronize(
+            &mut stream,
+            &mut raw,
+            &hash(&[b"req1", &secret]),
+            MAX_PAD_LEN,
+        )
+        .await?;
+
+        fill(&mut stream, &mut raw, 20).await?;
+        let obfuscated = raw.split_to(20);
+        let req3 = hash(&[b"req3", &secret]);
+        let info_hash = info_hashes
+            .iter()
+            .find(|info_hash| {
+                hash(&[b"req2", &info_hash[..]])
+                    .iter()
+                    .zip(req3)
+                    .map(|(a, b)| a ^ b)
+                    .eq(obfuscated.iter().copied())
+            })
+            .copied()
+            .ok_or(MseError::UnknownInfoHash)?;
+
+        let mut decryptor = Rc4::for_mse(&hash(&[b"keyA", &secret, &info_hash]));
+        let mut encryptor = Rc4::for_mse(&hash(&[b"keyB", &secret, &info_hash]));
+
+        fill(&mut stream, &mut raw, 14).await?;
+        let mut header = raw.split_to(14);
+        decryptor.apply(&mut header);
+        if header[..8] != VC {
+            return Err(MseError::VerificationConstant);
+        }
+        let provided = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+        let pad_len = u16::from_be_bytes([header[12], header[13]]) as usize;
+
+        if pad_len > MAX_PAD_LEN {
+            return Err(MseError::PaddingTooLong);
+        }
+        fill(&mut stream, &mut raw, pad_len + 2).await?;
+        decryptor.apply(&mut raw.split_to(pad_len));
+        let mut ia_len = raw.split_to(2);
+        decryptor.apply(&mut ia_len);
+        let ia_len = u16::from_be_bytes([ia_len[0], ia_len[1]]) as usize;
+
+        // The initial payload is usually the bittorrent handshake
+        fill(&mut stream, &mut raw, ia_len).await?;
+        let mut initial_payload = raw.split_to(ia_len);
+        decryptor.apply(&mut initial_payload);
+
+        let selected = if provided & CRYPTO_RC4 != 0 {
+            CRYPTO_RC4
+        } else if provided & CRYPTO_PLAINTEXT != 0 && policy == EncryptionPolicy::PreferEncrypted {
+            CRYPTO_PLAINTEXT
+        } else {
+            return Err(MseError::CryptoNegotiation {
+                provided,
+                selected: 0,
+            });
+        };
+
+        // 4. B->A: ENCRYPT(VC, crypto_select, len(padD), padD), ENCRYPT2(Payload Stream)
+        let mut message = VC.to_vec();
+        message.extend(selected.to_be_bytes());
+        message.extend(0u16.to_be_bytes());
+        encryptor.apply(&mut message);
+        stream.write_all(&message).await?;
+
+        Ok((
+            Self::established(stream, raw, initial_payload, selected, encryptor, decryptor),
+            Some(info_hash),
+        ))
+    }
+
+    /// Builds the stream once the handshake is complete.
+    ///
+    /// Any raw bytes left over from the handshake belong to the payload stream.
+    fn established(
+        inner: S,
+        mut raw: BytesMut,
+        mut read_buffer: BytesMut,
+        selected: u32,
+        encryptor: Rc4,
+        mut decryptor: Rc4,
+    ) -> Self {
+        let (read_cipher, write_cipher) = if selected == CRYPTO_RC4 {
+            decryptor.apply(&mut raw);
+            (Some(decryptor), Some(encryptor))
+        } else {
+            (None, None)
+        };
+        read_buffer.unsplit(raw);
+
+        Self {
+            inner,
+            read_cipher,
+            write_cipher,
+            read_buffer,
+            write_buffer: BytesMut::new(),
+        }
+    }
+
+    /// Writes out all the pending encrypted data
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buffer.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buffer))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buffer.advance(written);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Reads from the stream until the buffer contains at least `len` bytes
+async fn fill<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer: &mut BytesMut,
+    len: usize,
+) -> io::Result<()> {
+    while buffer.len() < len {
+        if stream.read_buf(buffer).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Skips the random padding until right after `pattern`, which must appear within `max_skip` bytes
+async fn synchronize<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer: &mut BytesMut,
+    pattern: &[u8],
+    max_skip: usize,
+) -> Result<(), MseError> {
+    loop {
+        if let Some(position) = buffer
+            .windows(pattern.len())
+            .position(|window| window == pattern)
+        {
+            buffer.advance(position + pattern.len());
+            return Ok(());
+        }
+
+        // Only the last pattern.len() - 1 bytes could still be the start of a match
+        if buffer.len().saturating_sub(pattern.len() - 1) > max_skip {
+            return Err(MseError::Sync);
+        }
+
+        if stream.read_buf(buffer).await? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if !this.read_buffer.is_empty() {
+            let len = this.read_buffer.len().min(buf.remaining());
+            buf.put_slice(&this.read_buffer.split_to(len));
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+
+        if let Some(cipher) = &mut this.read_cipher {
+            cipher.apply(&mut buf.filled_mut()[filled..]);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if this.write_cipher.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        // Once data is encrypted the keystream has moved forward so it must be written no matter what.
+        // We only accept new data after the previous batch is out of the door.
+        ready!(this.poll_drain(cx))?;
+
+        this.write_buffer.extend_from_slice(buf);
+        if let Some(cipher) = &mut this.write_cipher {
+            cipher.apply(&mut this.write_buffer);
+        }
+
+        // Pending is fine here, the data is already buffered and the waker is registered
+        if let Poll::Ready(Err(error)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(error));
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    const INFO_HASH: [u8; 20] = [7; 20];
+
+    #[test]
+    fn rc4_test_vector() {
+        let mut data = *b"Plaintext";
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(data, [0xBB, 0xF3, 0x16, 0xE8, 0xD9, 0x40, 0xAF, 0x0A, 0xD3]);
+    }
+
+    async fn connect(
+        outgoing: EncryptionPolicy,
+        incoming: EncryptionPolicy,
+    ) -> (
+        Result<MseStream<tokio::io::DuplexStream>, MseError>,
+        Result<(MseStream<tokio::io::DuplexStream>, Option<[u8; 20]>), MseError>,
+    ) {
+        let (a, b) = duplex(4096);
+        tokio::join!(
+            MseStream::initiate(a, INFO_HASH, outgoing),
+            MseStream::accept(b, &[[1; 20], INFO_HASH], incoming)
+        )
+    }
+
+    #[tokio::test]
+    async fn encrypted_roundtrip() {
+        let (a, b) = connect(
+            EncryptionPolicy::PreferEncrypted,
+            EncryptionPolicy::RequireEncrypted,
+        )
+        .await;
+        let mut a = a.unwrap();
+        let (mut b, info_hash) = b.unwrap();
+
+        assert!(a.is_encrypted() && b.is_encrypted());
+        assert_eq!(info_hash, Some(INFO_HASH));
+
+        a.write_all(b"hello from a").await.unwrap();
+        a.flush().await.unwrap();
+        b.write_all(b"hello from b").await.unwrap();
+        b.flush().await.unwrap();
+
+        let mut buffer = [0u8; 12];
+        b.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"hello from a");
+        a.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"hello from b");
+    }
+
+    #[tokio::test]
+    async fn plaintext_passthrough() {
+        let (a, b) = duplex(4096);
+        let mut a = MseStream::plaintext(a);
+        a.write_all(&PLAINTEXT_HEADER[..]).await.unwrap();
+
+        let (mut b, info_hash) =
+            MseStream::accept(b, &[INFO_HASH], EncryptionPolicy::PreferEncrypted)
+                .await
+                .unwrap();
+        assert!(!b.is_encrypted());
+        assert_eq!(info_hash, None);
+
+        let mut buffer = [0u8; 20];
+        b.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, PLAINTEXT_HEADER);
+    }
+
+    #[tokio::test]
+    async fn policies_are_enforced() {
+        let (a, b) = duplex(4096);
+        let mut a = MseStream::plaintext(a);
+        a.write_all(&PLAINTEXT_HEADER[..]).await.unwrap();
+        let result = MseStream::accept(b, &[INFO_HASH], EncryptionPolicy::RequireEncrypted).await;
+        assert!(matches!(result, Err(MseError::PlaintextRejected)));
+
+        let (_, b) = connect(
+            EncryptionPolicy::RequireEncrypted,
+            EncryptionPolicy::PlaintextOnly,
+        )
+        .await;
+        assert!(matches!(b, Err(MseError::EncryptionRejected)));
+    }
+
+    #[tokio::test]
+    async fn unknown_info_hash() {
+        let (a, b) = duplex(4096);
+        let (_, b) = tokio::join!(
+            MseStream::initiate(a, [9; 20], EncryptionPolicy::RequireEncrypted),
+            MseStream::accept(b, &[INFO_HASH], EncryptionPolicy::PreferEncrypted)
+        );
+        assert!(matches!(b, Err(MseError::UnknownInfoHash)));
+    }
+}
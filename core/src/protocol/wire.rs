@@ -1,6 +1,7 @@
 use bitvec::{order::Msb0, slice::BitSlice};
 use color_eyre::eyre::Result;
 use futures::SinkExt;
+use std::sync::Arc;
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Error as TokioIoError};
 use tokio_stream::StreamExt;
@@ -10,6 +11,10 @@ use super::{
     handshake::Handshake,
     message::{Message, MessageCodec},
 };
+use crate::{
+    limit::RateLimits,
+    stats::{split, TransferStats},
+};
 
 #[derive(Debug, Error)]
 pub enum HandshakeError {
@@ -28,10 +33,20 @@ pub enum HandshakeError {
         expected: [u8; 20],
         received: [u8; 20],
     },
+    /// The remote peer asked for a torrent we don't have
+    #[error("Unknown info hash requested by peer")]
+    UnknownInfoHash([u8; 20]),
 }
 
 pub struct Wire<S> {
     stream: Framed<S, MessageCodec>,
+    /// Every message that goes through is counted by each of these
+    stats: Vec<Arc<TransferStats>>,
+    /// Every message that goes through waits for each of these, in order
+    limits: Vec<Arc<RateLimits>>,
+    /// Payload and protocol bytes of the last message received, still owed to the download
+    /// limits starting from the one at the index
+    unpaid: Option<(usize, u64, u64)>,
 }
 
 #[derive(Debug)]
@@ -42,7 +57,31 @@ pub struct PeerInfo {
     pub dht_extension: bool,
 }
 
+impl PeerInfo {
+    fn from_handshake(handshake: &Handshake) -> Self {
+        // Parse the reserved bytes as bit flags
+        let reserved_bits: &BitSlice<u8, Msb0> =
+            unsafe { BitSlice::from_slice_unchecked(&handshake.reserved_bytes) };
+
+        Self {
+            peer_id: handshake.peer_id,
+            extension_protocol: reserved_bits[43],
+            fast_extension: reserved_bits[61],
+            dht_extension: reserved_bits[63],
+        }
+    }
+}
+
 impl<S: AsyncRead + AsyncWrite + Unpin> Wire<S> {
+    fn new(stream: S) -> Self {
+        Self {
+            stream: Framed::new(stream, MessageCodec::new()),
+            stats: Vec::new(),
+            limits: Vec::new(),
+            unpaid: None,
+        }
+    }
+
     pub async fn handshake(
         handshake: Handshake,
         mut stream: S,
@@ -53,51 +92,104 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Wire<S> {
             .await
             .map_err(HandshakeError::Send)?;
 
-        // Create a buffer for the handshake, fill it and then parse it.
-        let mut remote_handshake_buffer = [0u8; 68];
-        stream
-            .read_exact(&mut remote_handshake_buffer)
-            .await
-            .map_err(HandshakeError::Read)?;
-        let remote_handshake = Handshake::from_bytes(&remote_handshake_buffer)
-            .map_err(|_error| HandshakeError::Invalid(remote_handshake_buffer))?;
+        let remote_handshake = Self::read_handshake(&mut stream).await?;
 
         // Ensure the info hash matches.
         if handshake.info_hash != remote_handshake.info_hash {
             return Err(HandshakeError::InfoHash {
                 expected: handshake.info_hash,
-                received: handshake.info_hash,
+                received: remote_handshake.info_hash,
             });
         }
 
-        // Parse the reserved bytes as bit flags
-        let reserved_bits: &BitSlice<u8, Msb0> =
-            unsafe { BitSlice::from_slice_unchecked(&remote_handshake.reserved_bytes) };
+        Ok((
+            PeerInfo::from_handshake(&remote_handshake),
+            Self::new(stream),
+        ))
+    }
+
+    /// Answers the handshake of an incoming connection.
+    ///
+    /// The remote peer speaks first so we only reply if it asked for one of `info_hashes`.
+    /// Returns the info hash the connection is for.
+    pub async fn accept(
+        reserved_bytes: [u8; 8],
+        peer_id: [u8; 20],
+        info_hashes: &[[u8; 20]],
+        mut stream: S,
+    ) -> Result<(PeerInfo, [u8; 20], Self), HandshakeError> {
+        let remote_handshake = Self::read_handshake(&mut stream).await?;
 
-        let extension_protocol = reserved_bits[43];
-        let fast_extension = reserved_bits[61];
-        let dht_extension = reserved_bits[63];
+        if !info_hashes.contains(&remote_handshake.info_hash) {
+            return Err(HandshakeError::UnknownInfoHash(remote_handshake.info_hash));
+        }
+
+        let handshake = Handshake::new(reserved_bytes, remote_handshake.info_hash, peer_id);
+        stream
+            .write_all(&handshake.as_bytes())
+            .await
+            .map_err(HandshakeError::Send)?;
 
         Ok((
-            PeerInfo {
-                peer_id: remote_handshake.peer_id,
-                extension_protocol,
-                fast_extension,
-                dht_extension,
-            },
-            Self {
-                stream: Framed::new(stream, MessageCodec::new()),
-            },
+            PeerInfo::from_handshake(&remote_handshake),
+            remote_handshake.info_hash,
+            Self::new(stream),
         ))
     }
 
-    /// Read the next message in the stream
+    async fn read_handshake(stream: &mut S) -> Result<Handshake, HandshakeError> {
+        // Create a buffer for the handshake, fill it and then parse it.
+        let mut remote_handshake_buffer = [0u8; 68];
+        stream
+            .read_exact(&mut remote_handshake_buffer)
+            .await
+            .map_err(HandshakeError::Read)?;
+        Handshake::from_bytes(&remote_handshake_buffer)
+            .map_err(|_error| HandshakeError::Invalid(remote_handshake_buffer))
+    }
+
+    /// Counts the messages sent and received from now on
+    pub fn add_stats(&mut self, stats: Arc<TransferStats>) {
+        self.stats.push(stats);
+    }
+
+    /// Throttles the messages sent and received from now on
+    pub fn add_limits(&mut self, limits: Arc<RateLimits>) {
+        self.limits.push(limits);
+    }
+
+    /// Read the next message in the stream.
+    ///
+    /// This is cancel safe: a message is only paid for to the download limits before reading the
+    /// next one, so it's never lost while waiting for them.
     pub async fn read_message(&mut self) -> Result<Option<Message>> {
-        self.stream.try_next().await
+        while let Some((index, payload, protocol)) = self.unpaid {
+            match self.limits.get(index) {
+                Some(limits) => {
+                    limits.download.acquire_transfer(payload, protocol).await;
+                    self.unpaid = Some((index + 1, payload, protocol));
+                }
+                None => self.unpaid = None,
+            }
+        }
+
+        let message = self.stream.try_next().await?;
+        if let Some(message) = &message {
+            self.stats.iter().for_each(|stats| stats.received(message));
+            let (payload, protocol) = split(message);
+            self.unpaid = Some((0, payload, protocol));
+        }
+        Ok(message)
     }
 
-    /// Write a message to the internal sink, this does not flush
+    /// Write a message to the internal sink, this does not flush.
+    ///
+    /// Waits for the upload limits first.
     pub async fn write_message(&mut self, message: Message) -> Result<()> {
+        for limits in &self.limits {
+            limits.upload.acquire_message(&message).await;
+        }
+        self.stats.iter().for_each(|stats| stats.sent(&message));
         self.stream.feed(message).await
     }
 
@@ -109,11 +201,68 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Wire<S> {
 
 #[cfg(test)]
 mod tests {
-    // use super::*;
-    // use tokio_test::io::Builder as MockBuilder;
+    use super::*;
+    use bytes::Bytes;
+    use tokio::{io::duplex, time::Instant};
 
     #[tokio::test]
     async fn do_handshake() {
-        // let stream = MockBuilder::new().build();
+        let (a, b) = duplex(1024);
+        let info_hash = [1; 20];
+        let info_hashes = [info_hash];
+
+        let (outgoing, incoming) = tokio::join!(
+            Wire::handshake(
+                Handshake::new([0, 0, 0, 0, 0, 0x10, 0, 0], info_hash, [2; 20]),
+                a
+            ),
+            Wire::accept([0, 0, 0, 0, 0, 0, 0, 0x05], [3; 20], &info_hashes, b)
+        );
+
+        let (peer_info, _) = outgoing.unwrap();
+        assert_eq!(peer_info.peer_id, [3; 20]);
+        assert!(peer_info.fast_extension && peer_info.dht_extension);
+        assert!(!peer_info.extension_protocol);
+
+        let (peer_info, accepted_info_hash, _) = incoming.unwrap();
+        assert_eq!(peer_info.peer_id, [2; 20]);
+        assert_eq!(accepted_info_hash, info_hash);
+        assert!(peer_info.extension_protocol);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_and_stats() {
+        let (a, b) = duplex(1 << 16);
+        let info_hash = [1; 20];
+        let info_hashes = [info_hash];
+        let (outgoing, incoming) = tokio::join!(
+            Wire::handshake(Handshake::new([0; 8], info_hash, [2; 20]), a),
+            Wire::accept([0; 8], [3; 20], &info_hashes, b)
+        );
+        let (_, mut sender) = outgoing.unwrap();
+        let (_, _, mut receiver) = incoming.unwrap();
+
+        let limits = Arc::new(RateLimits::new(1000, 0));
+        let stats = Arc::new(TransferStats::new());
+        sender.add_limits(limits);
+        receiver.add_stats(stats.clone());
+
+        // Pieces of 500 bytes, the first 3 fit in the bucket
+        let start = Instant::now();
+        for _ in 0..4 {
+            let block = Bytes::from(vec![0; 487]);
+            sender
+                .write_message(Message::piece(0, 0, block))
+                .await
+                .unwrap();
+        }
+        sender.flush().await.unwrap();
+        assert_eq!(start.elapsed().as_millis(), 500);
+
+        for _ in 0..4 {
+            receiver.read_message().await.unwrap().unwrap();
+        }
+        assert_eq!(stats.snapshot().payload_download.total, 487 * 4);
+        assert_eq!(stats.snapshot().protocol_download.total, 13 * 4);
     }
 }
@@ -0,0 +1,184 @@
+//! Fast-resume data, so torrents can be restarted without hashing everything again
+
+use color_eyre::eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::{
+    collections::BTreeMap,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+use tokio::fs;
+
+use crate::{meta_info::Info, storage::FileMetadata};
+
+/// A piece that was partially downloaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnfinishedPiece {
+    pub piece: u32,
+    /// Bitfield of the blocks already written to storage
+    pub blocks: Vec<u8>,
+}
+
+/// Per-torrent state saved next to the download as bencode
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ResumeData {
+    pub info_hash: Vec<u8>,
+    /// Bitfield of the verified pieces
+    pub pieces: Vec<u8>,
+    pub unfinished: Vec<UnfinishedPiece>,
+    /// Piece priority of each file, see [`FilePriority::piece_priority`][crate::FilePriority::piece_priority]
+    pub file_priorities: Vec<u8>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    /// What the files looked like when the data was saved, missing files are all zeros
+    pub files: Vec<FileMetadata>,
+}
+
+/// The bencoded form of [`ResumeData`], only made of lists, dictionaries and plain values
+#[derive(Serialize, Deserialize)]
+struct RawResumeData {
+    #[serde(rename = "info-hash", with = "serde_bytes")]
+    info_hash: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pieces: Vec<u8>,
+    /// Piece index to block bitfield
+    unfinished: BTreeMap<String, ByteBuf>,
+    #[serde(rename = "file-priorities")]
+    file_priorities: Vec<u8>,
+    uploaded: u64,
+    downloaded: u64,
+    #[serde(rename = "file-sizes")]
+    file_sizes: Vec<u64>,
+    #[serde(rename = "file-mtimes")]
+    file_mtimes: Vec<u64>,
+}
+
+impl From<&ResumeData> for RawResumeData {
+    fn from(data: &ResumeData) -> Self {
+        Self {
+            info_hash: data.info_hash.clone(),
+            pieces: data.pieces.clone(),
+            unfinished: data
+                .unfinished
+                .iter()
+                .map(|piece| (piece.piece.to_string(), ByteBuf::from(piece.blocks.clone())))
+                .collect(),
+            file_priorities: data.file_priorities.clone(),
+            uploaded: data.uploaded,
+            downloaded: data.downloaded,
+            file_sizes: data.files.iter().map(|file| file.size).collect(),
+            file_mtimes: data.files.iter().map(|file| file.mtime).collect(),
+        }
+    }
+}
+
+impl TryFrom<RawResumeData> for ResumeData {
+    type Error = color_eyre::eyre::Error;
+
+    fn try_from(raw: RawResumeData) -> Result<Self> {
+        if raw.file_sizes.len() != raw.file_mtimes.len() {
+            return Err(eyre!("Mismatched file sizes and modification times"));
+        }
+
+        let mut unfinished = raw
+            .unfinished
+            .into_iter()
+            .map(|(piece, blocks)| {
+                Ok(UnfinishedPiece {
+                    piece: piece.parse()?,
+                    blocks: blocks.into_vec(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // Keys are sorted as strings
+        unfinished.sort_unstable_by_key(|piece| piece.piece);
+
+        Ok(Self {
+            info_hash: raw.info_hash,
+            pieces: raw.pieces,
+            unfinished,
+            file_priorities: raw.file_priorities,
+            uploaded: raw.uploaded,
+            downloaded: raw.downloaded,
+            files: raw
+                .file_sizes
+                .into_iter()
+                .zip(raw.file_mtimes)
+                .map(|(size, mtime)| FileMetadata { size, mtime })
+                .collect(),
+        })
+    }
+}
+
+impl ResumeData {
+    /// Where the resume data of a torrent downloaded to `root` is kept
+    pub fn path(root: &Path, info: &Info) -> PathBuf {
+        root.join(format!("{}.resume", info.name))
+    }
+
+    /// Reads resume data, returns `None` if there is none
+    pub async fn load(path: &Path) -> Result<Option<Self>> {
+        match fs::read(path).await {
+            Ok(data) => Ok(Some(bde::from_bytes::<RawResumeData>(&data)?.try_into()?)),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Writes resume data, replacing the old file only once the new one is complete
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        // Nothing else creates the directory when every file is skipped
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+
+        fs::write(&temporary, bde::to_bytes(&RawResumeData::from(self))?).await?;
+        fs::rename(&temporary, path).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    #[tokio::test]
+    async fn roundtrip() {
+        let path = temp_dir().join(format!("leech-{}.resume", rand::random::<u64>()));
+        assert_eq!(ResumeData::load(&path).await.unwrap(), None);
+
+        let data = ResumeData {
+            info_hash: vec![7; 20],
+            pieces: vec![0b1010_0000],
+            unfinished: vec![
+                UnfinishedPiece {
+                    piece: 2,
+                    blocks: vec![0b1000_0000],
+                },
+                UnfinishedPiece {
+                    piece: 10,
+                    blocks: vec![0b0100_0000, 0b1000_0000],
+                },
+            ],
+            file_priorities: vec![4, 0],
+            uploaded: 1 << 40,
+            downloaded: 12345,
+            files: vec![
+                FileMetadata {
+                    size: 100,
+                    mtime: 1_700_000_000,
+                },
+                FileMetadata::default(),
+            ],
+        };
+        data.save(&path).await.unwrap();
+        assert_eq!(ResumeData::load(&path).await.unwrap(), Some(data));
+
+        fs::remove_file(path).await.unwrap();
+    }
+}
@@ -0,0 +1,106 @@
+use std::net::SocketAddr;
+
+/// Something that happened in a [`Session`](super::Session), see [`Session::subscribe`](super::Session::subscribe).
+///
+/// Torrents are identified by their info hash and peers by their address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionEvent {
+    TorrentAdded {
+        info_hash: [u8; 20],
+    },
+    TorrentPaused {
+        info_hash: [u8; 20],
+    },
+    TorrentResumed {
+        info_hash: [u8; 20],
+    },
+    TorrentRemoved {
+        info_hash: [u8; 20],
+    },
+    /// The info dictionary of a magnet link was downloaded from the peers
+    MetadataReceived {
+        info_hash: [u8; 20],
+    },
+    /// A piece was downloaded and passed the hash check
+    PieceFinished {
+        info_hash: [u8; 20],
+        index: u32,
+    },
+    /// A piece failed the hash check, every peer that sent some of it is listed
+    HashFailed {
+        info_hash: [u8; 20],
+        index: u32,
+        peers: Vec<SocketAddr>,
+    },
+    TrackerReply {
+        info_hash: [u8; 20],
+        url: String,
+        peers: usize,
+    },
+    TrackerError {
+        info_hash: [u8; 20],
+        url: String,
+        error: String,
+    },
+    PeerConnected {
+        info_hash: [u8; 20],
+        peer: SocketAddr,
+        peer_id: [u8; 20],
+    },
+    PeerDisconnected {
+        info_hash: [u8; 20],
+        peer: SocketAddr,
+        /// Why the connection ended, `None` if it was closed normally
+        error: Option<String>,
+    },
+    /// Every piece of a file was downloaded
+    FileCompleted {
+        info_hash: [u8; 20],
+        file: usize,
+    },
+    /// Every wanted piece of the torrent was downloaded
+    TorrentFinished {
+        info_hash: [u8; 20],
+    },
+    /// A finished torrent reached its ratio, seeding time or idle limit, it's paused right after
+    SeedGoalReached {
+        info_hash: [u8; 20],
+    },
+    /// Reading or writing the files of a torrent failed
+    StorageError {
+        info_hash: [u8; 20],
+        error: String,
+    },
+}
+
+impl SessionEvent {
+    /// The torrent the event is about
+    pub const fn info_hash(&self) -> [u8; 20] {
+        match self {
+            SessionEvent::TorrentAdded { info_hash }
+            | SessionEvent::TorrentPaused { info_hash }
+            | SessionEvent::TorrentResumed { info_hash }
+            | SessionEvent::TorrentRemoved { info_hash }
+            | SessionEvent::MetadataReceived { info_hash }
+            | SessionEvent::PieceFinished { info_hash, .. }
+            | SessionEvent::HashFailed { info_hash, .. }
+            | SessionEvent::TrackerReply { info_hash, .. }
+            | SessionEvent::TrackerError { info_hash, .. }
+            | SessionEvent::PeerConnected { info_hash, .. }
+            | SessionEvent::PeerDisconnected { info_hash, .. }
+            | SessionEvent::FileCompleted { info_hash, .. }
+            | SessionEvent::TorrentFinished { info_hash }
+            | SessionEvent::SeedGoalReached { info_hash }
+            | SessionEvent::StorageError { info_hash, .. } => *info_hash,
+        }
+    }
+
+    /// The peer the event is about, if it's about a single one
+    pub const fn peer(&self) -> Option<SocketAddr> {
+        match self {
+            SessionEvent::PeerConnected { peer, .. }
+            | SessionEvent::PeerDisconnected { peer, .. } => Some(*peer),
+            _ => None,
+        }
+    }
+}
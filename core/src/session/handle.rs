@@ -0,0 +1,1013 @@
+use bytes::Bytes;
+use color_eyre::eyre::{eyre, Result};
+use indexmap::IndexSet;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    io::ErrorKind,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::Duration,
+};
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncWrite},
+    sync::{mpsc, watch, Semaphore},
+    task::{self, JoinHandle},
+    time::{interval, timeout, Instant},
+};
+use tracing::{debug, info, warn};
+use tracker::tracker::http::{AnnounceRequest, AnnounceResponse, Event};
+
+use super::{
+    metadata::{self, MetadataDownload},
+    peer::{PeerCommand, PeerHandle, Permits, MAX_UPLOAD_QUEUE, UT_METADATA},
+    super_seed::SuperSeed,
+    AddOptions, PeerStatus, SessionEvent, Shared, TorrentState, TorrentStatus,
+};
+#[cfg(target_os = "linux")]
+use crate::storage::MmapStorage;
+use crate::{
+    choker::{ChokeCandidate, Choker, CHOKE_INTERVAL},
+    download::{Download, FilePriority},
+    limit::RateLimits,
+    meta_info::Info,
+    picker::PiecePicker,
+    resume::ResumeData,
+    stats::TransferStats,
+    storage::{FileStorage, Layout, MemoryStorage, Storage, StorageBackend},
+    torrent::Torrent,
+    Connection, ExtendedHandshake, METADATA_PIECE_SIZE,
+};
+
+/// How often new peers are connected to and the trackers are checked
+const TICK_INTERVAL: Duration = Duration::from_secs(5);
+/// How often the resume data is saved while downloading
+const RESUME_INTERVAL: Duration = Duration::from_secs(60);
+/// How long to wait before announcing again when every tracker failed
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Shorter than [`CONNECT_TIMEOUT`], most peers that don't answer over uTP only speak tcp
+const UTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the stopped announce can take, it's only a courtesy to the trackers
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a finished torrent has been seeding, to stop it once one of its goals is reached
+struct Seeding {
+    since: Instant,
+    /// Payload bytes the torrent uploaded when it was last checked
+    uploaded: u64,
+    last_upload: Instant,
+}
+
+/// A torrent added to a [`Session`](super::Session)
+#[derive(Clone)]
+pub struct TorrentHandle {
+    pub(super) inner: Arc<Inner>,
+}
+
+pub(super) struct Inner {
+    session: Arc<Shared>,
+    info_hash: [u8; 20],
+    /// Display name of a magnet link
+    name: Option<String>,
+    options: AddOptions,
+    trackers: Vec<String>,
+    /// The info dictionary as it's encoded, sent to peers that only have the info hash
+    metadata: OnceLock<Bytes>,
+    metadata_download: Mutex<MetadataDownload>,
+    /// Pieces revealed to each peer while super seeding
+    super_seed: Mutex<SuperSeed>,
+    /// Set once the metadata is known and the existing data was checked
+    torrent: watch::Sender<Option<Torrent>>,
+    finished: watch::Sender<bool>,
+    /// The download finished while running, the trackers still have to be told
+    completed: AtomicBool,
+    paused: AtomicBool,
+    removed: AtomicBool,
+    peers: Mutex<HashMap<SocketAddr, PeerHandle>>,
+    next_peer_id: AtomicU64,
+    /// Peers we know about but aren't connected to
+    candidates: Mutex<IndexSet<SocketAddr>>,
+    /// Peers that sent too much corrupt data
+    banned: Mutex<HashSet<SocketAddr>>,
+    /// Seeds and partial seeds, not connected to again once we're done downloading too
+    upload_only: Mutex<HashSet<SocketAddr>>,
+    /// Connections of this torrent
+    connections: Arc<Semaphore>,
+    /// Traffic of the connections of this torrent
+    stats: Arc<TransferStats>,
+    /// Limits shared by the connections of this torrent
+    limits: Arc<RateLimits>,
+    /// Seeds and downloaders in the swarm, from the last tracker reply
+    swarm: Mutex<(Option<u64>, Option<u64>)>,
+    /// Picks the peers that can download from us
+    choker: Mutex<Box<dyn Choker>>,
+    /// Announces to the trackers and connects to new peers while the torrent is running
+    driver: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl fmt::Debug for TorrentHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TorrentHandle")
+            .field("info_hash", &self.inner.info_hash)
+            .field("name", &self.name())
+            .finish()
+    }
+}
+
+impl TorrentHandle {
+    pub(super) fn new(
+        session: Arc<Shared>,
+        info_hash: [u8; 20],
+        name: Option<String>,
+        trackers: Vec<String>,
+        options: AddOptions,
+    ) -> Self {
+        // Only http trackers are supported by the client
+        let (trackers, unsupported): (Vec<_>, Vec<_>) = trackers
+            .into_iter()
+            .partition(|tracker| tracker.starts_with("http://") || tracker.starts_with("https://"));
+        for tracker in unsupported {
+            debug!("Ignoring unsupported tracker {}", tracker);
+        }
+
+        let connections = Arc::new(Semaphore::new(session.max_connections_per_torrent));
+        let paused = AtomicBool::new(options.paused);
+        let limits = Arc::new(session.rate_limits(options.upload_limit, options.download_limit));
+        let choker = (session.choker)();
+
+        Self {
+            inner: Arc::new(Inner {
+                session,
+                info_hash,
+                name,
+                options,
+                trackers,
+                metadata: OnceLock::new(),
+                metadata_download: Mutex::new(MetadataDownload::default()),
+                super_seed: Mutex::new(SuperSeed::default()),
+                torrent: watch::channel(None).0,
+                finished: watch::channel(false).0,
+                completed: AtomicBool::new(false),
+                paused,
+                removed: AtomicBool::new(false),
+                peers: Mutex::new(HashMap::new()),
+                next_peer_id: AtomicU64::new(0),
+                candidates: Mutex::new(IndexSet::new()),
+                banned: Mutex::new(HashSet::new()),
+                upload_only: Mutex::new(HashSet::new()),
+                connections,
+                stats: Arc::new(TransferStats::new()),
+                limits,
+                swarm: Mutex::new((None, None)),
+                choker: Mutex::new(choker),
+                driver: Mutex::new(None),
+            }),
+        }
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.inner.info_hash
+    }
+
+    /// Name of the torrent, only known from the metadata or the display name of a magnet link
+    pub fn name(&self) -> Option<String> {
+        match self.torrent() {
+            Some(torrent) => Some(torrent.info().name.clone()),
+            None => self.inner.name.clone(),
+        }
+    }
+
+    /// The torrent, once the metadata is known and the existing data was checked
+    pub fn torrent(&self) -> Option<Torrent> {
+        self.inner.torrent()
+    }
+
+    /// Waits until the metadata is known and the existing data was checked
+    pub async fn wait_for_metadata(&self) -> Torrent {
+        let mut torrent = self.inner.torrent.subscribe();
+        loop {
+            if let Some(torrent) = torrent.borrow_and_update().clone() {
+                return torrent;
+            }
+            // The sender lives as long as the handle
+            let _ = torrent.changed().await;
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.inner.paused.load(Ordering::SeqCst)
+    }
+
+    /// Whether every wanted piece was downloaded
+    pub fn is_finished(&self) -> bool {
+        *self.inner.finished.borrow()
+    }
+
+    /// Changes the priority of a file while the torrent runs, it can be skipped or wanted again
+    pub async fn set_file_priority(&self, file: usize, priority: FilePriority) -> Result<()> {
+        let torrent = self
+            .torrent()
+            .ok_or_else(|| eyre!("The metadata isn't known yet"))?;
+        let complete = {
+            let mut download = torrent.download().await;
+            download.set_file_priority(file, priority)?;
+            download.picker().is_complete()
+        };
+
+        if complete {
+            self.inner.finish();
+        } else {
+            self.inner.finished.send_replace(false);
+            self.inner.broadcast(PeerCommand::Wanted, None);
+        }
+        Ok(())
+    }
+
+    /// Asks for a piece to be downloaded by `deadline`, before any other piece
+    pub async fn set_piece_deadline(&self, index: u32, deadline: Instant) -> Result<()> {
+        let torrent = self
+            .torrent()
+            .ok_or_else(|| eyre!("The metadata isn't known yet"))?;
+        torrent.set_piece_deadline(index, deadline).await;
+        Ok(())
+    }
+
+    /// Sets how many pieces after the position of a file reader are downloaded in order first
+    pub async fn set_read_ahead(&self, pieces: u32) -> Result<()> {
+        let torrent = self
+            .torrent()
+            .ok_or_else(|| eyre!("The metadata isn't known yet"))?;
+        torrent.set_read_ahead(pieces).await;
+        Ok(())
+    }
+
+    /// Waits until every wanted piece is downloaded
+    pub async fn wait_finished(&self) {
+        let mut finished = self.inner.finished.subscribe();
+        while !*finished.borrow_and_update() {
+            let _ = finished.changed().await;
+        }
+    }
+
+    /// Disconnects from every peer and stops announcing, saving the resume data
+    pub async fn pause(&self) -> Result<()> {
+        if self.inner.paused.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.inner.stop();
+        self.inner.emit(SessionEvent::TorrentPaused {
+            info_hash: self.inner.info_hash,
+        });
+        self.inner.announce_stopped().await;
+        self.inner.save_resume_data().await
+    }
+
+    pub fn resume(&self) {
+        if self.inner.paused.swap(false, Ordering::SeqCst) {
+            self.inner.start();
+            self.inner.emit(SessionEvent::TorrentResumed {
+                info_hash: self.inner.info_hash,
+            });
+        }
+    }
+
+    /// Adds a peer to connect to, found some other way than through the trackers
+    pub fn add_peer(&self, addr: SocketAddr) {
+        if !self
+            .inner
+            .banned
+            .lock()
+            .expect("Poisoned lock")
+            .contains(&addr)
+        {
+            let mut candidates = self.inner.candidates.lock().expect("Poisoned lock");
+            candidates.insert(addr);
+        }
+    }
+
+    /// Number of peers we are connected to
+    pub fn peer_count(&self) -> usize {
+        self.inner.peers.lock().expect("Poisoned lock").len()
+    }
+
+    /// Limits of this torrent, their rates can be changed at any time
+    pub fn limits(&self) -> &RateLimits {
+        &self.inner.limits
+    }
+
+    /// Limits of a single connected peer
+    pub fn peer_limits(&self, addr: SocketAddr) -> Option<Arc<RateLimits>> {
+        let peers = self.inner.peers.lock().expect("Poisoned lock");
+        peers.get(&addr).map(|peer| peer.limits.clone())
+    }
+
+    /// A snapshot of every connected peer
+    pub fn peers(&self) -> Vec<PeerStatus> {
+        let peers = self.inner.peers.lock().expect("Poisoned lock");
+        peers.values().map(PeerHandle::status).collect()
+    }
+
+    /// A snapshot of the state, progress and traffic of the torrent
+    pub async fn status(&self) -> TorrentStatus {
+        let inner = &self.inner;
+        let peers = self.peers();
+        let (swarm_seeds, swarm_peers) = *inner.swarm.lock().expect("Poisoned lock");
+        let mut status = TorrentStatus {
+            info_hash: inner.info_hash,
+            name: self.name(),
+            state: TorrentState::DownloadingMetadata,
+            progress: 0.0,
+            total_wanted: 0,
+            total_wanted_done: 0,
+            transfer: inner.stats.snapshot(),
+            all_time_download: 0,
+            all_time_upload: 0,
+            failed_bytes: 0,
+            wasted_bytes: 0,
+            ratio: 0.0,
+            seeds: peers.iter().filter(|peer| peer.flags.seed).count(),
+            peers: peers.len(),
+            swarm_seeds,
+            swarm_peers,
+            eta: None,
+            distributed_copies: 0.0,
+            file_progress: Vec::new(),
+        };
+
+        match self.torrent() {
+            Some(torrent) => {
+                let download = torrent.download().await;
+                let picker = download.picker();
+                let wanted = (0..picker.piece_count() as u32)
+                    .filter(|index| picker.has_piece(*index) || picker.priority(*index) > 0);
+                for index in wanted {
+                    status.total_wanted += picker.piece_length(index) as u64;
+                    if picker.has_piece(index) {
+                        status.total_wanted_done += picker.piece_length(index) as u64;
+                    }
+                }
+                status.state = if picker.bitfield().all() {
+                    TorrentState::Seeding
+                } else if picker.is_complete() {
+                    TorrentState::Finished
+                } else {
+                    TorrentState::Downloading
+                };
+                status.progress = if status.total_wanted == 0 {
+                    1.0
+                } else {
+                    status.total_wanted_done as f64 / status.total_wanted as f64
+                };
+                status.all_time_download = download.downloaded_bytes();
+                status.all_time_upload = download.uploaded_bytes();
+                status.failed_bytes = download.failed_bytes();
+                status.wasted_bytes = picker.wasted_bytes();
+                status.distributed_copies = picker.distributed_copies();
+                status.file_progress = download.file_progress();
+            }
+            None if inner.metadata().is_some() => status.state = TorrentState::Checking,
+            None => {}
+        }
+        if self.is_paused() {
+            status.state = TorrentState::Paused;
+        }
+
+        // Seeds that never downloaded anything are compared against the data they have
+        let downloaded = match status.all_time_download {
+            0 => status.total_wanted_done,
+            downloaded => downloaded,
+        };
+        if downloaded > 0 {
+            status.ratio = status.all_time_upload as f64 / downloaded as f64;
+        }
+
+        let left = status.total_wanted - status.total_wanted_done;
+        let rate = status.transfer.payload_download.rate;
+        status.eta = if self.torrent().is_some() && left == 0 {
+            Some(Duration::ZERO)
+        } else if rate > 0 {
+            Some(Duration::from_secs(left.div_ceil(rate)))
+        } else {
+            None
+        };
+
+        status
+    }
+}
+
+impl Inner {
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
+
+    pub fn emit(&self, event: SessionEvent) {
+        self.session.emit(event);
+    }
+
+    pub fn torrent(&self) -> Option<Torrent> {
+        self.torrent.borrow().clone()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Option<Torrent>> {
+        self.torrent.subscribe()
+    }
+
+    pub fn metadata(&self) -> Option<&Bytes> {
+        self.metadata.get()
+    }
+
+    pub fn metadata_download(&self) -> &Mutex<MetadataDownload> {
+        &self.metadata_download
+    }
+
+    pub fn super_seed(&self) -> &Mutex<SuperSeed> {
+        &self.super_seed
+    }
+
+    /// Whether peers are shown our pieces one at a time once we have all of them
+    pub fn super_seeding(&self) -> bool {
+        self.options.super_seeding
+    }
+
+    /// Whether the torrent is running and accepts peers
+    pub fn is_active(&self) -> bool {
+        !self.paused.load(Ordering::SeqCst) && !self.removed.load(Ordering::SeqCst)
+    }
+
+    /// Stores the metadata and starts checking the existing data
+    pub fn set_info(self: &Arc<Self>, info: Info) -> Result<()> {
+        let metadata = Bytes::from(bde::to_bytes(&info)?);
+        if self.metadata.set(metadata).is_ok() {
+            tokio::spawn(self.clone().load(info));
+        }
+        Ok(())
+    }
+
+    /// Verifies the metadata received from the peers against the info hash
+    pub fn metadata_received(self: &Arc<Self>, metadata: Bytes) {
+        let Some(info) = metadata::parse(self.info_hash, &metadata) else {
+            return debug!("Received metadata doesn't match the info hash");
+        };
+        if self.metadata.set(metadata).is_ok() {
+            info!("Received metadata of {}", info.name);
+            self.emit(SessionEvent::MetadataReceived {
+                info_hash: self.info_hash,
+            });
+            tokio::spawn(self.clone().load(info));
+        }
+    }
+
+    async fn load(self: Arc<Self>, info: Info) {
+        let name = info.name.clone();
+        match self.open(info).await {
+            Ok(torrent) => {
+                let complete = torrent.download().await.picker().is_complete();
+                self.finished.send_replace(complete);
+                self.torrent.send_replace(Some(torrent));
+            }
+            Err(error) => {
+                warn!("Failed to load {}: {}", name, error);
+                self.emit(SessionEvent::StorageError {
+                    info_hash: self.info_hash,
+                    error: error.to_string(),
+                });
+            }
+        }
+    }
+
+    /// Creates the storage of the torrent and finds out which pieces are already there
+    async fn open(&self, info: Info) -> Result<Torrent> {
+        let options = &self.options;
+        let layout = Layout::new(&info)?;
+        let storage: Arc<dyn Storage> = match options.storage {
+            StorageBackend::File => {
+                let mut storage =
+                    FileStorage::with_allocation(&options.save_path, layout, options.allocation);
+                storage.set_max_open_files(options.max_open_files);
+                Arc::new(storage)
+            }
+            #[cfg(target_os = "linux")]
+            StorageBackend::Mmap => Arc::new(MmapStorage::new(&options.save_path, layout)),
+            StorageBackend::Memory { limit: None } => Arc::new(MemoryStorage::new(layout)),
+            StorageBackend::Memory { limit: Some(limit) } => {
+                Arc::new(MemoryStorage::with_limit(layout, limit))
+            }
+        };
+        let mut download = Download::with_disk(&info, self.session.disk.open(storage));
+
+        // Skip the recheck when the files are exactly as we left them
+        let resume_path = ResumeData::path(&options.save_path, &info);
+        let restored = match ResumeData::load(&resume_path).await? {
+            Some(data) if self.is_persistent() => download.restore(self.info_hash, &data)?,
+            _ => false,
+        };
+        if !restored {
+            download
+                .recheck(&info, |progress| {
+                    debug!("Checked {}/{} pieces", progress.checked, progress.total)
+                })
+                .await?;
+        }
+
+        for (file, priority) in options.file_priorities.iter().enumerate() {
+            download.set_file_priority(file, *priority)?;
+        }
+        download.picker_mut().set_sequential(options.sequential);
+        download.picker_mut().set_read_ahead(options.read_ahead);
+        info!(
+            "Loaded {} with {} valid pieces",
+            info.name,
+            download.picker().bitfield().count_ones()
+        );
+
+        Ok(Torrent::new(self.info_hash, info, download))
+    }
+
+    /// The extended handshake sent to every peer
+    pub fn extended_handshake(&self) -> ExtendedHandshake {
+        ExtendedHandshake {
+            messages: [("ut_metadata".to_string(), UT_METADATA as u32)].into(),
+            port: Some(self.session.port),
+            version: Some(format!("Leech {}", env!("CARGO_PKG_VERSION"))),
+            yourip: None,
+            reqq: Some(MAX_UPLOAD_QUEUE as u32),
+            metadata_size: self.metadata().map(|metadata| metadata.len() as u32),
+            upload_only: self.is_finished().then_some(1),
+        }
+    }
+
+    pub fn start(self: &Arc<Self>) {
+        if self.is_active() {
+            let driver = tokio::spawn(self.clone().drive());
+            if let Some(previous) = self.driver.lock().expect("Poisoned lock").replace(driver) {
+                previous.abort();
+            }
+        }
+    }
+
+    /// Stops announcing and disconnects every peer
+    pub fn stop(&self) {
+        if let Some(driver) = self.driver.lock().expect("Poisoned lock").take() {
+            driver.abort();
+        }
+        // Peers stop as soon as their handle is dropped
+        self.peers.lock().expect("Poisoned lock").clear();
+    }
+
+    /// Stops the torrent for good, without waiting for anything
+    pub fn close(&self) {
+        self.removed.store(true, Ordering::SeqCst);
+        self.stop();
+    }
+
+    pub async fn remove(&self, delete_files: bool) -> Result<()> {
+        let active = self.is_active();
+        self.close();
+        if active {
+            self.announce_stopped().await;
+        }
+
+        let Some(torrent) = self.torrent() else {
+            return Ok(());
+        };
+        if !delete_files {
+            return self.save_resume_data().await;
+        }
+
+        torrent.disk().discard();
+        let storage = torrent.disk().storage().clone();
+        task::spawn_blocking(move || storage.delete()).await??;
+        let resume_path = ResumeData::path(&self.options.save_path, torrent.info());
+        match fs::remove_file(resume_path).await {
+            Err(error) if error.kind() != ErrorKind::NotFound => Err(error.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn save_resume_data(&self) -> Result<()> {
+        let Some(torrent) = self.torrent().filter(|_| self.is_persistent()) else {
+            return Ok(());
+        };
+        let download = torrent.download().await;
+        download.flush().await?;
+        let path = ResumeData::path(&self.options.save_path, torrent.info());
+        download.resume_data(self.info_hash)?.save(&path).await
+    }
+
+    /// Whether the data outlives the torrent, only then is there resume data to save
+    fn is_persistent(&self) -> bool {
+        !matches!(self.options.storage, StorageBackend::Memory { .. })
+    }
+
+    /// Takes a connection slot from both the session and the torrent
+    pub fn permits(&self) -> Option<Permits> {
+        let torrent = self.connections.clone().try_acquire_owned().ok()?;
+        let session = self.session.connections.clone().try_acquire_owned().ok()?;
+        Some(Permits::new(session, torrent))
+    }
+
+    /// Adds a connected peer, returning its id and where its commands are received.
+    ///
+    /// `None` if the torrent isn't running or the peer is already connected or banned.
+    pub fn register(
+        &self,
+        addr: SocketAddr,
+        stats: Arc<TransferStats>,
+        limits: Arc<RateLimits>,
+        status: Arc<Mutex<PeerStatus>>,
+    ) -> Option<(u64, mpsc::UnboundedReceiver<PeerCommand>)> {
+        if !self.is_active() || self.banned.lock().expect("Poisoned lock").contains(&addr) {
+            return None;
+        }
+        let mut peers = self.peers.lock().expect("Poisoned lock");
+        if peers.contains_key(&addr) {
+            return None;
+        }
+
+        let id = self.next_peer_id.fetch_add(1, Ordering::Relaxed);
+        let (commands, receiver) = mpsc::unbounded_channel();
+        peers.insert(
+            addr,
+            PeerHandle {
+                id,
+                commands,
+                connected: Instant::now(),
+                stats,
+                limits,
+                status,
+            },
+        );
+        Some((id, receiver))
+    }
+
+    /// Counts the traffic of a connection in the totals of the torrent and of the session, and
+    /// throttles it with their limits after the ones of the connection itself
+    pub fn account<S>(&self, connection: &mut Connection<S>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (upload, download) = self.session.peer_limits;
+        let limits = connection.limits();
+        limits.upload.set_rate(upload);
+        limits.download.set_rate(download);
+        limits.set_include_overhead(self.session.limit_overhead);
+
+        connection.add_stats(self.stats.clone());
+        connection.add_stats(self.session.stats.clone());
+        connection.add_limits(self.limits.clone());
+        connection.add_limits(self.session.limits.clone());
+    }
+
+    pub fn unregister(&self, addr: SocketAddr, id: u64) {
+        let mut peers = self.peers.lock().expect("Poisoned lock");
+        if peers.get(&addr).is_some_and(|peer| peer.id == id) {
+            peers.remove(&addr);
+        }
+    }
+
+    /// Disconnects a peer and never connects to it again
+    pub fn ban(&self, addr: SocketAddr) {
+        debug!("Banning {}", addr);
+        self.banned.lock().expect("Poisoned lock").insert(addr);
+        self.candidates
+            .lock()
+            .expect("Poisoned lock")
+            .shift_remove(&addr);
+        self.peers.lock().expect("Poisoned lock").remove(&addr);
+    }
+
+    /// Sends a command to a single peer
+    pub fn send(&self, addr: SocketAddr, command: PeerCommand) {
+        if let Some(peer) = self.peers.lock().expect("Poisoned lock").get(&addr) {
+            let _ = peer.commands.send(command);
+        }
+    }
+
+    /// Sends a command to every peer, except the one with id `except`
+    pub fn broadcast(&self, command: PeerCommand, except: Option<u64>) {
+        for peer in self.peers.lock().expect("Poisoned lock").values() {
+            if Some(peer.id) != except {
+                // The peer is going away if its channel is closed
+                let _ = peer.commands.send(command);
+            }
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        *self.finished.borrow()
+    }
+
+    /// Remembers a peer that has nothing we want, it's a seed or a partial seed
+    pub fn upload_only(&self, addr: SocketAddr) {
+        self.upload_only.lock().expect("Poisoned lock").insert(addr);
+    }
+
+    /// Called when the last wanted piece was downloaded
+    pub fn finish(&self) {
+        if !self.finished.send_replace(true) {
+            self.completed.store(true, Ordering::SeqCst);
+            self.broadcast(PeerCommand::Wanted, None);
+            self.emit(SessionEvent::TorrentFinished {
+                info_hash: self.info_hash,
+            });
+        }
+    }
+
+    /// Lets the peers the choker picked download from us, and chokes every other one
+    fn rechoke(&self) {
+        let seeding = self.is_finished();
+        let peers = self.peers.lock().expect("Poisoned lock");
+        let candidates: Vec<ChokeCandidate> = peers
+            .iter()
+            .map(|(addr, peer)| {
+                let status = peer.status();
+                ChokeCandidate {
+                    addr: *addr,
+                    interested: status.flags.peer_interested,
+                    download_rate: status.transfer.payload_download.rate,
+                    upload_rate: status.transfer.payload_upload.rate,
+                    connected_for: peer.connected.elapsed(),
+                }
+            })
+            .collect();
+
+        let unchoked = self
+            .choker
+            .lock()
+            .expect("Poisoned lock")
+            .unchoke(&candidates, seeding);
+        for (addr, peer) in peers.iter() {
+            let command = if unchoked.contains(addr) {
+                PeerCommand::Unchoke
+            } else {
+                PeerCommand::Choke
+            };
+            let _ = peer.commands.send(command);
+        }
+    }
+
+    /// Connects to as many known peers as the limits allow
+    fn connect_peers(self: &Arc<Self>) {
+        while self.is_active() {
+            let addr = {
+                let mut candidates = self.candidates.lock().expect("Poisoned lock");
+                let peers = self.peers.lock().expect("Poisoned lock");
+                candidates.retain(|addr| !peers.contains_key(addr));
+                if self.is_finished() {
+                    let upload_only = self.upload_only.lock().expect("Poisoned lock");
+                    candidates.retain(|addr| !upload_only.contains(addr));
+                }
+                match candidates.first() {
+                    Some(addr) => *addr,
+                    None => return,
+                }
+            };
+            let Some(permits) = self.permits() else {
+                return;
+            };
+            self.candidates
+                .lock()
+                .expect("Poisoned lock")
+                .shift_remove(&addr);
+            tokio::spawn(self.clone().connect(addr, permits));
+        }
+    }
+
+    async fn connect(self: Arc<Self>, addr: SocketAddr, permits: Permits) {
+        let builder = self.session.connection_builder();
+        // Peers that don't answer over uTP are dialed over tcp, the socket only does ipv4
+        if let Some(socket) = self.session.utp.clone().filter(|_| addr.is_ipv4()) {
+            let connection = timeout(
+                UTP_CONNECT_TIMEOUT,
+                builder.connect_utp(&socket, addr, self.info_hash, self.session.peer_id),
+            )
+            .await;
+            match connection {
+                Ok(Ok(connection)) => {
+                    return super::peer::run(self, addr, connection, permits).await;
+                }
+                Ok(Err(error)) => debug!("Failed to connect to {} over uTP: {}", addr, error),
+                Err(_) => debug!("Connecting to {} over uTP timed out", addr),
+            }
+        }
+
+        let connection = timeout(
+            CONNECT_TIMEOUT,
+            builder.connect_tcp(addr, self.info_hash, self.session.peer_id),
+        )
+        .await;
+
+        match connection {
+            Ok(Ok(connection)) => super::peer::run(self, addr, connection, permits).await,
+            Ok(Err(error)) => debug!("Failed to connect to {}: {}", addr, error),
+            Err(_) => debug!("Connecting to {} timed out", addr),
+        }
+    }
+
+    /// Keeps the torrent going while it's running: announces, connects to peers, chokes them and saves the resume data
+    async fn drive(self: Arc<Self>) {
+        let mut event = Some(Event::Started);
+        let mut next_announce = Instant::now();
+        let mut next_save = Instant::now() + RESUME_INTERVAL;
+        let mut finished = self.finished.subscribe();
+        let mut ticker = interval(TICK_INTERVAL);
+        let mut choke_ticker = interval(CHOKE_INTERVAL);
+        let mut seeding: Option<Seeding> = None;
+
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                Ok(()) = finished.changed() => {}
+                _ = choke_ticker.tick() => {
+                    self.rechoke();
+                    continue;
+                }
+            }
+
+            let now = Instant::now();
+            if self.completed.swap(false, Ordering::SeqCst) {
+                let handle = TorrentHandle {
+                    inner: self.clone(),
+                };
+                info!("Finished downloading {}", handle.name().unwrap_or_default());
+                // A torrent finished before the first announce is reported as started
+                if event.is_none() {
+                    event = Some(Event::Completed);
+                }
+                next_announce = now;
+                next_save = now;
+            }
+
+            if now >= next_announce && !self.trackers.is_empty() {
+                match self.announce(event).await {
+                    Ok(response) => {
+                        debug!("Tracker returned {} peers", response.peers.len());
+                        *self.swarm.lock().expect("Poisoned lock") =
+                            (response.complete, response.incomplete);
+                        let handle = TorrentHandle {
+                            inner: self.clone(),
+                        };
+                        response
+                            .peers
+                            .into_iter()
+                            .for_each(|peer| handle.add_peer(peer));
+                        event = None;
+                        next_announce = now + Duration::from_secs(response.interval.max(1));
+                    }
+                    Err(error) => {
+                        debug!("Failed to announce: {}", error);
+                        next_announce = now + RETRY_INTERVAL;
+                    }
+                }
+            }
+
+            if now >= next_save {
+                if let Err(error) = self.save_resume_data().await {
+                    warn!("Failed to save resume data: {}", error);
+                    self.emit(SessionEvent::StorageError {
+                        info_hash: self.info_hash,
+                        error: error.to_string(),
+                    });
+                }
+                next_save = now + RESUME_INTERVAL;
+            }
+
+            if self.is_finished() {
+                let uploaded = self.stats.snapshot().payload_upload.total;
+                let seeding = seeding.get_or_insert(Seeding {
+                    since: now,
+                    uploaded,
+                    last_upload: now,
+                });
+                if uploaded > seeding.uploaded {
+                    seeding.uploaded = uploaded;
+                    seeding.last_upload = now;
+                }
+                if self.clone().seed_goal_reached(seeding, now).await {
+                    let handle = TorrentHandle {
+                        inner: self.clone(),
+                    };
+                    info!("Done seeding {}", handle.name().unwrap_or_default());
+                    self.emit(SessionEvent::SeedGoalReached {
+                        info_hash: self.info_hash,
+                    });
+                    // Pausing aborts this task, so it's left to another one
+                    tokio::spawn(async move {
+                        if let Err(error) = handle.pause().await {
+                            warn!("Failed to pause: {}", error);
+                        }
+                    });
+                    return;
+                }
+            } else {
+                // Wanting some skipped files again starts the download over
+                seeding = None;
+            }
+
+            self.connect_peers();
+        }
+    }
+
+    async fn seed_goal_reached(self: Arc<Self>, seeding: &Seeding, now: Instant) -> bool {
+        let options = &self.options;
+        if options
+            .seed_time_limit
+            .is_some_and(|limit| now - seeding.since >= limit)
+            || options
+                .seed_idle_limit
+                .is_some_and(|limit| now - seeding.last_upload >= limit)
+        {
+            return true;
+        }
+        match options.ratio_limit {
+            Some(limit) => {
+                let handle = TorrentHandle { inner: self };
+                handle.status().await.ratio >= limit
+            }
+            None => false,
+        }
+    }
+
+    /// Announces to the first tracker that answers
+    async fn announce(&self, event: Option<Event>) -> Result<AnnounceResponse> {
+        let (uploaded, downloaded, left) = match self.torrent() {
+            Some(torrent) => {
+                let download = torrent.download().await;
+                (
+                    download.uploaded_bytes(),
+                    download.downloaded_bytes(),
+                    left(download.picker()),
+                )
+            }
+            // The size isn't known yet, anything but zero so we aren't taken for a seed
+            None => (0, 0, METADATA_PIECE_SIZE as u64),
+        };
+        // Partial seeds tell the trackers they aren't downloading, the ones that don't know the
+        // event take it for a regular announce
+        let event = match event {
+            None | Some(Event::Completed) if self.is_finished() && left > 0 => Some(Event::Paused),
+            event => event,
+        };
+
+        let request = AnnounceRequest {
+            info_hash: self.info_hash,
+            peer_id: self.session.peer_id,
+            ip: None,
+            port: self.session.port,
+            uploaded,
+            downloaded,
+            left,
+            event,
+            compact: true,
+            numwant: None,
+        };
+
+        let mut last_error = None;
+        for tracker in &self.trackers {
+            match self.session.client.announce(tracker, &request).await {
+                Ok(response) => {
+                    self.emit(SessionEvent::TrackerReply {
+                        info_hash: self.info_hash,
+                        url: tracker.clone(),
+                        peers: response.peers.len(),
+                    });
+                    return Ok(response);
+                }
+                Err(error) => {
+                    debug!("Announce to {} failed: {}", tracker, error);
+                    self.emit(SessionEvent::TrackerError {
+                        info_hash: self.info_hash,
+                        url: tracker.clone(),
+                        error: error.to_string(),
+                    });
+                    last_error = Some(error);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| eyre!("No tracker to announce to")))
+    }
+
+    async fn announce_stopped(&self) {
+        if self.trackers.is_empty() {
+            return;
+        }
+        match timeout(STOP_TIMEOUT, self.announce(Some(Event::Stopped))).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(error)) => debug!("Failed to announce stop: {}", error),
+            Err(_) => debug!("Announcing stop timed out"),
+        }
+    }
+}
+
+/// Bytes of the pieces that are still missing
+fn left(picker: &PiecePicker) -> u64 {
+    (0..picker.piece_count() as u32)
+        .filter(|index| !picker.has_piece(*index))
+        .map(|index| picker.piece_length(index) as u64)
+        .sum()
+}
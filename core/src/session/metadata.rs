@@ -0,0 +1,129 @@
+use bytes::Bytes;
+use sha1::{Digest, Sha1};
+
+use crate::{meta_info::Info, METADATA_PIECE_SIZE};
+
+/// Info dictionaries bigger than this are refused, real ones are a few megabytes at most
+const MAX_METADATA_SIZE: u32 = 16 << 20;
+
+/// Collects the info dictionary of a magnet link from the peers, one piece at a time
+#[derive(Debug, Default)]
+pub(super) struct MetadataDownload {
+    size: Option<u32>,
+    pieces: Vec<Option<Bytes>>,
+    requested: Vec<bool>,
+}
+
+impl MetadataDownload {
+    /// Picks a piece to request from a peer that told us the metadata is `size` bytes long
+    pub fn pick(&mut self, size: u32) -> Option<u32> {
+        if size == 0 || size > MAX_METADATA_SIZE {
+            return None;
+        }
+        match self.size {
+            // Peers disagreeing with the first one we heard from are ignored
+            Some(known) if known != size => return None,
+            Some(_) => {}
+            None => {
+                let count = (size as usize).div_ceil(METADATA_PIECE_SIZE);
+                self.size = Some(size);
+                self.pieces = vec![None; count];
+                self.requested = vec![false; count];
+            }
+        }
+
+        let piece = (0..self.pieces.len())
+            .find(|&piece| self.pieces[piece].is_none() && !self.requested[piece])?;
+        self.requested[piece] = true;
+        Some(piece as u32)
+    }
+
+    /// Makes a piece available to other peers, after it was rejected or its peer went away
+    pub fn abort(&mut self, piece: u32) {
+        if let Some(requested) = self.requested.get_mut(piece as usize) {
+            *requested = false;
+        }
+    }
+
+    /// Stores a piece, returning the whole info dictionary once every piece is there
+    pub fn received(&mut self, piece: u32, total_size: u32, data: Bytes) -> Option<Bytes> {
+        let size = self.size.filter(|size| *size == total_size)?;
+        if piece as usize >= self.pieces.len() {
+            return None;
+        }
+        let expected =
+            (size as usize - piece as usize * METADATA_PIECE_SIZE).min(METADATA_PIECE_SIZE);
+        if data.len() != expected {
+            self.abort(piece);
+            return None;
+        }
+        self.pieces[piece as usize] = Some(data);
+
+        if self.pieces.iter().any(Option::is_none) {
+            return None;
+        }
+        let metadata: Vec<u8> = self.pieces.iter().flatten().flatten().copied().collect();
+        *self = Self::default();
+        Some(metadata.into())
+    }
+}
+
+/// Decodes an info dictionary, making sure it's the one the info hash was computed from
+pub(super) fn parse(info_hash: [u8; 20], metadata: &[u8]) -> Option<Info> {
+    let hash: [u8; 20] = Sha1::digest(metadata).into();
+    if hash != info_hash {
+        return None;
+    }
+    bde::from_bytes(metadata).ok()
+}
+
+/// The piece of the metadata to send to a peer that asked for it
+pub(super) fn piece(metadata: &Bytes, piece: u32) -> Option<Bytes> {
+    let begin = piece as usize * METADATA_PIECE_SIZE;
+    if begin >= metadata.len() {
+        return None;
+    }
+    Some(metadata.slice(begin..metadata.len().min(begin + METADATA_PIECE_SIZE)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assembles_pieces() {
+        let metadata: Bytes = (0..METADATA_PIECE_SIZE * 2 + 10)
+            .map(|_| rand::random::<u8>())
+            .collect::<Vec<_>>()
+            .into();
+        let size = metadata.len() as u32;
+        let mut download = MetadataDownload::default();
+
+        assert_eq!(download.pick(size), Some(0));
+        assert_eq!(download.pick(size + 1), None);
+        assert_eq!(download.pick(size), Some(1));
+        assert_eq!(download.pick(size), Some(2));
+        assert_eq!(download.pick(size), None);
+
+        // A rejected piece can be asked to someone else
+        download.abort(1);
+        assert_eq!(download.pick(size), Some(1));
+
+        let pieces: Vec<Bytes> = (0..3)
+            .map(|index| piece(&metadata, index).unwrap())
+            .collect();
+        assert_eq!(piece(&metadata, 3), None);
+        assert_eq!(pieces[2].len(), 10);
+
+        // Pieces with the wrong length are thrown away
+        assert_eq!(download.received(2, size, pieces[0].clone()), None);
+        assert_eq!(download.pick(size), Some(2));
+
+        assert_eq!(download.received(2, size, pieces[2].clone()), None);
+        assert_eq!(download.received(0, size, pieces[0].clone()), None);
+        assert_eq!(
+            download.received(1, size, pieces[1].clone()),
+            Some(metadata)
+        );
+    }
+}
@@ -0,0 +1,1094 @@
+//! Runs any number of torrents sharing the same listener, trackers and disk I/O
+
+mod event;
+mod handle;
+mod metadata;
+mod peer;
+mod status;
+mod super_seed;
+
+use color_eyre::eyre::Result;
+use magnet::Magnet;
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::{broadcast, Semaphore},
+    task::JoinHandle,
+    time::timeout,
+};
+use tracing::debug;
+
+use crate::{
+    choker::{Choker, TitForTat},
+    client::Client,
+    disk::DiskIo,
+    download::FilePriority,
+    limit::RateLimits,
+    meta_info::MetaInfo,
+    picker::DEFAULT_READ_AHEAD,
+    stats::TransferStats,
+    storage::{Allocation, StorageBackend, DEFAULT_MAX_OPEN_FILES},
+    utp::UtpSocket,
+    ConnectionBuilder, EncryptionPolicy,
+};
+
+pub use event::SessionEvent;
+pub use handle::TorrentHandle;
+pub use status::{PeerFlags, PeerStatus, SessionStatus, TorrentState, TorrentStatus};
+
+/// How long a peer has to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Creates the choker of each torrent
+type ChokerFactory = Arc<dyn Fn() -> Box<dyn Choker> + Send + Sync>;
+
+#[derive(Debug, Error)]
+pub enum SessionError {
+    #[error("Torrent is already in the session")]
+    DuplicateTorrent,
+    #[error("Torrent is not in the session")]
+    UnknownTorrent,
+}
+
+/// What a torrent is added from
+pub enum TorrentSource {
+    MetaInfo(Box<MetaInfo>),
+    /// The info dictionary is downloaded from the peers before anything else
+    Magnet(Magnet),
+}
+
+impl From<MetaInfo> for TorrentSource {
+    fn from(meta_info: MetaInfo) -> Self {
+        TorrentSource::MetaInfo(Box::new(meta_info))
+    }
+}
+
+impl From<Magnet> for TorrentSource {
+    fn from(magnet: Magnet) -> Self {
+        TorrentSource::Magnet(magnet)
+    }
+}
+
+/// How a torrent is added to a [`Session`]
+#[derive(Debug, Clone)]
+pub struct AddOptions {
+    save_path: PathBuf,
+    allocation: Allocation,
+    storage: StorageBackend,
+    max_open_files: usize,
+    paused: bool,
+    sequential: bool,
+    read_ahead: u32,
+    file_priorities: Vec<FilePriority>,
+    upload_limit: u64,
+    download_limit: u64,
+    super_seeding: bool,
+    ratio_limit: Option<f64>,
+    seed_time_limit: Option<Duration>,
+    seed_idle_limit: Option<Duration>,
+}
+
+impl AddOptions {
+    pub fn new() -> Self {
+        Self {
+            save_path: PathBuf::from("."),
+            allocation: Allocation::default(),
+            storage: StorageBackend::default(),
+            max_open_files: DEFAULT_MAX_OPEN_FILES,
+            paused: false,
+            sequential: false,
+            read_ahead: DEFAULT_READ_AHEAD,
+            file_priorities: Vec::new(),
+            upload_limit: 0,
+            download_limit: 0,
+            super_seeding: false,
+            ratio_limit: None,
+            seed_time_limit: None,
+            seed_idle_limit: None,
+        }
+    }
+
+    /// Sets the directory the torrent is downloaded to
+    pub fn save_path<P: Into<PathBuf>>(&mut self, path: P) -> &mut Self {
+        self.save_path = path.into();
+        self
+    }
+
+    pub fn allocation(&mut self, allocation: Allocation) -> &mut Self {
+        self.allocation = allocation;
+        self
+    }
+
+    /// Picks where the data of the torrent is kept, the allocation only applies to [`StorageBackend::File`]
+    pub fn storage(&mut self, storage: StorageBackend) -> &mut Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Sets how many files of the torrent are kept open at once with [`StorageBackend::File`],
+    /// [`DEFAULT_MAX_OPEN_FILES`] by default
+    pub fn max_open_files(&mut self, max: usize) -> &mut Self {
+        self.max_open_files = max;
+        self
+    }
+
+    /// Adds the torrent without connecting to anyone until it's resumed
+    pub fn paused(&mut self, paused: bool) -> &mut Self {
+        self.paused = paused;
+        self
+    }
+
+    /// Downloads the pieces in order
+    pub fn sequential(&mut self, sequential: bool) -> &mut Self {
+        self.sequential = sequential;
+        self
+    }
+
+    /// Sets how many pieces after the position of a [`TorrentFile`](crate::TorrentFile) reader
+    /// are downloaded in order before anything else, [`DEFAULT_READ_AHEAD`] by default
+    pub fn read_ahead(&mut self, pieces: u32) -> &mut Self {
+        self.read_ahead = pieces;
+        self
+    }
+
+    /// Sets the priority of each file, overriding the ones in the resume data
+    pub fn file_priorities(&mut self, priorities: Vec<FilePriority>) -> &mut Self {
+        self.file_priorities = priorities;
+        self
+    }
+
+    /// Sets the upload limit of the torrent in bytes per second, 0 means unlimited
+    pub fn upload_limit(&mut self, rate: u64) -> &mut Self {
+        self.upload_limit = rate;
+        self
+    }
+
+    /// Sets the download limit of the torrent in bytes per second, 0 means unlimited
+    pub fn download_limit(&mut self, rate: u64) -> &mut Self {
+        self.download_limit = rate;
+        self
+    }
+
+    /// Reveals the pieces to peers one at a time until they spread, to seed a new torrent with as
+    /// little upload as possible. Only used while we have every piece.
+    pub fn super_seeding(&mut self, enabled: bool) -> &mut Self {
+        self.super_seeding = enabled;
+        self
+    }
+
+    /// Pauses the torrent once it's finished and uploaded `ratio` times what it downloaded
+    pub fn ratio_limit(&mut self, ratio: f64) -> &mut Self {
+        self.ratio_limit = Some(ratio);
+        self
+    }
+
+    /// Pauses the torrent once it's been seeding for `time`
+    pub fn seed_time_limit(&mut self, time: Duration) -> &mut Self {
+        self.seed_time_limit = Some(time);
+        self
+    }
+
+    /// Pauses the torrent once it's finished and nothing was uploaded for `time`
+    pub fn seed_idle_limit(&mut self, time: Duration) -> &mut Self {
+        self.seed_idle_limit = Some(time);
+        self
+    }
+}
+
+impl Default for AddOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// State shared by the session and all of its torrents
+struct Shared {
+    peer_id: [u8; 20],
+    port: u16,
+    keep_alive_interval: Duration,
+    encryption: EncryptionPolicy,
+    client: Client,
+    disk: DiskIo,
+    /// Connections across every torrent
+    connections: Arc<Semaphore>,
+    max_connections_per_torrent: usize,
+    torrents: Mutex<HashMap<[u8; 20], TorrentHandle>>,
+    events: broadcast::Sender<SessionEvent>,
+    /// Traffic of every connection
+    stats: Arc<TransferStats>,
+    /// Limits shared by every connection
+    limits: Arc<RateLimits>,
+    /// Upload and download limits each new connection starts with
+    peer_limits: (u64, u64),
+    /// Whether protocol messages count against the limits
+    limit_overhead: bool,
+    choker: ChokerFactory,
+    /// Socket for uTP connections both ways, if it's enabled
+    utp: Option<Arc<UtpSocket>>,
+}
+
+impl Shared {
+    fn connection_builder(&self) -> ConnectionBuilder {
+        let mut builder = ConnectionBuilder::new();
+        builder
+            .keep_alive_interval(self.keep_alive_interval)
+            .encryption(self.encryption);
+        builder
+    }
+
+    /// Creates limits that count what the session was configured to
+    fn rate_limits(&self, upload: u64, download: u64) -> RateLimits {
+        let limits = RateLimits::new(upload, download);
+        limits.set_include_overhead(self.limit_overhead);
+        limits
+    }
+
+    fn torrent(&self, info_hash: [u8; 20]) -> Option<TorrentHandle> {
+        let torrents = self.torrents.lock().expect("Poisoned lock");
+        torrents.get(&info_hash).cloned()
+    }
+
+    fn emit(&self, event: SessionEvent) {
+        // Nobody is listening if it fails
+        let _ = self.events.send(event);
+    }
+}
+
+/// Downloads torrents, owning everything they share: the listener, the tracker client and the disk I/O.
+///
+/// Peers come from trackers, magnet links and [`TorrentHandle::add_peer`], there's no DHT.
+pub struct Session {
+    shared: Arc<Shared>,
+    /// Tasks accepting incoming connections
+    listeners: Vec<JoinHandle<()>>,
+}
+
+impl Session {
+    pub fn builder() -> SessionBuilder {
+        SessionBuilder::new()
+    }
+
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.shared.peer_id
+    }
+
+    /// The port incoming connections are accepted on
+    pub fn port(&self) -> u16 {
+        self.shared.port
+    }
+
+    pub fn disk(&self) -> &DiskIo {
+        &self.shared.disk
+    }
+
+    /// Limits of the whole session, their rates can be changed at any time
+    pub fn limits(&self) -> &RateLimits {
+        &self.shared.limits
+    }
+
+    /// Returns a builder for peer connections configured with the session settings
+    pub fn connection_builder(&self) -> ConnectionBuilder {
+        self.shared.connection_builder()
+    }
+
+    /// Returns a stream of everything that happens to the torrents from now on.
+    ///
+    /// Receivers that fall too far behind lose the oldest events, see [`SessionBuilder::event_capacity`].
+    pub fn subscribe(&self) -> broadcast::Receiver<SessionEvent> {
+        self.shared.events.subscribe()
+    }
+
+    /// Adds a torrent and starts downloading it, unless it's added paused.
+    ///
+    /// Existing data is checked in the background, [`TorrentHandle::wait_for_metadata`] waits for it.
+    pub fn add_torrent<T: Into<TorrentSource>>(
+        &self,
+        source: T,
+        options: &AddOptions,
+    ) -> Result<TorrentHandle> {
+        let (info_hash, name, trackers, peers, info) = match source.into() {
+            TorrentSource::MetaInfo(meta_info) => {
+                let mut trackers = Vec::new();
+                for url in meta_info
+                    .announce
+                    .iter()
+                    .chain(meta_info.announce_list.iter().flatten().flatten())
+                {
+                    if !trackers.contains(&url.to_string()) {
+                        trackers.push(url.to_string());
+                    }
+                }
+                (
+                    meta_info.info_hash()?,
+                    Some(meta_info.info.name.clone()),
+                    trackers,
+                    Vec::new(),
+                    Some(meta_info.info.clone()),
+                )
+            }
+            TorrentSource::Magnet(magnet) => {
+                let peers = magnet
+                    .peers
+                    .iter()
+                    .filter_map(|peer| peer.parse().ok())
+                    .collect();
+                (
+                    magnet.info_hash,
+                    magnet.display_name,
+                    magnet.trackers,
+                    peers,
+                    None,
+                )
+            }
+        };
+
+        let handle = {
+            let mut torrents = self.shared.torrents.lock().expect("Poisoned lock");
+            if torrents.contains_key(&info_hash) {
+                return Err(SessionError::DuplicateTorrent.into());
+            }
+            let handle = TorrentHandle::new(
+                self.shared.clone(),
+                info_hash,
+                name,
+                trackers,
+                options.clone(),
+            );
+            torrents.insert(info_hash, handle.clone());
+            handle
+        };
+        self.shared.emit(SessionEvent::TorrentAdded { info_hash });
+
+        if let Some(info) = info {
+            handle.inner.set_info(info)?;
+        }
+        for peer in peers {
+            handle.add_peer(peer);
+        }
+        if !options.paused {
+            handle.inner.start();
+        }
+
+        Ok(handle)
+    }
+
+    pub fn torrent(&self, info_hash: [u8; 20]) -> Option<TorrentHandle> {
+        self.shared.torrent(info_hash)
+    }
+
+    pub fn torrents(&self) -> Vec<TorrentHandle> {
+        let torrents = self.shared.torrents.lock().expect("Poisoned lock");
+        torrents.values().cloned().collect()
+    }
+
+    /// Totals and rates across every torrent
+    pub fn status(&self) -> SessionStatus {
+        let torrents = self.torrents();
+        SessionStatus {
+            torrents: torrents.len(),
+            peers: torrents.iter().map(TorrentHandle::peer_count).sum(),
+            transfer: self.shared.stats.snapshot(),
+        }
+    }
+
+    /// Disconnects a torrent from everyone and drops it from the session.
+    ///
+    /// Its data is deleted with `delete_files`, otherwise the resume data is saved so it can be added back.
+    pub async fn remove_torrent(&self, info_hash: [u8; 20], delete_files: bool) -> Result<()> {
+        let handle = self
+            .shared
+            .torrents
+            .lock()
+            .expect("Poisoned lock")
+            .remove(&info_hash)
+            .ok_or(SessionError::UnknownTorrent)?;
+
+        let result = handle.inner.remove(delete_files).await;
+        self.shared.emit(SessionEvent::TorrentRemoved { info_hash });
+        result
+    }
+
+    /// Removes every torrent, keeping their data and saving their resume data
+    pub async fn shutdown(self) -> Result<()> {
+        for handle in self.torrents() {
+            self.remove_torrent(handle.info_hash(), false).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        for listener in &self.listeners {
+            listener.abort();
+        }
+        // Torrents keep a reference to the session, which has to be broken here
+        let mut torrents = self.shared.torrents.lock().expect("Poisoned lock");
+        for (_, handle) in torrents.drain() {
+            handle.inner.close();
+        }
+    }
+}
+
+pub struct SessionBuilder {
+    peer_id: [u8; 20],
+    keep_alive_interval: Duration,
+    listen_port: u16,
+    encryption: EncryptionPolicy,
+    utp: bool,
+    disk: Option<DiskIo>,
+    max_connections: usize,
+    max_connections_per_torrent: usize,
+    event_capacity: usize,
+    upload_limit: u64,
+    download_limit: u64,
+    peer_upload_limit: u64,
+    peer_download_limit: u64,
+    limit_overhead: bool,
+    unchoke_slots: usize,
+    choker: Option<ChokerFactory>,
+}
+
+impl SessionBuilder {
+    pub fn new() -> Self {
+        Self {
+            peer_id: peers::peer_id(b"LE", b"0001"),
+            keep_alive_interval: Duration::from_secs(120),
+            listen_port: 6881,
+            encryption: EncryptionPolicy::PreferEncrypted,
+            utp: true,
+            disk: None,
+            max_connections: 200,
+            max_connections_per_torrent: 50,
+            event_capacity: 1024,
+            upload_limit: 0,
+            download_limit: 0,
+            peer_upload_limit: 0,
+            peer_download_limit: 0,
+            limit_overhead: true,
+            unchoke_slots: 4,
+            choker: None,
+        }
+    }
+
+    /// Starts listening for peers and returns the session
+    pub async fn connect(&mut self) -> Result<Session> {
+        // An ipv6 socket takes ipv4 connections as well where the system allows it, otherwise
+        // there's a second listener for them
+        let mut tcp = Vec::new();
+        match TcpListener::bind((Ipv6Addr::UNSPECIFIED, self.listen_port)).await {
+            Ok(listener) => tcp.push(listener),
+            Err(error) => debug!("Failed to listen on ipv6: {}", error),
+        }
+        let port = match tcp.first() {
+            Some(listener) => listener.local_addr()?.port(),
+            None => self.listen_port,
+        };
+        match TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await {
+            Ok(listener) => tcp.push(listener),
+            Err(error) if tcp.is_empty() => return Err(error.into()),
+            Err(_) => {}
+        }
+        let port = tcp[0].local_addr()?.port();
+
+        let utp = match self.utp {
+            // uTP is optional, the session still works over tcp if the udp port is taken
+            true => match UtpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).await {
+                Ok(socket) => Some(Arc::new(socket)),
+                Err(error) => {
+                    debug!("Failed to listen for uTP connections: {}", error);
+                    None
+                }
+            },
+            false => None,
+        };
+
+        let shared = Arc::new(Shared {
+            peer_id: self.peer_id,
+            port,
+            keep_alive_interval: self.keep_alive_interval,
+            encryption: self.encryption,
+            client: Client::new().await?,
+            disk: self.disk.clone().unwrap_or_default(),
+            connections: Arc::new(Semaphore::new(self.max_connections)),
+            max_connections_per_torrent: self.max_connections_per_torrent,
+            torrents: Mutex::new(HashMap::new()),
+            events: broadcast::channel(self.event_capacity).0,
+            stats: Arc::new(TransferStats::new()),
+            limits: Arc::new(RateLimits::new(self.upload_limit, self.download_limit)),
+            peer_limits: (self.peer_upload_limit, self.peer_download_limit),
+            limit_overhead: self.limit_overhead,
+            choker: self.choker.clone().unwrap_or_else(|| {
+                let slots = self.unchoke_slots;
+                Arc::new(move || Box::new(TitForTat::new(slots)))
+            }),
+            utp,
+        });
+        shared.limits.set_include_overhead(self.limit_overhead);
+
+        let mut listeners: Vec<JoinHandle<()>> = tcp
+            .into_iter()
+            .map(|listener| tokio::spawn(accept_tcp(shared.clone(), listener)))
+            .collect();
+        if let Some(socket) = shared.utp.clone() {
+            listeners.push(tokio::spawn(accept_utp(shared.clone(), socket)));
+        }
+
+        Ok(Session { shared, listeners })
+    }
+
+    /// Sets how often keep-alives are sent to peers we have nothing else to say to
+    pub fn keep_alive_interval(&mut self, interval: Duration) -> &mut Self {
+        self.keep_alive_interval = interval;
+        self
+    }
+
+    pub fn peer_id(&mut self, peer_id: [u8; 20]) -> &mut Self {
+        self.peer_id = peer_id;
+        self
+    }
+
+    /// Sets the port to accept connections on, 0 picks any free one
+    pub fn listen_port(&mut self, port: u16) -> &mut Self {
+        self.listen_port = port;
+        self
+    }
+
+    /// Sets how message stream encryption is negotiated with every peer
+    pub fn encryption(&mut self, policy: EncryptionPolicy) -> &mut Self {
+        self.encryption = policy;
+        self
+    }
+
+    /// Sets whether uTP is used on the same port, peers are then dialed over uTP before tcp
+    pub fn utp(&mut self, enabled: bool) -> &mut Self {
+        self.utp = enabled;
+        self
+    }
+
+    /// Sets the disk I/O the torrents go through, so its caches can be configured
+    pub fn disk_io(&mut self, disk: DiskIo) -> &mut Self {
+        self.disk = Some(disk);
+        self
+    }
+
+    /// Sets how many peers the session can be connected to across all of its torrents
+    pub fn max_connections(&mut self, max: usize) -> &mut Self {
+        self.max_connections = max;
+        self
+    }
+
+    /// Sets how many peers a single torrent can be connected to
+    pub fn max_connections_per_torrent(&mut self, max: usize) -> &mut Self {
+        self.max_connections_per_torrent = max;
+        self
+    }
+
+    /// Sets how many events are kept for subscribers that haven't received them yet
+    pub fn event_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.event_capacity = capacity;
+        self
+    }
+
+    /// Sets the upload limit of the session in bytes per second, 0 means unlimited
+    pub fn upload_limit(&mut self, rate: u64) -> &mut Self {
+        self.upload_limit = rate;
+        self
+    }
+
+    /// Sets the download limit of the session in bytes per second, 0 means unlimited
+    pub fn download_limit(&mut self, rate: u64) -> &mut Self {
+        self.download_limit = rate;
+        self
+    }
+
+    /// Sets the upload limit every connection starts with, 0 means unlimited
+    pub fn peer_upload_limit(&mut self, rate: u64) -> &mut Self {
+        self.peer_upload_limit = rate;
+        self
+    }
+
+    /// Sets the download limit every connection starts with, 0 means unlimited
+    pub fn peer_download_limit(&mut self, rate: u64) -> &mut Self {
+        self.peer_download_limit = rate;
+        self
+    }
+
+    /// Sets whether protocol messages count against the limits, otherwise only piece data does
+    pub fn limit_overhead(&mut self, include: bool) -> &mut Self {
+        self.limit_overhead = include;
+        self
+    }
+
+    /// Sets how many peers of each torrent are unchoked for their rates, on top of the optimistic unchoke
+    pub fn unchoke_slots(&mut self, slots: usize) -> &mut Self {
+        self.unchoke_slots = slots;
+        self
+    }
+
+    /// Replaces the tit-for-tat choker, `choker` is called for each torrent that is added.
+    ///
+    /// The unchoke slots aren't used by a custom choker.
+    pub fn choker<F>(&mut self, choker: F) -> &mut Self
+    where
+        F: Fn() -> Box<dyn Choker> + Send + Sync + 'static,
+    {
+        self.choker = Some(Arc::new(choker));
+        self
+    }
+}
+
+impl Default for SessionBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+async fn accept_tcp(shared: Arc<Shared>, listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                // Peers reaching the ipv6 socket over ipv4 get their plain address back
+                let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+                tokio::spawn(incoming(shared.clone(), stream, addr));
+            }
+            Err(error) => debug!("Failed to accept connection: {}", error),
+        }
+    }
+}
+
+async fn accept_utp(shared: Arc<Shared>, socket: Arc<UtpSocket>) {
+    loop {
+        match socket.accept().await {
+            Ok(stream) => {
+                let addr = stream.peer_addr();
+                tokio::spawn(incoming(shared.clone(), stream, addr));
+            }
+            Err(error) => debug!("Failed to accept uTP connection: {}", error),
+        }
+    }
+}
+
+/// Handshakes a peer that connected to us and hands it to the torrent it asked for
+async fn incoming<S>(shared: Arc<Shared>, stream: S, addr: SocketAddr)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let info_hashes: Vec<[u8; 20]> = shared
+        .torrents
+        .lock()
+        .expect("Poisoned lock")
+        .values()
+        .filter(|handle| handle.inner.is_active())
+        .map(TorrentHandle::info_hash)
+        .collect();
+
+    let builder = shared.connection_builder();
+    let (info_hash, connection) = match timeout(
+        HANDSHAKE_TIMEOUT,
+        builder.accept(stream, &info_hashes, shared.peer_id),
+    )
+    .await
+    {
+        Ok(Ok(accepted)) => accepted,
+        Ok(Err(error)) => return debug!("Failed to handshake with {}: {}", addr, error),
+        Err(_) => return debug!("Handshake with {} timed out", addr),
+    };
+
+    let Some(handle) = shared.torrent(info_hash) else {
+        return;
+    };
+    match handle.inner.permits() {
+        Some(permits) => peer::run(handle.inner, addr, connection, permits).await,
+        None => debug!("Too many connections, dropping {}", addr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{meta_info::Info, pipeline::BLOCK_SIZE};
+    use std::{env::temp_dir, fs, path::Path};
+    use tokio::io::AsyncReadExt;
+
+    fn meta_info(data: &[u8]) -> MetaInfo {
+        let piece_length = BLOCK_SIZE as usize * 2;
+
+        MetaInfo {
+            announce: None,
+            announce_list: None,
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            encoding: None,
+            http_seeds: None,
+            info: Info::from_data("file", piece_length, data),
+            url_list: None,
+        }
+    }
+
+    async fn session() -> Session {
+        Session::builder()
+            .listen_port(0)
+            .utp(false)
+            .connect()
+            .await
+            .unwrap()
+    }
+
+    fn seed(root: &Path) -> MetaInfo {
+        let data: Vec<u8> = (0..BLOCK_SIZE * 5).map(|_| rand::random()).collect();
+        fs::create_dir_all(root).unwrap();
+        fs::write(root.join("file"), &data).unwrap();
+        meta_info(&data)
+    }
+
+    #[tokio::test]
+    async fn magnet_links_get_metadata_from_peers() {
+        let root = temp_dir().join(format!("leech-session-{}", rand::random::<u64>()));
+        let meta_info = seed(&root.join("seed"));
+        let info_hash = meta_info.info_hash().unwrap();
+
+        let seeder = session().await;
+        let seed = seeder
+            .add_torrent(
+                meta_info.clone(),
+                AddOptions::new().save_path(root.join("seed")),
+            )
+            .unwrap();
+        timeout(Duration::from_secs(10), seed.wait_finished())
+            .await
+            .unwrap();
+
+        let leecher = session().await;
+        let mut events = leecher.subscribe();
+        let magnet = Magnet {
+            info_hash,
+            display_name: None,
+            trackers: Vec::new(),
+            peers: vec![format!("127.0.0.1:{}", seeder.port())],
+        };
+        let handle = leecher
+            .add_torrent(magnet, AddOptions::new().save_path(root.join("leech")))
+            .unwrap();
+        assert_eq!(handle.name(), None);
+
+        let torrent = timeout(Duration::from_secs(10), handle.wait_for_metadata())
+            .await
+            .unwrap();
+        assert_eq!(torrent.info().pieces, meta_info.info.pieces);
+        assert_eq!(handle.name().as_deref(), Some("file"));
+        assert_eq!(handle.peer_count(), 1);
+        assert!(!handle.is_finished());
+
+        assert_eq!(
+            events.recv().await.unwrap(),
+            SessionEvent::TorrentAdded { info_hash }
+        );
+        let SessionEvent::PeerConnected { peer, peer_id, .. } = events.recv().await.unwrap() else {
+            panic!("Expected the seed to connect");
+        };
+        assert_eq!(peer.port(), seeder.port());
+        assert_eq!(peer_id, seeder.peer_id());
+        assert_eq!(
+            events.recv().await.unwrap(),
+            SessionEvent::MetadataReceived { info_hash }
+        );
+
+        let peers = handle.peers();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(
+            peers[0].client.as_deref(),
+            Some(concat!("Leech ", env!("CARGO_PKG_VERSION")))
+        );
+        assert!(peers[0].flags.extension_protocol);
+        assert!(peers[0].transfer.protocol_download.total > 0);
+
+        let status = handle.status().await;
+        assert_eq!(status.state, TorrentState::Downloading);
+        assert_eq!(status.peers, 1);
+        assert_eq!(status.progress, 0.0);
+        assert_eq!(status.total_wanted, BLOCK_SIZE as u64 * 5);
+        assert_eq!(status.file_progress, vec![0]);
+        assert_eq!(seed.status().await.state, TorrentState::Seeding);
+        assert_eq!(leecher.status().torrents, 1);
+
+        leecher.remove_torrent(info_hash, true).await.unwrap();
+        assert!(leecher.torrent(info_hash).is_none());
+        seeder.shutdown().await.unwrap();
+        assert!(root.join("seed/file").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn seeds_until_the_ratio_goal() {
+        let root = temp_dir().join(format!("leech-session-{}", rand::random::<u64>()));
+        let meta_info = seed(&root.join("seed"));
+        let info_hash = meta_info.info_hash().unwrap();
+
+        let seeder = session().await;
+        let mut events = seeder.subscribe();
+        let seed = seeder
+            .add_torrent(
+                meta_info.clone(),
+                AddOptions::new()
+                    .save_path(root.join("seed"))
+                    .ratio_limit(1.0),
+            )
+            .unwrap();
+        timeout(Duration::from_secs(10), seed.wait_finished())
+            .await
+            .unwrap();
+
+        let leecher = session().await;
+        let handle = leecher
+            .add_torrent(meta_info, AddOptions::new().save_path(root.join("leech")))
+            .unwrap();
+        handle.add_peer(SocketAddr::from(([127, 0, 0, 1], seeder.port())));
+        // The seed unchokes the leecher at its next choking round
+        timeout(Duration::from_secs(60), handle.wait_finished())
+            .await
+            .unwrap();
+        assert_eq!(
+            fs::read(root.join("leech/file")).unwrap(),
+            fs::read(root.join("seed/file")).unwrap()
+        );
+
+        timeout(Duration::from_secs(20), async {
+            while events.recv().await.unwrap() != (SessionEvent::SeedGoalReached { info_hash }) {}
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            events.recv().await.unwrap(),
+            SessionEvent::TorrentPaused { info_hash }
+        );
+        // Blocks the leecher asked for again after a timeout are sent twice
+        let status = seed.status().await;
+        assert_eq!(status.state, TorrentState::Paused);
+        assert!(status.all_time_upload >= BLOCK_SIZE as u64 * 5);
+        assert_eq!(status.transfer.payload_upload.total, status.all_time_upload);
+        assert!(status.ratio >= 1.0);
+
+        leecher.shutdown().await.unwrap();
+        seeder.shutdown().await.unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn dials_peers_over_utp() {
+        let root = temp_dir().join(format!("leech-session-{}", rand::random::<u64>()));
+        let meta_info = seed(&root.join("seed"));
+
+        let seeder = Session::builder().listen_port(0).connect().await.unwrap();
+        let seed = seeder
+            .add_torrent(
+                meta_info.clone(),
+                AddOptions::new().save_path(root.join("seed")),
+            )
+            .unwrap();
+        timeout(Duration::from_secs(10), seed.wait_finished())
+            .await
+            .unwrap();
+
+        let leecher = Session::builder().listen_port(0).connect().await.unwrap();
+        let handle = leecher
+            .add_torrent(meta_info, AddOptions::new().save_path(root.join("leech")))
+            .unwrap();
+        handle.add_peer(SocketAddr::from(([127, 0, 0, 1], seeder.port())));
+        timeout(Duration::from_secs(60), handle.wait_finished())
+            .await
+            .unwrap();
+
+        // Over tcp the leecher would come from another port than the one it listens on
+        let peers = seed.peers();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].addr.port(), leecher.port());
+
+        leecher.shutdown().await.unwrap();
+        seeder.shutdown().await.unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn downloads_into_memory() {
+        let root = temp_dir().join(format!("leech-session-{}", rand::random::<u64>()));
+        let meta_info = seed(&root.join("seed"));
+
+        let seeder = session().await;
+        let seed = seeder
+            .add_torrent(
+                meta_info.clone(),
+                AddOptions::new().save_path(root.join("seed")),
+            )
+            .unwrap();
+        timeout(Duration::from_secs(10), seed.wait_finished())
+            .await
+            .unwrap();
+
+        let leecher = session().await;
+        let handle = leecher
+            .add_torrent(
+                meta_info,
+                AddOptions::new()
+                    .save_path(root.join("leech"))
+                    .storage(StorageBackend::Memory { limit: None }),
+            )
+            .unwrap();
+        handle.add_peer(SocketAddr::from(([127, 0, 0, 1], seeder.port())));
+        timeout(Duration::from_secs(60), handle.wait_finished())
+            .await
+            .unwrap();
+
+        let mut data = Vec::new();
+        let torrent = handle.torrent().unwrap();
+        torrent
+            .open_file(0)
+            .unwrap()
+            .read_to_end(&mut data)
+            .await
+            .unwrap();
+        assert_eq!(data, fs::read(root.join("seed/file")).unwrap());
+        leecher.shutdown().await.unwrap();
+        // Not even the resume data is written
+        assert!(!root.join("leech").exists());
+
+        seeder.shutdown().await.unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn partial_seeds_leave_seeds_alone() {
+        let root = temp_dir().join(format!("leech-session-{}", rand::random::<u64>()));
+        let meta_info = seed(&root.join("seed"));
+
+        let seeder = session().await;
+        let seed = seeder
+            .add_torrent(
+                meta_info.clone(),
+                AddOptions::new().save_path(root.join("seed")),
+            )
+            .unwrap();
+        timeout(Duration::from_secs(10), seed.wait_finished())
+            .await
+            .unwrap();
+
+        // Every file is skipped so there is nothing left to download
+        let leecher = session().await;
+        let mut events = leecher.subscribe();
+        let handle = leecher
+            .add_torrent(
+                meta_info,
+                AddOptions::new()
+                    .save_path(root.join("leech"))
+                    .file_priorities(vec![FilePriority::Skip]),
+            )
+            .unwrap();
+        timeout(Duration::from_secs(10), handle.wait_finished())
+            .await
+            .unwrap();
+        assert_eq!(handle.status().await.state, TorrentState::Finished);
+
+        handle.add_peer(SocketAddr::from(([127, 0, 0, 1], seeder.port())));
+        let error = timeout(Duration::from_secs(15), async {
+            loop {
+                if let SessionEvent::PeerDisconnected { error, .. } = events.recv().await.unwrap() {
+                    return error;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(error, None);
+        assert_eq!(handle.peer_count(), 0);
+
+        // Wanting the file again makes the seed worth downloading from
+        assert!(handle
+            .set_file_priority(1, FilePriority::Normal)
+            .await
+            .is_err());
+        handle
+            .set_file_priority(0, FilePriority::Normal)
+            .await
+            .unwrap();
+        assert!(!handle.is_finished());
+        handle.add_peer(SocketAddr::from(([127, 0, 0, 1], seeder.port())));
+        timeout(Duration::from_secs(60), handle.wait_finished())
+            .await
+            .unwrap();
+        assert_eq!(
+            fs::read(root.join("leech/file")).unwrap(),
+            fs::read(root.join("seed/file")).unwrap()
+        );
+
+        leecher.shutdown().await.unwrap();
+        seeder.shutdown().await.unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn pause_resume_and_remove() {
+        let root = temp_dir().join(format!("leech-session-{}", rand::random::<u64>()));
+        let meta_info = seed(&root);
+        let info_hash = meta_info.info_hash().unwrap();
+        let session = session().await;
+        let mut events = session.subscribe();
+
+        let handle = session
+            .add_torrent(
+                meta_info.clone(),
+                AddOptions::new().save_path(&root).paused(true),
+            )
+            .unwrap();
+        assert!(handle.is_paused());
+        // Existing data is checked even while paused
+        handle.wait_for_metadata().await;
+        assert!(handle.is_finished());
+
+        let status = handle.status().await;
+        assert_eq!(status.state, TorrentState::Paused);
+        assert_eq!(status.progress, 1.0);
+        assert_eq!(status.file_progress, vec![BLOCK_SIZE as u64 * 5]);
+        assert_eq!(status.eta, Some(Duration::ZERO));
+
+        handle.resume();
+        assert!(!handle.is_paused());
+        handle.pause().await.unwrap();
+        assert!(handle.is_paused());
+
+        let error = session
+            .add_torrent(meta_info, &AddOptions::new())
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(SessionError::DuplicateTorrent)
+        ));
+        assert_eq!(session.torrents().len(), 1);
+
+        session.remove_torrent(info_hash, true).await.unwrap();
+        assert!(!root.join("file").exists());
+        for event in [
+            SessionEvent::TorrentAdded { info_hash },
+            SessionEvent::TorrentResumed { info_hash },
+            SessionEvent::TorrentPaused { info_hash },
+            SessionEvent::TorrentRemoved { info_hash },
+        ] {
+            assert_eq!(events.try_recv().unwrap(), event);
+        }
+
+        let error = session.remove_torrent(info_hash, true).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(SessionError::UnknownTorrent)
+        ));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
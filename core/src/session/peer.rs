@@ -0,0 +1,708 @@
+use bitvec::vec::BitVec;
+use bytes::Bytes;
+use color_eyre::eyre::Result;
+use std::{
+    collections::VecDeque,
+    future::ready,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{mpsc, watch, OwnedSemaphorePermit},
+    time::Instant,
+};
+use tracing::debug;
+
+use super::{handle::Inner, metadata, PeerFlags, PeerStatus, SessionEvent};
+use crate::{
+    download::BlockOutcome, limit::RateLimits, pipeline::Block, stats::TransferStats,
+    torrent::Torrent, Connection, ConnectionEvent, Message, MetadataMessage,
+};
+
+/// The id we want for metadata messages, sent in our extended handshake
+pub(super) const UT_METADATA: u8 = 1;
+/// Peers that sent blocks of this many pieces that failed the hash check are banned
+const MAX_HASH_FAILURES: u32 = 3;
+/// Requests a peer can queue up with us, sent as `reqq` in the extended handshake
+pub(super) const MAX_UPLOAD_QUEUE: usize = 250;
+
+/// Something a peer task is asked to do by the rest of the torrent
+#[derive(Debug, Clone, Copy)]
+pub(super) enum PeerCommand {
+    /// We have a new piece
+    Have(u32),
+    /// The block was received from someone else
+    Cancel(Block),
+    /// The choker stopped letting the peer download from us
+    Choke,
+    /// The choker let the peer download from us
+    Unchoke,
+    /// The piece revealed to the peer while super seeding spread, it can be shown another one
+    Reveal,
+    /// The pieces we want changed, the peer is told if we only upload now and whether it has anything we want
+    Wanted,
+}
+
+/// How the torrent talks to one of its peer tasks, dropping it disconnects the peer
+pub(super) struct PeerHandle {
+    pub id: u64,
+    pub commands: mpsc::UnboundedSender<PeerCommand>,
+    pub connected: Instant,
+    pub stats: Arc<TransferStats>,
+    pub limits: Arc<RateLimits>,
+    /// Updated by the peer task as it goes, the transfer is filled in from `stats`
+    pub status: Arc<Mutex<PeerStatus>>,
+}
+
+impl PeerHandle {
+    pub fn status(&self) -> PeerStatus {
+        let mut status = self.status.lock().expect("Poisoned lock").clone();
+        status.transfer = self.stats.snapshot();
+        status
+    }
+}
+
+/// Counts a connection against the limits of the session and of the torrent until it's dropped
+pub(super) struct Permits {
+    _session: OwnedSemaphorePermit,
+    _torrent: OwnedSemaphorePermit,
+}
+
+impl Permits {
+    pub fn new(session: OwnedSemaphorePermit, torrent: OwnedSemaphorePermit) -> Self {
+        Self {
+            _session: session,
+            _torrent: torrent,
+        }
+    }
+}
+
+/// Everything about a peer but its transfer, which is counted as it goes
+fn status<S: AsyncRead + AsyncWrite + Unpin>(
+    addr: SocketAddr,
+    connection: &Connection<S>,
+    upload_queue: usize,
+) -> PeerStatus {
+    let bitfield = connection.bitfield();
+    let connection_status = connection.status();
+    PeerStatus {
+        addr,
+        peer_id: connection.peer_id(),
+        client: connection
+            .client()
+            .map(str::to_string)
+            .or_else(|| peers::client_name(&connection.peer_id())),
+        flags: PeerFlags {
+            am_choking: connection_status.am_choking,
+            am_interested: connection_status.am_interested,
+            peer_choking: connection_status.peer_choking,
+            peer_interested: connection_status.peer_interested,
+            seed: !bitfield.is_empty() && bitfield.all(),
+            upload_only: connection.is_upload_only(),
+            encrypted: connection.is_encrypted(),
+            fast_extension: connection.is_fast(),
+            extension_protocol: connection.is_extended(),
+        },
+        progress: if bitfield.is_empty() {
+            0.0
+        } else {
+            bitfield.count_ones() as f64 / bitfield.len() as f64
+        },
+        transfer: Default::default(),
+        download_queue: connection.pipeline().outstanding().count(),
+        download_queue_limit: connection.pipeline().depth(),
+        upload_queue,
+    }
+}
+
+/// Talks to a connected peer until either side goes away
+pub(super) async fn run<S: AsyncRead + AsyncWrite + Unpin>(
+    inner: Arc<Inner>,
+    addr: SocketAddr,
+    mut connection: Connection<S>,
+    _permits: Permits,
+) {
+    let status = Arc::new(Mutex::new(status(addr, &connection, 0)));
+    let Some((id, commands)) = inner.register(
+        addr,
+        connection.stats().clone(),
+        connection.limits().clone(),
+        status.clone(),
+    ) else {
+        return;
+    };
+    inner.account(&mut connection);
+    debug!("Connected to {}", addr);
+    inner.emit(SessionEvent::PeerConnected {
+        info_hash: inner.info_hash(),
+        peer: addr,
+        peer_id: connection.peer_id(),
+    });
+
+    let mut peer = Peer {
+        torrent_updates: inner.subscribe(),
+        inner,
+        id,
+        addr,
+        connection,
+        commands,
+        status,
+        torrent: None,
+        metadata_request: None,
+        metadata_rejected: false,
+        uploads: VecDeque::new(),
+        super_seeding: false,
+        wasted_bytes: 0,
+    };
+    let error = match peer.run().await {
+        Ok(()) => None,
+        Err(error) => {
+            debug!("Disconnected from {}: {}", addr, error);
+            Some(error.to_string())
+        }
+    };
+    peer.disconnected().await;
+    peer.inner.unregister(addr, id);
+    peer.inner.emit(SessionEvent::PeerDisconnected {
+        info_hash: peer.inner.info_hash(),
+        peer: addr,
+        error,
+    });
+}
+
+struct Peer<S> {
+    inner: Arc<Inner>,
+    id: u64,
+    addr: SocketAddr,
+    connection: Connection<S>,
+    commands: mpsc::UnboundedReceiver<PeerCommand>,
+    torrent_updates: watch::Receiver<Option<Torrent>>,
+    status: Arc<Mutex<PeerStatus>>,
+    /// Set once the torrent is loaded and the pieces of the peer are counted by the picker
+    torrent: Option<Torrent>,
+    /// Piece of the metadata we are waiting for
+    metadata_request: Option<u32>,
+    /// The peer won't send us the metadata
+    metadata_rejected: bool,
+    /// Blocks the peer asked for, served in order
+    uploads: VecDeque<Block>,
+    /// Pieces are revealed one at a time instead of sending our bitfield
+    super_seeding: bool,
+    /// Wasted bytes of the connection already counted by the torrent
+    wasted_bytes: u64,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Peer<S> {
+    async fn run(&mut self) -> Result<()> {
+        // The bitfield can only be the first message, later pieces are announced one by one
+        let torrent = self.torrent_updates.borrow_and_update().clone();
+        let bitfield = match &torrent {
+            Some(torrent) => torrent.download().await.picker().bitfield().to_bitvec(),
+            None => BitVec::EMPTY,
+        };
+        self.super_seeding = torrent.is_some() && self.inner.super_seeding() && bitfield.all();
+        // Super seeds pretend to have nothing and reveal pieces one at a time
+        let shown = if self.super_seeding {
+            BitVec::EMPTY
+        } else {
+            bitfield
+        };
+        self.connection.send_pieces(shown).await?;
+        if let Some(torrent) = torrent {
+            self.attach(torrent, false).await?;
+        }
+        let handshake = self.inner.extended_handshake();
+        self.connection.send_extended_handshake(&handshake).await?;
+
+        loop {
+            // Neither side wants anything from the other
+            if self.inner.is_finished() && self.is_upload_only() {
+                debug!("Disconnecting from {}, a seed like us", self.addr);
+                self.inner.upload_only(self.addr);
+                return Ok(());
+            }
+            match self.torrent.clone() {
+                Some(torrent) => self.request_blocks(&torrent).await?,
+                None => self.request_metadata().await?,
+            }
+            self.count_waste().await;
+            *self.status.lock().expect("Poisoned lock") =
+                status(self.addr, &self.connection, self.uploads.len());
+
+            tokio::select! {
+                event = self.connection.next_event() => match event? {
+                    Some(event) => self.on_event(event).await?,
+                    None => return Ok(()),
+                },
+                command = self.commands.recv() => match command {
+                    Some(command) => self.on_command(command).await?,
+                    // The torrent was stopped or the peer banned
+                    None => return Ok(()),
+                },
+                Ok(()) = self.torrent_updates.changed(), if self.torrent.is_none() => {
+                    let torrent = self.torrent_updates.borrow_and_update().clone();
+                    if let Some(torrent) = torrent {
+                        self.attach(torrent, true).await?;
+                    }
+                }
+                // One block at a time, so cancels are still heard while the queue is long
+                _ = ready(()), if !self.uploads.is_empty() => self.upload().await?,
+            }
+        }
+    }
+
+    /// Starts downloading pieces from the peer, once the torrent is loaded
+    async fn attach(&mut self, torrent: Torrent, send_haves: bool) -> Result<()> {
+        let piece_count = torrent.disk().storage().layout().piece_count();
+        self.connection.set_piece_count(piece_count)?;
+
+        let haves: Vec<usize> = {
+            let mut download = torrent.download().await;
+            let picker = download.picker_mut();
+            picker.peer_bitfield(self.connection.bitfield());
+            if send_haves {
+                picker.bitfield().iter_ones().collect()
+            } else {
+                Vec::new()
+            }
+        };
+        for index in haves {
+            self.connection.have(index as u32).await?;
+        }
+
+        if let Some(piece) = self.metadata_request.take() {
+            self.inner
+                .metadata_download()
+                .lock()
+                .expect("Poisoned lock")
+                .abort(piece);
+        }
+        self.torrent = Some(torrent);
+        if self.super_seeding {
+            self.reveal_piece().await?;
+        }
+        self.update_interest().await
+    }
+
+    /// Adds the blocks the connection dropped since the last call to the waste of the torrent
+    async fn count_waste(&mut self) {
+        let wasted = self.connection.wasted_bytes();
+        if wasted > self.wasted_bytes {
+            if let Some(torrent) = &self.torrent {
+                let bytes = wasted - self.wasted_bytes;
+                torrent.download().await.picker_mut().add_wasted(bytes);
+            }
+            self.wasted_bytes = wasted;
+        }
+    }
+
+    /// Whether the peer is a seed or a partial seed
+    fn is_upload_only(&self) -> bool {
+        let bitfield = self.connection.bitfield();
+        self.connection.is_upload_only()
+            || (self.torrent.is_some() && !bitfield.is_empty() && bitfield.all())
+    }
+
+    /// Shows the peer one more of our pieces while super seeding
+    async fn reveal_piece(&mut self) -> Result<()> {
+        let Some(torrent) = self.torrent.clone() else {
+            return Ok(());
+        };
+        let piece = {
+            let download = torrent.download().await;
+            let mut super_seed = self.inner.super_seed().lock().expect("Poisoned lock");
+            super_seed.reveal(self.addr, self.connection.bitfield(), download.picker())
+        };
+        match piece {
+            Some(index) => self.connection.have(index).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn on_event(&mut self, event: ConnectionEvent) -> Result<()> {
+        match event {
+            ConnectionEvent::Choked { dropped } => self.abort(&dropped).await,
+            ConnectionEvent::Have(index) => {
+                if let Some(torrent) = &self.torrent {
+                    torrent.download().await.picker_mut().peer_have(index);
+                }
+                if self.super_seeding {
+                    let spread = self
+                        .inner
+                        .super_seed()
+                        .lock()
+                        .expect("Poisoned lock")
+                        .peer_have(self.addr, index);
+                    for addr in spread {
+                        self.inner.send(addr, PeerCommand::Reveal);
+                    }
+                }
+                self.update_interest().await?;
+            }
+            ConnectionEvent::Bitfield => {
+                if let Some(torrent) = &self.torrent {
+                    let mut download = torrent.download().await;
+                    download
+                        .picker_mut()
+                        .peer_bitfield(self.connection.bitfield());
+                }
+                self.update_interest().await?;
+            }
+            ConnectionEvent::Block { index, begin, data } => {
+                self.on_block(index, begin, data).await?
+            }
+            ConnectionEvent::Rejected(block) => self.abort(&[block]).await,
+            ConnectionEvent::TimedOut(blocks) => self.abort(&blocks).await,
+            ConnectionEvent::Request {
+                index,
+                begin,
+                length,
+            } => self.on_request(Block::new(index, begin, length)).await?,
+            ConnectionEvent::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                let block = Block::new(index, begin, length);
+                self.uploads.retain(|queued| *queued != block);
+            }
+            ConnectionEvent::Extended { id, payload } if id == UT_METADATA => {
+                self.on_metadata(payload).await?
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn on_command(&mut self, command: PeerCommand) -> Result<()> {
+        match command {
+            PeerCommand::Have(index) => {
+                if !self.connection.has_piece(index) {
+                    self.connection.have(index).await?;
+                }
+                self.update_interest().await
+            }
+            PeerCommand::Cancel(block) => self.connection.cancel(block).await,
+            PeerCommand::Choke => {
+                self.connection.choke().await?;
+                // Choking drops every request, peers with the fast extension are told which
+                while let Some(block) = self.uploads.pop_front() {
+                    self.connection.reject(block).await?;
+                }
+                Ok(())
+            }
+            PeerCommand::Unchoke => self.connection.unchoke().await,
+            PeerCommand::Reveal => self.reveal_piece().await,
+            PeerCommand::Wanted => {
+                let handshake = self.inner.extended_handshake();
+                self.connection.send_extended_handshake(&handshake).await?;
+                self.update_interest().await
+            }
+        }
+    }
+
+    /// Queues a block to send the peer, if it's allowed to ask for it
+    async fn on_request(&mut self, block: Block) -> Result<()> {
+        let valid = match &self.torrent {
+            Some(torrent) => {
+                let mut download = torrent.download().await;
+                download.forget_evicted();
+                download.picker().is_valid_request(&block)
+            }
+            None => false,
+        };
+        if !valid {
+            debug!("Invalid request from {}: {:?}", self.addr, block);
+            return self.connection.reject(block).await;
+        }
+        if self.connection.status().am_choking || self.uploads.len() >= MAX_UPLOAD_QUEUE {
+            return self.connection.reject(block).await;
+        }
+        if !self.uploads.contains(&block) {
+            self.uploads.push_back(block);
+        }
+        Ok(())
+    }
+
+    /// Sends the peer the first block it asked for
+    async fn upload(&mut self) -> Result<()> {
+        let (Some(torrent), Some(block)) = (self.torrent.clone(), self.uploads.pop_front()) else {
+            return Ok(());
+        };
+        let data = match torrent
+            .disk()
+            .read(block.index, block.begin, block.length)
+            .await
+        {
+            Ok(data) => data,
+            Err(error) => {
+                // Pieces evicted from memory since the request are rejected, the storage is fine
+                let mut download = torrent.download().await;
+                download.forget_evicted();
+                if !download.picker().has_piece(block.index) {
+                    drop(download);
+                    return self.connection.reject(block).await;
+                }
+                drop(download);
+                self.inner.emit(SessionEvent::StorageError {
+                    info_hash: self.inner.info_hash(),
+                    error: error.to_string(),
+                });
+                return Err(error);
+            }
+        };
+        self.connection
+            .send(Message::piece(block.index, block.begin, data))
+            .await?;
+        torrent.download().await.record_upload(block.length as u64);
+        Ok(())
+    }
+
+    async fn on_block(&mut self, index: u32, begin: u32, data: Bytes) -> Result<()> {
+        let Some(torrent) = self.torrent.clone() else {
+            return Ok(());
+        };
+        let block = Block::new(index, begin, data.len() as u32);
+        let endgame = torrent.download().await.picker().is_endgame();
+
+        let outcome = match torrent.block_received(self.addr, index, begin, data).await {
+            Ok(outcome) => outcome,
+            Err(error) => {
+                self.inner.emit(SessionEvent::StorageError {
+                    info_hash: self.inner.info_hash(),
+                    error: error.to_string(),
+                });
+                return Err(error);
+            }
+        };
+        // In endgame the same block was requested from other peers, their copy isn't needed anymore
+        if endgame {
+            self.inner
+                .broadcast(PeerCommand::Cancel(block), Some(self.id));
+        }
+
+        match outcome {
+            BlockOutcome::Verified(index) => {
+                self.inner.broadcast(PeerCommand::Have(index), None);
+                let info_hash = self.inner.info_hash();
+                self.inner
+                    .emit(SessionEvent::PieceFinished { info_hash, index });
+
+                let (files, complete) = {
+                    let download = torrent.download().await;
+                    (
+                        download.completed_files(index),
+                        download.picker().is_complete(),
+                    )
+                };
+                for file in files {
+                    self.inner
+                        .emit(SessionEvent::FileCompleted { info_hash, file });
+                }
+                if complete {
+                    self.inner.finish();
+                }
+            }
+            BlockOutcome::HashFailed {
+                index,
+                contributors,
+            } => {
+                debug!("Piece {} failed the hash check", index);
+                self.inner.emit(SessionEvent::HashFailed {
+                    info_hash: self.inner.info_hash(),
+                    index,
+                    peers: contributors.clone(),
+                });
+                let download = torrent.download().await;
+                let banned: Vec<SocketAddr> = contributors
+                    .into_iter()
+                    .filter(|peer| download.hash_failures(peer) >= MAX_HASH_FAILURES)
+                    .collect();
+                drop(download);
+                banned.into_iter().for_each(|peer| self.inner.ban(peer));
+            }
+            BlockOutcome::Stored | BlockOutcome::Duplicate | BlockOutcome::Invalid => {}
+        }
+        Ok(())
+    }
+
+    async fn on_metadata(&mut self, payload: Bytes) -> Result<()> {
+        let message = match MetadataMessage::from_bytes(&payload) {
+            Ok(message) => message,
+            Err(error) => {
+                debug!("Invalid metadata message from {}: {}", self.addr, error);
+                return Ok(());
+            }
+        };
+
+        match message {
+            MetadataMessage::Request(piece) => {
+                let Some(id) = self.connection.extension("ut_metadata") else {
+                    return Ok(());
+                };
+                let reply = match self.inner.metadata() {
+                    Some(metadata) => match metadata::piece(metadata, piece) {
+                        Some(data) => MetadataMessage::Data {
+                            piece,
+                            total_size: metadata.len() as u32,
+                            data,
+                        },
+                        None => MetadataMessage::Reject(piece),
+                    },
+                    None => MetadataMessage::Reject(piece),
+                };
+                let payload = reply.to_bytes()?;
+                self.connection
+                    .send(Message::Extended { id, payload })
+                    .await?;
+            }
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => {
+                if self.metadata_request != Some(piece) {
+                    return Ok(());
+                }
+                self.metadata_request = None;
+                let metadata = self
+                    .inner
+                    .metadata_download()
+                    .lock()
+                    .expect("Poisoned lock")
+                    .received(piece, total_size, data);
+                if let Some(metadata) = metadata {
+                    self.inner.metadata_received(metadata);
+                }
+            }
+            MetadataMessage::Reject(piece) => {
+                if self.metadata_request == Some(piece) {
+                    self.metadata_request = None;
+                    self.metadata_rejected = true;
+                    let mut download = self
+                        .inner
+                        .metadata_download()
+                        .lock()
+                        .expect("Poisoned lock");
+                    download.abort(piece);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Asks the peer for a piece of the metadata, if it has it and nobody else was asked for it
+    async fn request_metadata(&mut self) -> Result<()> {
+        if self.metadata_request.is_some()
+            || self.metadata_rejected
+            || self.inner.metadata().is_some()
+        {
+            return Ok(());
+        }
+        let (Some(id), Some(size)) = (
+            self.connection.extension("ut_metadata"),
+            self.connection.metadata_size(),
+        ) else {
+            return Ok(());
+        };
+
+        let piece = self
+            .inner
+            .metadata_download()
+            .lock()
+            .expect("Poisoned lock")
+            .pick(size);
+        if let Some(piece) = piece {
+            self.metadata_request = Some(piece);
+            let payload = MetadataMessage::Request(piece).to_bytes()?;
+            self.connection
+                .send(Message::Extended { id, payload })
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Fills the request pipeline with blocks picked for this peer
+    async fn request_blocks(&mut self, torrent: &Torrent) -> Result<()> {
+        let slots = self.connection.request_slots();
+        if slots == 0 {
+            return Ok(());
+        }
+
+        let blocks = {
+            let mut download = torrent.download().await;
+            let picker = download.picker_mut();
+            let mut blocks = picker.pick(self.connection.bitfield(), slots);
+            if blocks.len() < slots && picker.is_endgame() {
+                blocks.extend(picker.pick_endgame(
+                    self.connection.bitfield(),
+                    slots - blocks.len(),
+                    |block| self.connection.pipeline().is_requested(block),
+                ));
+            }
+            blocks
+        };
+        for block in blocks {
+            self.connection.request(block).await?;
+        }
+        Ok(())
+    }
+
+    /// Tells the peer whether it has anything we still want
+    async fn update_interest(&mut self) -> Result<()> {
+        let Some(torrent) = &self.torrent else {
+            return Ok(());
+        };
+        let interesting = {
+            let download = torrent.download().await;
+            let picker = download.picker();
+            self.connection.bitfield().iter_ones().any(|index| {
+                let index = index as u32;
+                !picker.has_piece(index) && picker.priority(index) > 0
+            })
+        };
+
+        if interesting {
+            self.connection.interested().await
+        } else {
+            self.connection.not_interested().await
+        }
+    }
+
+    /// Makes blocks that won't arrive from this peer available to be picked again
+    async fn abort(&self, blocks: &[Block]) {
+        if let Some(torrent) = &self.torrent {
+            let mut download = torrent.download().await;
+            blocks
+                .iter()
+                .for_each(|block| download.picker_mut().abort_block(block));
+        }
+    }
+
+    async fn disconnected(&mut self) {
+        if self.super_seeding {
+            self.inner
+                .super_seed()
+                .lock()
+                .expect("Poisoned lock")
+                .peer_disconnected(self.addr);
+        }
+        if let Some(piece) = self.metadata_request.take() {
+            self.inner
+                .metadata_download()
+                .lock()
+                .expect("Poisoned lock")
+                .abort(piece);
+        }
+        if let Some(torrent) = &self.torrent {
+            let mut download = torrent.download().await;
+            let picker = download.picker_mut();
+            picker.peer_disconnected(self.connection.bitfield());
+            self.connection
+                .pipeline()
+                .outstanding()
+                .for_each(|block| picker.abort_block(block));
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use std::{net::SocketAddr, time::Duration};
+
+use crate::stats::TransferSnapshot;
+
+/// What a torrent is busy with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TorrentState {
+    /// Waiting for the peers to send the info dictionary of a magnet link
+    DownloadingMetadata,
+    /// Checking the data that is already on disk
+    Checking,
+    Downloading,
+    /// Every wanted piece was downloaded but some files were skipped, so we're a partial seed
+    Finished,
+    /// Every piece was downloaded
+    Seeding,
+    Paused,
+}
+
+/// A snapshot of a torrent, see [`TorrentHandle::status`](super::TorrentHandle::status)
+#[derive(Debug, Clone)]
+pub struct TorrentStatus {
+    pub info_hash: [u8; 20],
+    pub name: Option<String>,
+    pub state: TorrentState,
+    /// Share of the wanted bytes that were downloaded, from 0 to 1
+    pub progress: f64,
+    /// Bytes of the pieces we want, or already have
+    pub total_wanted: u64,
+    pub total_wanted_done: u64,
+    /// Traffic of the connections since the torrent was added
+    pub transfer: TransferSnapshot,
+    /// Verified bytes downloaded across sessions, as kept in the resume data
+    pub all_time_download: u64,
+    pub all_time_upload: u64,
+    /// Bytes of pieces that failed the hash check
+    pub failed_bytes: u64,
+    /// Bytes received for blocks we already had
+    pub wasted_bytes: u64,
+    /// Uploaded over downloaded, against the data we have when nothing was downloaded
+    pub ratio: f64,
+    /// Connected peers that have every piece
+    pub seeds: usize,
+    /// Connected peers, seeds included
+    pub peers: usize,
+    /// Seeds in the swarm according to the tracker
+    pub swarm_seeds: Option<u64>,
+    /// Peers in the swarm that are still downloading according to the tracker
+    pub swarm_peers: Option<u64>,
+    /// Time left at the current download rate, `None` if nothing is coming in
+    pub eta: Option<Duration>,
+    /// How many copies of the torrent the connected peers have between them
+    pub distributed_copies: f64,
+    /// Bytes of each file that were downloaded
+    pub file_progress: Vec<u64>,
+}
+
+/// What we know about the connection with a peer
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PeerFlags {
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+    /// The peer has every piece
+    pub seed: bool,
+    /// The peer won't download anything, it's a seed or has every piece it wants
+    pub upload_only: bool,
+    pub encrypted: bool,
+    pub fast_extension: bool,
+    pub extension_protocol: bool,
+}
+
+/// A snapshot of a connected peer, see [`TorrentHandle::peers`](super::TorrentHandle::peers)
+#[derive(Debug, Clone)]
+pub struct PeerStatus {
+    pub addr: SocketAddr,
+    pub peer_id: [u8; 20],
+    /// Name and version of the client, from the extended handshake or guessed from the peer id
+    pub client: Option<String>,
+    pub flags: PeerFlags,
+    /// Share of the pieces the peer has, from 0 to 1
+    pub progress: f64,
+    pub transfer: TransferSnapshot,
+    /// Requests sent to the peer that weren't answered yet
+    pub download_queue: usize,
+    /// How many requests we try to keep in flight with the peer
+    pub download_queue_limit: usize,
+    /// Requests from the peer waiting to be served
+    pub upload_queue: usize,
+}
+
+/// A snapshot of a whole session, see [`Session::status`](super::Session::status)
+#[derive(Debug, Clone)]
+pub struct SessionStatus {
+    pub torrents: usize,
+    /// Connected peers across every torrent
+    pub peers: usize,
+    pub transfer: TransferSnapshot,
+}
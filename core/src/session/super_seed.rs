@@ -0,0 +1,100 @@
+use bitvec::{order::Msb0, slice::BitSlice};
+use std::{collections::HashMap, net::SocketAddr};
+
+use crate::picker::PiecePicker;
+
+/// Decides which pieces a super seed reveals to each peer, see [BEP 16](https://www.bittorrent.org/beps/bep_0016.html).
+///
+/// Every peer is shown a single piece, and another one only once that piece was seen at some
+/// other peer, so what we upload goes on to the rest of the swarm instead of being downloaded twice.
+#[derive(Debug, Default)]
+pub(super) struct SuperSeed {
+    /// How many peers each piece was revealed to
+    revealed: HashMap<u32, u32>,
+    /// The piece each peer was last shown, until it spreads
+    assigned: HashMap<SocketAddr, u32>,
+}
+
+impl SuperSeed {
+    /// Picks the piece to reveal to a peer: the rarest one it doesn't have, shown to the fewest peers.
+    ///
+    /// `None` while the piece the peer was shown before hasn't spread, or if it has every piece.
+    pub fn reveal(
+        &mut self,
+        peer: SocketAddr,
+        peer_has: &BitSlice<u8, Msb0>,
+        picker: &PiecePicker,
+    ) -> Option<u32> {
+        if self.assigned.contains_key(&peer) {
+            return None;
+        }
+        let index = (0..picker.piece_count() as u32)
+            .filter(|index| !peer_has.get(*index as usize).is_some_and(|has| *has))
+            .min_by_key(|index| {
+                (
+                    picker.availability(*index),
+                    self.revealed.get(index).copied().unwrap_or_default(),
+                )
+            })?;
+
+        *self.revealed.entry(index).or_default() += 1;
+        self.assigned.insert(peer, index);
+        Some(index)
+    }
+
+    /// A peer announced a piece, returns the peers it was revealed to, which can be shown another one
+    pub fn peer_have(&mut self, peer: SocketAddr, index: u32) -> Vec<SocketAddr> {
+        let spread: Vec<SocketAddr> = self
+            .assigned
+            .iter()
+            .filter(|(addr, piece)| **addr != peer && **piece == index)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in &spread {
+            self.assigned.remove(addr);
+        }
+        spread
+    }
+
+    pub fn peer_disconnected(&mut self, peer: SocketAddr) {
+        self.assigned.remove(&peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::BLOCK_SIZE;
+    use bitvec::vec::BitVec;
+
+    #[test]
+    fn reveals_rare_pieces_once_they_spread() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE, BLOCK_SIZE as u64 * 4);
+        let a = SocketAddr::from(([10, 0, 0, 1], 1));
+        let b = SocketAddr::from(([10, 0, 0, 2], 1));
+        let c = SocketAddr::from(([10, 0, 0, 3], 1));
+        let none = BitVec::<u8, Msb0>::repeat(false, 4);
+        let mut super_seed = SuperSeed::default();
+
+        // Piece 0 is already out there
+        let mut has_first = none.clone();
+        has_first.set(0, true);
+        picker.peer_bitfield(&has_first);
+
+        assert_eq!(super_seed.reveal(a, &none, &picker), Some(1));
+        assert_eq!(super_seed.reveal(a, &none, &picker), None);
+        // Pieces that were shown to fewer peers come first
+        assert_eq!(super_seed.reveal(b, &has_first, &picker), Some(2));
+
+        // The peer getting the piece itself doesn't count
+        assert!(super_seed.peer_have(a, 1).is_empty());
+        picker.peer_have(1);
+        assert_eq!(super_seed.peer_have(c, 1), vec![a]);
+        assert_eq!(super_seed.reveal(a, &none, &picker), Some(3));
+
+        super_seed.peer_disconnected(b);
+        assert!(super_seed.peer_have(c, 2).is_empty());
+        let all = BitVec::<u8, Msb0>::repeat(true, 4);
+        assert_eq!(super_seed.reveal(c, &all, &picker), None);
+    }
+}
@@ -0,0 +1,201 @@
+//! Byte counters for the connections, shared by every level that reports them: peer, torrent and session
+
+use std::sync::Mutex;
+use tokio::time::Instant;
+
+use crate::Message;
+
+/// Seconds the rate is averaged over
+const WINDOW: usize = 5;
+
+/// Counts bytes as they go and how fast they went over the last few seconds
+#[derive(Debug, Clone)]
+pub struct Rate {
+    total: u64,
+    start: Instant,
+    /// Seconds since `start` the current bucket is for
+    second: u64,
+    /// Bytes of each of the last seconds, used as a ring
+    buckets: [u64; WINDOW],
+}
+
+impl Rate {
+    pub fn new() -> Self {
+        Self::starting_at(Instant::now())
+    }
+
+    fn starting_at(start: Instant) -> Self {
+        Self {
+            total: 0,
+            start,
+            second: 0,
+            buckets: [0; WINDOW],
+        }
+    }
+
+    pub const fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn add(&mut self, bytes: u64) {
+        self.add_at(bytes, Instant::now());
+    }
+
+    /// Bytes per second over the last few seconds
+    pub fn rate(&mut self) -> u64 {
+        self.rate_at(Instant::now())
+    }
+
+    fn add_at(&mut self, bytes: u64, now: Instant) {
+        self.advance(now);
+        self.total += bytes;
+        self.buckets[self.second as usize % WINDOW] += bytes;
+    }
+
+    fn rate_at(&mut self, now: Instant) -> u64 {
+        self.advance(now);
+        let elapsed = now.saturating_duration_since(self.start).as_secs_f64();
+        // The current second is only partly over, the ones before it are whole
+        let window = elapsed.min((WINDOW - 1) as f64 + elapsed.fract()).max(1.0);
+        (self.buckets.iter().sum::<u64>() as f64 / window) as u64
+    }
+
+    /// Clears the buckets of the seconds that went by since the last call
+    fn advance(&mut self, now: Instant) {
+        let second = now.saturating_duration_since(self.start).as_secs();
+        if second - self.second >= WINDOW as u64 {
+            self.buckets = [0; WINDOW];
+        } else {
+            for second in self.second + 1..=second {
+                self.buckets[second as usize % WINDOW] = 0;
+            }
+        }
+        self.second = second;
+    }
+}
+
+impl Default for Rate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Bytes transferred in one direction
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Transfer {
+    pub total: u64,
+    /// Bytes per second
+    pub rate: u64,
+}
+
+/// Everything sent and received at a point in time, piece data is payload and the rest is protocol
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TransferSnapshot {
+    pub payload_download: Transfer,
+    pub payload_upload: Transfer,
+    pub protocol_download: Transfer,
+    pub protocol_upload: Transfer,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    payload_download: Rate,
+    payload_upload: Rate,
+    protocol_download: Rate,
+    protocol_upload: Rate,
+}
+
+/// Counts the messages that go through connections, see [`Wire::add_stats`](crate::Wire::add_stats)
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    counters: Mutex<Counters>,
+}
+
+impl TransferStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn received(&self, message: &Message) {
+        let (payload, protocol) = split(message);
+        let mut counters = self.counters.lock().expect("Poisoned lock");
+        counters.payload_download.add(payload);
+        counters.protocol_download.add(protocol);
+    }
+
+    pub fn sent(&self, message: &Message) {
+        let (payload, protocol) = split(message);
+        let mut counters = self.counters.lock().expect("Poisoned lock");
+        counters.payload_upload.add(payload);
+        counters.protocol_upload.add(protocol);
+    }
+
+    pub fn snapshot(&self) -> TransferSnapshot {
+        let mut counters = self.counters.lock().expect("Poisoned lock");
+        let transfer = |rate: &mut Rate| Transfer {
+            total: rate.total(),
+            rate: rate.rate(),
+        };
+        TransferSnapshot {
+            payload_download: transfer(&mut counters.payload_download),
+            payload_upload: transfer(&mut counters.payload_upload),
+            protocol_download: transfer(&mut counters.protocol_download),
+            protocol_upload: transfer(&mut counters.protocol_upload),
+        }
+    }
+}
+
+/// Splits the size of a message between payload and protocol bytes
+pub(crate) fn split(message: &Message) -> (u64, u64) {
+    let length = message.len() as u64;
+    match message {
+        Message::Piece(piece) => {
+            let payload = piece.block().len() as u64;
+            (payload, length - payload)
+        }
+        _ => (0, length),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use std::time::Duration;
+
+    #[test]
+    fn rate_over_the_last_seconds() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let mut rate = Rate::starting_at(start);
+
+        // Nothing is extrapolated from the first fraction of a second
+        rate.add_at(500, at(200));
+        assert_eq!(rate.rate_at(at(500)), 500);
+
+        for second in 1..=4 {
+            rate.add_at(1000, at(second * 1000));
+        }
+        assert_eq!(rate.rate_at(at(4000)), 4500 / 4);
+        assert_eq!(rate.rate_at(at(4500)), 4500 * 2 / 9);
+
+        // The first second falls out of the window
+        assert_eq!(rate.rate_at(at(5000)), 4000 / 4);
+        assert_eq!(rate.rate_at(at(20000)), 0);
+        assert_eq!(rate.total(), 4500);
+    }
+
+    #[test]
+    fn payload_and_protocol() {
+        let stats = TransferStats::new();
+        stats.received(&Message::piece(0, 0, Bytes::from_static(&[0; 100])));
+        stats.received(&Message::have(3));
+        stats.sent(&Message::request(0, 0, 100));
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.payload_download.total, 100);
+        assert_eq!(snapshot.protocol_download.total, 13 + 9);
+        assert_eq!(snapshot.payload_upload.total, 0);
+        assert_eq!(snapshot.protocol_upload.total, 17);
+    }
+}
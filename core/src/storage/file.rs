@@ -0,0 +1,657 @@
+use bytes::{Bytes, BytesMut};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::UNIX_EPOCH,
+};
+
+use super::{Allocation, FileMetadata, FileSlice, Layout, Storage, StorageError};
+
+/// Zeros are written in chunks of this size for [`Allocation::Full`]
+const ZERO_CHUNK: usize = 1024 * 1024;
+
+/// Files kept open by a storage unless changed with [`FileStorage::set_max_open_files`]
+pub const DEFAULT_MAX_OPEN_FILES: usize = 64;
+
+#[derive(Debug)]
+struct Handle {
+    file: Arc<File>,
+    writable: bool,
+    /// Value of [`Handles::clock`] when it was last used
+    last_used: u64,
+}
+
+/// Open files, the least recently used one is closed when there are too many
+#[derive(Debug, Default)]
+struct Handles {
+    open: HashMap<usize, Handle>,
+    /// Bumped every time a file is used
+    clock: u64,
+}
+
+/// Stores the torrent as regular files inside a download directory.
+///
+/// Pieces that overlap a skipped file keep the bytes of that file in a part file per piece, inside a
+/// hidden directory next to the download, so skipped files are never created.
+#[derive(Debug)]
+pub struct FileStorage {
+    root: RwLock<PathBuf>,
+    layout: Layout,
+    allocation: Allocation,
+    handles: Mutex<Handles>,
+    max_open_files: usize,
+    skipped: RwLock<HashSet<usize>>,
+}
+
+impl FileStorage {
+    pub fn new<P: Into<PathBuf>>(root: P, layout: Layout) -> Self {
+        Self::with_allocation(root, layout, Allocation::Sparse)
+    }
+
+    /// Creates a storage that reserves the space of each file when it's first written
+    pub fn with_allocation<P: Into<PathBuf>>(
+        root: P,
+        layout: Layout,
+        allocation: Allocation,
+    ) -> Self {
+        Self {
+            root: RwLock::new(root.into()),
+            layout,
+            allocation,
+            handles: Mutex::new(Handles::default()),
+            max_open_files: DEFAULT_MAX_OPEN_FILES,
+            skipped: RwLock::new(HashSet::new()),
+        }
+    }
+
+    /// Sets how many files are kept open at once, at least one
+    pub fn set_max_open_files(&mut self, max: usize) {
+        self.max_open_files = max.max(1);
+    }
+
+    pub const fn allocation(&self) -> Allocation {
+        self.allocation
+    }
+
+    /// The download directory
+    pub fn root(&self) -> PathBuf {
+        self.root.read().expect("Poisoned lock").clone()
+    }
+
+    /// Full path of a file
+    pub fn path(&self, file: usize) -> PathBuf {
+        self.root().join(&self.layout.files()[file].path)
+    }
+
+    /// Directory holding the part files, named after the torrent
+    pub fn parts_dir(&self) -> PathBuf {
+        let name = self
+            .layout
+            .files()
+            .first()
+            .and_then(|file| file.path.components().next())
+            .map_or_else(Default::default, |name| {
+                name.as_os_str().to_string_lossy().into_owned()
+            });
+        self.root().join(format!(".{}.parts", name))
+    }
+
+    fn part_path(&self, index: u32) -> PathBuf {
+        self.parts_dir().join(index.to_string())
+    }
+
+    pub fn is_skipped(&self, file: usize) -> bool {
+        self.skipped.read().expect("Poisoned lock").contains(&file)
+    }
+
+    /// Reads the part of a piece that lives in `slice`, `begin` being where it starts in the piece
+    pub(super) fn read_slice(
+        &self,
+        index: u32,
+        begin: u32,
+        slice: &FileSlice,
+        buf: &mut [u8],
+    ) -> io::Result<()> {
+        if self.is_skipped(slice.file) {
+            match File::open(self.part_path(index)) {
+                Ok(part) => return read_exact_at(&part, buf, begin as u64),
+                // Written before the file was skipped
+                Err(error) if error.kind() == ErrorKind::NotFound => {}
+                Err(error) => return Err(error),
+            }
+        }
+
+        read_exact_at(&*self.handle(slice.file, false)?, buf, slice.offset)
+    }
+
+    /// Writes the part of a piece that lives in `slice`, `begin` being where it starts in the piece
+    pub(super) fn write_slice(
+        &self,
+        index: u32,
+        begin: u32,
+        slice: &FileSlice,
+        data: &[u8],
+    ) -> io::Result<()> {
+        if self.is_skipped(slice.file) {
+            fs::create_dir_all(self.parts_dir())?;
+            let part = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(self.part_path(index))?;
+            return write_all_at(&part, data, begin as u64);
+        }
+
+        write_all_at(&*self.handle(slice.file, true)?, data, slice.offset)
+    }
+
+    /// Moves the bytes of a file that was unskipped out of the part files
+    fn restore_parts(&self, file: usize) -> Result<(), StorageError> {
+        let pieces = self.layout.file_pieces(file);
+        let edges = [pieces.start, pieces.end.saturating_sub(1)];
+
+        for index in edges.into_iter().filter(|index| pieces.contains(index)) {
+            let path = self.part_path(index);
+            let part = match File::open(&path) {
+                Ok(part) => part,
+                Err(error) if error.kind() == ErrorKind::NotFound => continue,
+                Err(error) => return Err(error.into()),
+            };
+
+            let mut begin = 0;
+            for slice in self
+                .layout
+                .slices(index, 0, self.layout.piece_length(index))?
+            {
+                if slice.file == file {
+                    let mut data = vec![0; slice.length];
+                    // Nothing was written there yet
+                    if read_exact_at(&part, &mut data, begin as u64).is_ok() {
+                        write_all_at(&*self.handle(file, true)?, &data, slice.offset)?;
+                    }
+                }
+                begin += slice.length as u32;
+            }
+
+            let still_needed = self
+                .layout
+                .slices(index, 0, self.layout.piece_length(index))?
+                .iter()
+                .any(|slice| self.is_skipped(slice.file));
+            if !still_needed {
+                fs::remove_file(path)?;
+            }
+        }
+
+        let _ = fs::remove_dir(self.parts_dir());
+        Ok(())
+    }
+
+    pub(super) fn handle(&self, file: usize, write: bool) -> io::Result<Arc<File>> {
+        let mut handles = self.handles.lock().expect("Poisoned lock");
+        handles.clock += 1;
+        let clock = handles.clock;
+        if let Some(handle) = handles.open.get_mut(&file) {
+            if handle.writable || !write {
+                handle.last_used = clock;
+                return Ok(handle.file.clone());
+            }
+        }
+
+        let path = self.path(file);
+        let handle = if write {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let handle = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+            allocate(&handle, self.layout.files()[file].length, self.allocation)?;
+            handle
+        } else {
+            File::open(&path)?
+        };
+
+        // Whoever still uses a closed file keeps it open until they're done with it
+        while handles.open.len() >= self.max_open_files && !handles.open.contains_key(&file) {
+            let Some(oldest) = handles
+                .open
+                .iter()
+                .min_by_key(|(_, handle)| handle.last_used)
+                .map(|(file, _)| *file)
+            else {
+                break;
+            };
+            let closed = handles.open.remove(&oldest).expect("File is open");
+            // What was written has to be persisted, flush only sees the open files
+            if closed.writable {
+                closed.file.sync_data()?;
+            }
+        }
+
+        let handle = Arc::new(handle);
+        handles.open.insert(
+            file,
+            Handle {
+                file: handle.clone(),
+                writable: write,
+                last_used: clock,
+            },
+        );
+        Ok(handle)
+    }
+
+    fn close_all(&self) {
+        self.handles.lock().expect("Poisoned lock").open.clear();
+    }
+}
+
+impl Storage for FileStorage {
+    fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Bytes, StorageError> {
+        let mut data = BytesMut::zeroed(length as usize);
+        let mut position = 0;
+
+        for slice in self.layout.slices(index, begin, length)? {
+            self.read_slice(
+                index,
+                begin + position as u32,
+                &slice,
+                &mut data[position..position + slice.length],
+            )?;
+            position += slice.length;
+        }
+
+        Ok(data.freeze())
+    }
+
+    fn write_block(&self, index: u32, begin: u32, data: &[u8]) -> Result<(), StorageError> {
+        let mut position = 0;
+
+        for slice in self.layout.slices(index, begin, data.len() as u32)? {
+            self.write_slice(
+                index,
+                begin + position as u32,
+                &slice,
+                &data[position..position + slice.length],
+            )?;
+            position += slice.length;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        let handles = self.handles.lock().expect("Poisoned lock");
+        for handle in handles.open.values() {
+            if handle.writable {
+                handle.file.sync_data()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn move_to(&self, root: &Path) -> Result<(), StorageError> {
+        self.close_all();
+        let parts = self.parts_dir();
+        let mut current = self.root.write().expect("Poisoned lock");
+
+        if parts.exists() {
+            fs::create_dir_all(root)?;
+            let to = root.join(parts.file_name().expect("Parts directory has a name"));
+            if fs::rename(&parts, &to).is_err() {
+                fs::create_dir_all(&to)?;
+                for entry in fs::read_dir(&parts)? {
+                    let entry = entry?;
+                    fs::copy(entry.path(), to.join(entry.file_name()))?;
+                }
+                fs::remove_dir_all(&parts)?;
+            }
+        }
+
+        for file in self.layout.files() {
+            let from = current.join(&file.path);
+            let to = root.join(&file.path);
+
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            match fs::rename(&from, &to) {
+                Ok(()) => {}
+                // Nothing was written to this file yet
+                Err(error) if error.kind() == ErrorKind::NotFound => {}
+                // Renaming doesn't work across file systems
+                Err(_) => {
+                    fs::copy(&from, &to)?;
+                    fs::remove_file(&from)?;
+                }
+            }
+        }
+
+        remove_empty_dirs(&current, &self.layout);
+        *current = root.to_path_buf();
+
+        Ok(())
+    }
+
+    fn delete(&self) -> Result<(), StorageError> {
+        self.close_all();
+        let root = self.root();
+
+        for file in self.layout.files() {
+            match fs::remove_file(root.join(&file.path)) {
+                Err(error) if error.kind() != ErrorKind::NotFound => return Err(error.into()),
+                _ => {}
+            }
+        }
+
+        match fs::remove_dir_all(self.parts_dir()) {
+            Err(error) if error.kind() != ErrorKind::NotFound => return Err(error.into()),
+            _ => {}
+        }
+
+        remove_empty_dirs(&root, &self.layout);
+        Ok(())
+    }
+
+    fn set_skipped(&self, file: usize, skipped: bool) -> Result<(), StorageError> {
+        let changed = {
+            let mut skipped_files = self.skipped.write().expect("Poisoned lock");
+            if skipped {
+                skipped_files.insert(file)
+            } else {
+                skipped_files.remove(&file)
+            }
+        };
+
+        if changed && !skipped {
+            self.restore_parts(file)?;
+        }
+        Ok(())
+    }
+
+    fn file_metadata(&self, file: usize) -> Result<Option<FileMetadata>, StorageError> {
+        let metadata = match fs::metadata(self.path(file)) {
+            Ok(metadata) => metadata,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |mtime| mtime.as_secs());
+
+        Ok(Some(FileMetadata {
+            size: metadata.len(),
+            mtime,
+        }))
+    }
+}
+
+/// Reserves the space of a file, keeping whatever it already contains
+fn allocate(file: &File, length: u64, allocation: Allocation) -> io::Result<()> {
+    let current = file.metadata()?.len();
+    if current >= length {
+        return Ok(());
+    }
+
+    match allocation {
+        Allocation::Sparse => Ok(()),
+        Allocation::Preallocate => preallocate(file, length),
+        Allocation::Full => {
+            let zeros = vec![0; ZERO_CHUNK];
+            let mut offset = current;
+            while offset < length {
+                let chunk = (length - offset).min(ZERO_CHUNK as u64) as usize;
+                write_all_at(file, &zeros[..chunk], offset)?;
+                offset += chunk as u64;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, length: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: the descriptor is valid for as long as `file` is borrowed
+    let result = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, length as libc::off_t) };
+    if result == 0 {
+        return Ok(());
+    }
+
+    match io::Error::last_os_error() {
+        // Not every file system supports it, a sparse file of the right size is the next best thing
+        error if error.raw_os_error() == Some(libc::EOPNOTSUPP) => file.set_len(length),
+        error => Err(error),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn preallocate(file: &File, length: u64) -> io::Result<()> {
+    file.set_len(length)
+}
+
+/// Removes the directories created for the files of the torrent, as long as they are empty
+fn remove_empty_dirs(root: &Path, layout: &Layout) {
+    let mut dirs: Vec<&Path> = layout
+        .files()
+        .iter()
+        .flat_map(|file| file.path.ancestors().skip(1))
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .collect();
+    // Deepest first so parents are empty by the time we get to them
+    dirs.sort_unstable_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+    dirs.dedup();
+
+    for dir in dirs {
+        let _ = fs::remove_dir(root.join(dir));
+    }
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(ErrorKind::UnexpectedEof.into()),
+            read => {
+                buf = &mut buf[read..];
+                offset += read as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset)? {
+            0 => return Err(ErrorKind::WriteZero.into()),
+            written => {
+                buf = &buf[written..];
+                offset += written as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha1::{Digest, Sha1};
+    use std::env::temp_dir;
+
+    fn layout() -> Layout {
+        Layout::from_files(
+            vec![
+                ("torrent/a".into(), 10),
+                ("torrent/dir/b".into(), 4),
+                ("torrent/dir/nested/c".into(), 30),
+            ],
+            16,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn read_write_across_files() {
+        let root = temp_dir().join(format!("leech-storage-{}", rand::random::<u64>()));
+        let storage = FileStorage::new(&root, layout());
+        let data: Vec<u8> = (0..44).collect();
+
+        // Written out of order, the middle piece touches all three files
+        storage.write_block(2, 0, &data[32..]).unwrap();
+        storage.write_block(0, 0, &data[..16]).unwrap();
+        storage.write_block(1, 0, &data[16..32]).unwrap();
+
+        assert_eq!(fs::read(root.join("torrent/a")).unwrap(), &data[..10]);
+        assert_eq!(fs::read(root.join("torrent/dir/b")).unwrap(), &data[10..14]);
+        assert_eq!(
+            fs::read(root.join("torrent/dir/nested/c")).unwrap(),
+            &data[14..]
+        );
+
+        assert_eq!(storage.read_block(0, 8, 8).unwrap(), &data[8..16]);
+        let expected: [u8; 20] = Sha1::digest(&data[32..]).into();
+        assert_eq!(storage.hash_piece(2).unwrap(), expected);
+        storage.flush().unwrap();
+
+        let moved = root.join("moved");
+        storage.move_to(&moved).unwrap();
+        assert!(!root.join("torrent").exists());
+        assert_eq!(storage.read_block(1, 0, 16).unwrap(), &data[16..32]);
+
+        storage.delete().unwrap();
+        assert!(!moved.join("torrent").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn bounded_open_files() {
+        let root = temp_dir().join(format!("leech-storage-{}", rand::random::<u64>()));
+        let mut storage = FileStorage::new(&root, layout());
+        storage.set_max_open_files(2);
+        let data: Vec<u8> = (0..44).collect();
+
+        // The first piece touches all three files
+        storage.write_block(0, 0, &data[..16]).unwrap();
+        storage.write_block(2, 0, &data[32..]).unwrap();
+        assert_eq!(storage.handles.lock().unwrap().open.len(), 2);
+
+        // Only a is read, b was used least recently
+        assert_eq!(storage.read_block(0, 0, 8).unwrap(), &data[..8]);
+        let handles = storage.handles.lock().unwrap();
+        assert!(handles.open.contains_key(&0) && !handles.open.contains_key(&1));
+        drop(handles);
+
+        storage.write_block(1, 0, &data[16..32]).unwrap();
+        assert_eq!(storage.read_block(0, 0, 16).unwrap(), &data[..16]);
+        assert_eq!(storage.read_block(1, 0, 16).unwrap(), &data[16..32]);
+        storage.delete().unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn skipped_files_use_part_files() {
+        let root = temp_dir().join(format!("leech-storage-{}", rand::random::<u64>()));
+        let storage = FileStorage::new(&root, layout());
+        let data: Vec<u8> = (0..44).collect();
+
+        // The first piece touches all three files
+        storage.set_skipped(1, true).unwrap();
+        storage.write_block(0, 0, &data[..16]).unwrap();
+        assert!(!root.join("torrent/dir/b").exists());
+        assert!(storage.parts_dir().join("0").exists());
+        assert_eq!(storage.read_block(0, 0, 16).unwrap(), &data[..16]);
+
+        storage.set_skipped(1, false).unwrap();
+        assert_eq!(fs::read(root.join("torrent/dir/b")).unwrap(), &data[10..14]);
+        assert!(!storage.parts_dir().exists());
+        assert_eq!(storage.read_block(0, 0, 16).unwrap(), &data[..16]);
+
+        storage.set_skipped(2, true).unwrap();
+        storage.write_block(1, 0, &data[16..32]).unwrap();
+        assert!(storage.parts_dir().join("1").exists());
+        storage.delete().unwrap();
+        assert!(!root.join("torrent").exists());
+        assert!(!storage.parts_dir().exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn allocation_modes() {
+        use std::os::unix::fs::MetadataExt;
+
+        let root = temp_dir().join(format!("leech-storage-{}", rand::random::<u64>()));
+        let layout = Layout::from_files(vec![("file".into(), 1 << 20)], 1 << 16).unwrap();
+
+        for allocation in [
+            Allocation::Sparse,
+            Allocation::Preallocate,
+            Allocation::Full,
+        ] {
+            let storage = FileStorage::with_allocation(&root, layout.clone(), allocation);
+            storage.write_block(0, 0, &[1; 16]).unwrap();
+
+            let metadata = fs::metadata(storage.path(0)).unwrap();
+            match allocation {
+                Allocation::Sparse => assert_eq!(metadata.len(), 16),
+                _ => assert_eq!(metadata.len(), 1 << 20),
+            }
+            if allocation == Allocation::Full {
+                assert!(metadata.blocks() * 512 >= 1 << 20);
+            }
+            // Allocating doesn't clobber what was written
+            assert_eq!(storage.read_block(0, 0, 16).unwrap(), &[1; 16][..]);
+
+            storage.delete().unwrap();
+        }
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn missing_data() {
+        let root = temp_dir().join(format!("leech-storage-{}", rand::random::<u64>()));
+        let storage = FileStorage::new(&root, layout());
+
+        assert!(matches!(
+            storage.read_block(0, 0, 16),
+            Err(StorageError::Io(_))
+        ));
+        assert!(matches!(
+            storage.write_block(0, 10, &[0; 10]),
+            Err(StorageError::OutOfBounds { .. })
+        ));
+
+        // Deleting a torrent that was never written is fine
+        storage.delete().unwrap();
+        assert!(!root.exists());
+    }
+}
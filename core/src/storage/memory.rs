@@ -0,0 +1,179 @@
+use bytes::Bytes;
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::{self, ErrorKind},
+    path::Path,
+    sync::Mutex,
+};
+
+use super::{Layout, Storage, StorageError};
+
+/// Keeps the whole torrent in memory, nothing touches the file system.
+///
+/// With a size limit, pieces marked as [consumed][`Self::consume`] are dropped to make room for
+/// new ones, so data can stream through without ever holding all of it.
+#[derive(Debug)]
+pub struct MemoryStorage {
+    layout: Layout,
+    limit: Option<usize>,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    pieces: HashMap<u32, Vec<u8>>,
+    /// Bytes held by `pieces`
+    size: usize,
+    /// Pieces that can be evicted, lowest index first
+    consumed: BTreeSet<u32>,
+    /// Evicted pieces not yet handed to [`Storage::take_evicted`]
+    evicted: Vec<u32>,
+}
+
+impl MemoryStorage {
+    pub fn new(layout: Layout) -> Self {
+        Self {
+            layout,
+            limit: None,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Creates a storage that holds at most `limit` bytes of pieces
+    pub fn with_limit(layout: Layout, limit: usize) -> Self {
+        Self {
+            limit: Some(limit),
+            ..Self::new(layout)
+        }
+    }
+
+    /// Bytes currently held in memory
+    pub fn size(&self) -> usize {
+        self.state.lock().expect("Poisoned lock").size
+    }
+
+    /// Whether a piece is held in memory, it could have been evicted or never written
+    pub fn contains(&self, index: u32) -> bool {
+        let state = self.state.lock().expect("Poisoned lock");
+        state.pieces.contains_key(&index)
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Bytes, StorageError> {
+        self.layout.slices(index, begin, length)?;
+
+        let state = self.state.lock().expect("Poisoned lock");
+        let piece = state.pieces.get(&index).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::NotFound,
+                format!("Piece {} is not in memory", index),
+            )
+        })?;
+
+        Ok(Bytes::copy_from_slice(
+            &piece[begin as usize..(begin + length) as usize],
+        ))
+    }
+
+    fn write_block(&self, index: u32, begin: u32, data: &[u8]) -> Result<(), StorageError> {
+        self.layout.slices(index, begin, data.len() as u32)?;
+
+        let mut state = self.state.lock().expect("Poisoned lock");
+        if !state.pieces.contains_key(&index) {
+            let length = self.layout.piece_length(index) as usize;
+            if let Some(limit) = self.limit {
+                while state.size + length > limit {
+                    let Some(evicted) = state.consumed.pop_first() else {
+                        return Err(StorageError::Full);
+                    };
+                    if let Some(piece) = state.pieces.remove(&evicted) {
+                        state.size -= piece.len();
+                        state.evicted.push(evicted);
+                    }
+                }
+            }
+            state.pieces.insert(index, vec![0; length]);
+            state.size += length;
+        }
+
+        // Rewritten pieces are no longer the ones that were consumed
+        state.consumed.remove(&index);
+        let piece = state
+            .pieces
+            .get_mut(&index)
+            .expect("Piece was just inserted");
+        piece[begin as usize..begin as usize + data.len()].copy_from_slice(data);
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Marks a verified piece as read by whoever needed it, so it can be evicted when space runs out
+    fn consume(&self, index: u32) {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        if state.pieces.contains_key(&index) {
+            state.consumed.insert(index);
+        }
+    }
+
+    fn take_evicted(&self) -> Vec<u32> {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        std::mem::take(&mut state.evicted)
+    }
+
+    fn move_to(&self, _root: &Path) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn delete(&self) -> Result<(), StorageError> {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        *state = State::default();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_consumed_pieces() {
+        let layout = Layout::from_files(vec![("file".into(), 40)], 16).unwrap();
+        let storage = MemoryStorage::with_limit(layout, 32);
+        let data: Vec<u8> = (0..40).collect();
+
+        storage.write_block(0, 0, &data[..16]).unwrap();
+        storage.write_block(1, 8, &data[24..32]).unwrap();
+        storage.write_block(1, 0, &data[16..24]).unwrap();
+        assert_eq!(storage.size(), 32);
+        assert_eq!(storage.read_block(1, 4, 8).unwrap(), &data[20..28]);
+
+        // Nothing can be evicted yet
+        assert!(matches!(
+            storage.write_block(2, 0, &data[32..]),
+            Err(StorageError::Full)
+        ));
+
+        storage.consume(0);
+        storage.write_block(2, 0, &data[32..]).unwrap();
+        assert!(!storage.contains(0));
+        assert_eq!(storage.take_evicted(), vec![0]);
+        assert!(storage.take_evicted().is_empty());
+        assert_eq!(storage.size(), 24);
+        assert!(matches!(
+            storage.read_block(0, 0, 16),
+            Err(StorageError::Io(_))
+        ));
+
+        storage.delete().unwrap();
+        assert_eq!(storage.size(), 0);
+    }
+}
@@ -0,0 +1,259 @@
+use bytes::{Bytes, BytesMut};
+use memmap2::MmapMut;
+use sha1::{Digest, Sha1};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use super::{FileMetadata, FileStorage, Layout, Storage, StorageError};
+
+/// Files bigger than this aren't mapped, so large torrents don't exhaust the address space
+const MAX_MAP_LENGTH: u64 = if cfg!(target_pointer_width = "64") {
+    1 << 40
+} else {
+    1 << 28
+};
+
+/// A mapped file, or `None` if it's accessed with regular I/O instead
+type Map = Option<Arc<RwLock<MmapMut>>>;
+
+/// Stores the torrent as regular files, accessed through memory maps.
+///
+/// Blocks are copied straight into the mapping and pieces are hashed from it without reading them
+/// into a buffer first. Files that are too big or can't be mapped fall back to [`FileStorage`].
+#[derive(Debug)]
+pub struct MmapStorage {
+    files: FileStorage,
+    maps: RwLock<HashMap<usize, Map>>,
+    max_map_length: u64,
+}
+
+impl MmapStorage {
+    pub fn new<P: Into<PathBuf>>(root: P, layout: Layout) -> Self {
+        Self {
+            files: FileStorage::new(root, layout),
+            maps: RwLock::new(HashMap::new()),
+            max_map_length: MAX_MAP_LENGTH,
+        }
+    }
+
+    /// The download directory
+    pub fn root(&self) -> PathBuf {
+        self.files.root()
+    }
+
+    /// Full path of a file
+    pub fn path(&self, file: usize) -> PathBuf {
+        self.files.path(file)
+    }
+
+    /// Maps a file, creating it if `write` is set
+    fn map(&self, file: usize, write: bool) -> Result<Map, StorageError> {
+        // Skipped files go through the part files of the file storage
+        if self.files.is_skipped(file) {
+            return Ok(None);
+        }
+        if let Some(map) = self.maps.read().expect("Poisoned lock").get(&file) {
+            return Ok(map.clone());
+        }
+
+        let length = self.layout().files()[file].length;
+        if length == 0 || length > self.max_map_length {
+            self.maps.write().expect("Poisoned lock").insert(file, None);
+            return Ok(None);
+        }
+
+        if !write {
+            // Missing or partial files are read without mapping so they fail the same way,
+            // instead of being created or reading made up zeros
+            match fs::metadata(self.path(file)) {
+                Ok(metadata) if metadata.len() >= length => {}
+                Ok(_) => return Ok(None),
+                Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(error) => return Err(error.into()),
+            }
+        }
+
+        let handle = self.files.handle(file, true)?;
+        if handle.metadata()?.len() < length {
+            handle.set_len(length)?;
+        }
+
+        // SAFETY: the file is only modified through this mapping while it's open,
+        // every access is synchronized by the lock
+        let map = match unsafe { MmapMut::map_mut(&*handle) } {
+            Ok(map) => Some(Arc::new(RwLock::new(map))),
+            Err(_) => None,
+        };
+        let mut maps = self.maps.write().expect("Poisoned lock");
+        Ok(maps.entry(file).or_insert(map).clone())
+    }
+
+    fn unmap_all(&self) -> Result<(), StorageError> {
+        let mut maps = self.maps.write().expect("Poisoned lock");
+        for map in maps.values().flatten() {
+            map.read().expect("Poisoned lock").flush()?;
+        }
+        maps.clear();
+        Ok(())
+    }
+}
+
+impl Storage for MmapStorage {
+    fn layout(&self) -> &Layout {
+        self.files.layout()
+    }
+
+    fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Bytes, StorageError> {
+        let mut data = BytesMut::with_capacity(length as usize);
+
+        for slice in self.layout().slices(index, begin, length)? {
+            match self.map(slice.file, false)? {
+                Some(map) => {
+                    let map = map.read().expect("Poisoned lock");
+                    let offset = slice.offset as usize;
+                    data.extend_from_slice(&map[offset..offset + slice.length]);
+                }
+                None => {
+                    let start = data.len();
+                    data.resize(start + slice.length, 0);
+                    self.files.read_slice(
+                        index,
+                        begin + start as u32,
+                        &slice,
+                        &mut data[start..],
+                    )?;
+                }
+            }
+        }
+
+        Ok(data.freeze())
+    }
+
+    fn write_block(&self, index: u32, begin: u32, data: &[u8]) -> Result<(), StorageError> {
+        let mut position = 0;
+
+        for slice in self.layout().slices(index, begin, data.len() as u32)? {
+            let chunk = &data[position..position + slice.length];
+            match self.map(slice.file, true)? {
+                Some(map) => {
+                    let mut map = map.write().expect("Poisoned lock");
+                    let offset = slice.offset as usize;
+                    map[offset..offset + slice.length].copy_from_slice(chunk);
+                }
+                None => self
+                    .files
+                    .write_slice(index, begin + position as u32, &slice, chunk)?,
+            }
+            position += slice.length;
+        }
+
+        Ok(())
+    }
+
+    fn hash_piece(&self, index: u32) -> Result<[u8; 20], StorageError> {
+        let length = self.layout().piece_length(index);
+        let mut hasher = Sha1::new();
+        let mut position = 0;
+
+        for slice in self.layout().slices(index, 0, length)? {
+            match self.map(slice.file, false)? {
+                Some(map) => {
+                    let map = map.read().expect("Poisoned lock");
+                    let offset = slice.offset as usize;
+                    hasher.update(&map[offset..offset + slice.length]);
+                }
+                None => {
+                    let mut data = vec![0; slice.length];
+                    self.files.read_slice(index, position, &slice, &mut data)?;
+                    hasher.update(&data);
+                }
+            }
+            position += slice.length as u32;
+        }
+
+        Ok(hasher.finalize().into())
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        for map in self.maps.read().expect("Poisoned lock").values().flatten() {
+            map.read().expect("Poisoned lock").flush()?;
+        }
+        self.files.flush()
+    }
+
+    fn move_to(&self, root: &Path) -> Result<(), StorageError> {
+        self.unmap_all()?;
+        self.files.move_to(root)
+    }
+
+    fn delete(&self) -> Result<(), StorageError> {
+        self.maps.write().expect("Poisoned lock").clear();
+        self.files.delete()
+    }
+
+    fn set_skipped(&self, file: usize, skipped: bool) -> Result<(), StorageError> {
+        // Data moved out of the part files must not be overwritten by a stale mapping
+        self.unmap_all()?;
+        self.files.set_skipped(file, skipped)
+    }
+
+    fn file_metadata(&self, file: usize) -> Result<Option<FileMetadata>, StorageError> {
+        self.files.file_metadata(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    fn layout() -> Layout {
+        Layout::from_files(
+            vec![
+                ("torrent/a".into(), 10),
+                ("torrent/empty".into(), 0),
+                ("torrent/b".into(), 34),
+            ],
+            16,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn mapped_and_fallback_files() {
+        let root = temp_dir().join(format!("leech-mmap-{}", rand::random::<u64>()));
+        let mut storage = MmapStorage::new(&root, layout());
+        // Only the first file is small enough to be mapped
+        storage.max_map_length = 16;
+        let data: Vec<u8> = (0..44).collect();
+
+        assert!(matches!(
+            storage.read_block(0, 0, 16),
+            Err(StorageError::Io(_))
+        ));
+        assert!(!root.exists());
+        storage.write_block(0, 0, &data[..16]).unwrap();
+        storage.write_block(1, 0, &data[16..32]).unwrap();
+        storage.write_block(2, 0, &data[32..]).unwrap();
+        assert!(storage.maps.read().unwrap()[&0].is_some());
+        assert!(storage.maps.read().unwrap()[&2].is_none());
+
+        assert_eq!(storage.read_block(0, 4, 12).unwrap(), &data[4..16]);
+        let expected: [u8; 20] = Sha1::digest(&data[..16]).into();
+        assert_eq!(storage.hash_piece(0).unwrap(), expected);
+        storage.flush().unwrap();
+        assert_eq!(fs::read(root.join("torrent/a")).unwrap(), &data[..10]);
+        assert_eq!(fs::read(root.join("torrent/b")).unwrap(), &data[10..]);
+
+        let moved = root.join("moved");
+        storage.move_to(&moved).unwrap();
+        assert_eq!(storage.read_block(2, 0, 12).unwrap(), &data[32..]);
+
+        storage.delete().unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+}
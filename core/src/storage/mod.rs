@@ -0,0 +1,416 @@
+//! Maps pieces onto the files of a torrent
+
+mod file;
+mod memory;
+#[cfg(target_os = "linux")]
+mod mmap;
+
+use bytes::Bytes;
+use sha1::{Digest, Sha1};
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+};
+use thiserror::Error;
+
+use crate::meta_info::{FileKind, Info};
+
+pub use file::{FileStorage, DEFAULT_MAX_OPEN_FILES};
+pub use memory::MemoryStorage;
+#[cfg(target_os = "linux")]
+pub use mmap::MmapStorage;
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// A file path in the torrent is empty, absolute or tries to escape the download directory
+    #[error("Invalid file path {0:?}")]
+    InvalidPath(String),
+    #[error("Piece length can't be zero")]
+    InvalidPieceLength,
+    /// The requested range is outside of the torrent
+    #[error("Block {index}:{begin} with length {length} is out of bounds")]
+    OutOfBounds { index: u32, begin: u32, length: u32 },
+    /// There's no room left for new data
+    #[error("Storage is full")]
+    Full,
+}
+
+/// Where the data of a torrent is kept.
+///
+/// All the methods are blocking, so they are expected to run outside of the async runtime.
+pub trait Storage: Send + Sync {
+    fn layout(&self) -> &Layout;
+
+    fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Bytes, StorageError>;
+
+    fn write_block(&self, index: u32, begin: u32, data: &[u8]) -> Result<(), StorageError>;
+
+    /// Returns the SHA-1 hash of a whole piece
+    fn hash_piece(&self, index: u32) -> Result<[u8; 20], StorageError> {
+        let data = self.read_block(index, 0, self.layout().piece_length(index))?;
+
+        let mut hasher = Sha1::new();
+        hasher.update(&data);
+        Ok(hasher.finalize().into())
+    }
+
+    /// Makes sure everything written so far is persisted
+    fn flush(&self) -> Result<(), StorageError>;
+
+    /// Moves the data to a new download directory
+    fn move_to(&self, root: &Path) -> Result<(), StorageError>;
+
+    /// Deletes all the data of the torrent
+    fn delete(&self) -> Result<(), StorageError>;
+
+    /// Marks a file as not wanted. Its data is kept out of it where possible, for the pieces it
+    /// shares with other files, and moved back once it's wanted again.
+    fn set_skipped(&self, _file: usize, _skipped: bool) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// A reader moved past a verified piece, storages that can't hold every piece may drop it
+    fn consume(&self, _index: u32) {}
+
+    /// Pieces dropped since the last call, they have to be downloaded again to be read
+    fn take_evicted(&self) -> Vec<u32> {
+        Vec::new()
+    }
+
+    /// Size and modification time of a file, `None` if it doesn't exist or there are no real files
+    fn file_metadata(&self, _file: usize) -> Result<Option<FileMetadata>, StorageError> {
+        Ok(None)
+    }
+}
+
+/// How space for the files is reserved before they are written
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Allocation {
+    /// Files only take the space of what was written so far
+    #[default]
+    Sparse,
+    /// Space is reserved up front without writing it, avoiding fragmentation
+    Preallocate,
+    /// Files are filled with zeros up front, for file systems that can't preallocate
+    Full,
+}
+
+/// The storage a session creates for a torrent
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StorageBackend {
+    /// A [`FileStorage`] in the save path
+    #[default]
+    File,
+    /// A [`MmapStorage`] in the save path
+    #[cfg(target_os = "linux")]
+    Mmap,
+    /// A [`MemoryStorage`], holding at most `limit` bytes if set. Nothing is saved, so the
+    /// torrent starts over every time it's added.
+    Memory { limit: Option<usize> },
+}
+
+/// What a file looked like on disk, used to tell whether it was modified
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub size: u64,
+    /// Seconds since [UNIX epoch][`std::time::UNIX_EPOCH`]
+    pub mtime: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    /// Path relative to the download directory
+    pub path: PathBuf,
+    pub length: u64,
+    /// Offset of the first byte of the file in the torrent
+    pub offset: u64,
+}
+
+/// A range of a single file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSlice {
+    /// Index in [`Layout::files`]
+    pub file: usize,
+    pub offset: u64,
+    pub length: usize,
+}
+
+/// The files of a torrent, laid out one after the other as one contiguous stream of pieces
+#[derive(Debug, Clone)]
+pub struct Layout {
+    files: Vec<FileEntry>,
+    piece_length: u32,
+    total_length: u64,
+}
+
+impl Layout {
+    pub fn new(info: &Info) -> Result<Self, StorageError> {
+        let name = sanitize(&[&info.name])?;
+
+        let files = match &info.files {
+            FileKind::SingleFile { length, .. } => vec![(name, *length)],
+            FileKind::MultiFile { files } => files
+                .iter()
+                .map(|file| Ok((name.join(sanitize(&file.path)?), file.length)))
+                .collect::<Result<_, StorageError>>()?,
+        };
+
+        Self::from_files(files, info.piece_length as u32)
+    }
+
+    /// Builds a layout from the relative paths and lengths of the files
+    pub fn from_files(files: Vec<(PathBuf, u64)>, piece_length: u32) -> Result<Self, StorageError> {
+        if piece_length == 0 {
+            return Err(StorageError::InvalidPieceLength);
+        }
+
+        let mut offset = 0;
+        let files = files
+            .into_iter()
+            .map(|(path, length)| {
+                let entry = FileEntry {
+                    path,
+                    length,
+                    offset,
+                };
+                offset += length;
+                entry
+            })
+            .collect();
+
+        Ok(Self {
+            files,
+            piece_length,
+            total_length: offset,
+        })
+    }
+
+    pub fn files(&self) -> &[FileEntry] {
+        &self.files
+    }
+
+    pub const fn total_length(&self) -> u64 {
+        self.total_length
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.total_length.div_ceil(self.piece_length as u64) as usize
+    }
+
+    /// Length of a piece, only the last one can be shorter
+    pub fn piece_length(&self, index: u32) -> u32 {
+        let begin = index as u64 * self.piece_length as u64;
+        self.total_length
+            .saturating_sub(begin)
+            .min(self.piece_length as u64) as u32
+    }
+
+    /// Offset of a piece in the torrent
+    pub fn piece_offset(&self, index: u32) -> u64 {
+        index as u64 * self.piece_length as u64
+    }
+
+    /// The piece containing a byte of the torrent and where that byte is in it
+    pub const fn locate(&self, offset: u64) -> (u32, u32) {
+        (
+            (offset / self.piece_length as u64) as u32,
+            (offset % self.piece_length as u64) as u32,
+        )
+    }
+
+    /// The pieces that contain some bytes of a file
+    pub fn file_pieces(&self, file: usize) -> std::ops::Range<u32> {
+        let entry = &self.files[file];
+        let first = (entry.offset / self.piece_length as u64) as u32;
+        if entry.length == 0 {
+            return first..first;
+        }
+        let end = (entry.offset + entry.length).div_ceil(self.piece_length as u64);
+        first..end as u32
+    }
+
+    /// Splits a range of a piece into the file ranges it covers
+    pub fn slices(
+        &self,
+        index: u32,
+        begin: u32,
+        length: u32,
+    ) -> Result<Vec<FileSlice>, StorageError> {
+        if (index as usize) >= self.piece_count()
+            || begin as u64 + length as u64 > self.piece_length(index) as u64
+        {
+            return Err(StorageError::OutOfBounds {
+                index,
+                begin,
+                length,
+            });
+        }
+
+        let mut position = self.piece_offset(index) + begin as u64;
+        let end = position + length as u64;
+        // The first file that ends after the start of the range
+        let first = self
+            .files
+            .partition_point(|file| file.offset + file.length <= position);
+
+        let mut slices = Vec::new();
+        for (file, entry) in self.files.iter().enumerate().skip(first) {
+            if position == end {
+                break;
+            }
+            if entry.length == 0 {
+                continue;
+            }
+
+            let slice_end = end.min(entry.offset + entry.length);
+            slices.push(FileSlice {
+                file,
+                offset: position - entry.offset,
+                length: (slice_end - position) as usize,
+            });
+            position = slice_end;
+        }
+
+        Ok(slices)
+    }
+}
+
+/// Joins path components from a torrent, rejecting anything that could escape the download directory
+fn sanitize<S: AsRef<str>>(components: &[S]) -> Result<PathBuf, StorageError> {
+    let mut path = PathBuf::new();
+
+    for component in components {
+        let component = component.as_ref();
+        let mut parsed = Path::new(component).components();
+
+        match (parsed.next(), parsed.next()) {
+            (Some(Component::Normal(normal)), None)
+                if !component.contains(['/', '\\']) && normal == component =>
+            {
+                path.push(normal)
+            }
+            _ => return Err(StorageError::InvalidPath(components_to_string(components))),
+        }
+    }
+
+    if path.as_os_str().is_empty() {
+        return Err(StorageError::InvalidPath(components_to_string(components)));
+    }
+
+    Ok(path)
+}
+
+fn components_to_string<S: AsRef<str>>(components: &[S]) -> String {
+    components
+        .iter()
+        .map(AsRef::as_ref)
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta_info::File;
+
+    fn info(files: Vec<File>) -> Info {
+        Info {
+            name: "torrent".to_string(),
+            piece_length: 16,
+            pieces: Vec::new(),
+            private: None,
+            source: None,
+            files: FileKind::MultiFile { files },
+        }
+    }
+
+    fn file(path: &[&str], length: u64) -> File {
+        File {
+            length,
+            md5sum: None,
+            path: path.iter().map(|part| part.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn pieces_span_files() {
+        let layout = Layout::new(&info(vec![
+            file(&["a"], 10),
+            file(&["empty"], 0),
+            file(&["dir", "b"], 4),
+            file(&["c"], 30),
+        ]))
+        .unwrap();
+
+        assert_eq!(layout.total_length(), 44);
+        assert_eq!(layout.piece_count(), 3);
+        assert_eq!(layout.piece_length(2), 12);
+        assert_eq!(layout.files()[2].path, Path::new("torrent/dir/b"));
+        assert_eq!(layout.file_pieces(3), 0..3);
+        assert_eq!(layout.file_pieces(1), 0..0);
+        assert_eq!(layout.file_pieces(2), 0..1);
+
+        assert_eq!(
+            layout.slices(0, 8, 8).unwrap(),
+            vec![
+                FileSlice {
+                    file: 0,
+                    offset: 8,
+                    length: 2
+                },
+                FileSlice {
+                    file: 2,
+                    offset: 0,
+                    length: 4
+                },
+                FileSlice {
+                    file: 3,
+                    offset: 0,
+                    length: 2
+                }
+            ]
+        );
+        assert_eq!(
+            layout.slices(2, 0, 12).unwrap(),
+            vec![FileSlice {
+                file: 3,
+                offset: 18,
+                length: 12
+            }]
+        );
+
+        assert!(matches!(
+            layout.slices(2, 0, 13),
+            Err(StorageError::OutOfBounds { .. })
+        ));
+        assert!(layout.slices(3, 0, 1).is_err());
+    }
+
+    #[test]
+    fn rejects_path_traversal() {
+        for path in [
+            &["..", "etc", "passwd"][..],
+            &["/etc"],
+            &["a/../../b"],
+            &["."],
+            &[""],
+            &["dir\\file"],
+            &[],
+        ] {
+            assert!(
+                matches!(
+                    Layout::new(&info(vec![file(path, 1)])),
+                    Err(StorageError::InvalidPath(_))
+                ),
+                "{:?} was accepted",
+                path
+            );
+        }
+
+        let mut info = info(Vec::new());
+        info.name = "..".to_string();
+        assert!(Layout::new(&info).is_err());
+    }
+}
@@ -0,0 +1,659 @@
+use bytes::{Buf, Bytes};
+use color_eyre::eyre::{eyre, Result};
+use futures::future::BoxFuture;
+use std::{
+    io::{self, ErrorKind, SeekFrom},
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncSeek, ReadBuf},
+    runtime::Handle,
+    sync::{watch, Mutex, MutexGuard},
+    time::Instant,
+};
+
+use crate::{
+    disk::DiskHandle,
+    download::{BlockOutcome, Download},
+    meta_info::Info,
+    pipeline::Block,
+};
+
+/// A torrent being downloaded, shared between the tasks talking to peers and whoever reads its files
+#[derive(Clone)]
+pub struct Torrent {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    info_hash: [u8; 20],
+    info: Info,
+    download: Mutex<Download>,
+    disk: DiskHandle,
+    /// Bumped every time a piece is verified
+    verified: watch::Sender<u64>,
+}
+
+impl Torrent {
+    pub fn new(info_hash: [u8; 20], info: Info, download: Download) -> Self {
+        let disk = download.disk().clone();
+
+        Self {
+            inner: Arc::new(Inner {
+                info_hash,
+                info,
+                download: Mutex::new(download),
+                disk,
+                verified: watch::channel(0).0,
+            }),
+        }
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.inner.info_hash
+    }
+
+    pub fn info(&self) -> &Info {
+        &self.inner.info
+    }
+
+    pub fn disk(&self) -> &DiskHandle {
+        &self.inner.disk
+    }
+
+    /// Locks the download state
+    pub async fn download(&self) -> MutexGuard<'_, Download> {
+        self.inner.download.lock().await
+    }
+
+    /// Stores a block, waking up the readers waiting for its piece once it's verified
+    pub async fn block_received(
+        &self,
+        peer: SocketAddr,
+        index: u32,
+        begin: u32,
+        data: Bytes,
+    ) -> Result<BlockOutcome> {
+        // The download is only locked to update its state, not while the disk is busy
+        let block = Block::new(index, begin, data.len() as u32);
+        if let Some(outcome) = self.download().await.check_block(&block) {
+            return Ok(outcome);
+        }
+        self.disk().write(index, begin, data).await?;
+        let Some(contributors) = self.download().await.block_written(peer, &block) else {
+            return Ok(BlockOutcome::Stored);
+        };
+
+        let hash = self.disk().hash_piece(index).await?;
+        let valid = self.info().piece_hash(index) == Some(hash);
+        let outcome = self
+            .download()
+            .await
+            .piece_checked(index, valid, contributors);
+
+        if let BlockOutcome::Verified(_) = outcome {
+            self.inner.verified.send_modify(|count| *count += 1);
+        }
+        Ok(outcome)
+    }
+
+    /// Asks for a piece to be downloaded by `deadline`, before any other piece.
+    ///
+    /// Pieces we already have are ignored, the deadline is dropped once the piece is verified.
+    pub async fn set_piece_deadline(&self, index: u32, deadline: Instant) {
+        self.download()
+            .await
+            .picker_mut()
+            .set_piece_deadline(index, deadline);
+    }
+
+    /// Sets how many pieces after the position of a [`TorrentFile`] reader are downloaded in
+    /// order before anything else
+    pub async fn set_read_ahead(&self, pieces: u32) {
+        self.download().await.picker_mut().set_read_ahead(pieces);
+    }
+
+    /// Opens a file of the torrent for reading, before it's downloaded.
+    ///
+    /// Reads wait for the pieces they need and move the read-ahead window of the picker along.
+    /// Reading a skipped file waits until it's wanted again.
+    pub fn open_file(&self, file: usize) -> Result<TorrentFile> {
+        let length = self
+            .disk()
+            .storage()
+            .layout()
+            .files()
+            .get(file)
+            .ok_or_else(|| eyre!("Torrent has no file {}", file))?
+            .length;
+
+        Ok(TorrentFile {
+            torrent: self.clone(),
+            file,
+            length,
+            position: 0,
+            buffer: Bytes::new(),
+            read: None,
+            seek: None,
+        })
+    }
+
+    /// Reads up to `length` bytes from `offset` in the torrent, without crossing a piece boundary
+    async fn read_at(&self, offset: u64, length: u64) -> io::Result<Bytes> {
+        let layout = self.disk().storage().layout();
+        let (index, begin) = layout.locate(offset);
+        let length = length.min((layout.piece_length(index) - begin) as u64) as u32;
+
+        let mut verified = self.inner.verified.subscribe();
+        let mut _deadline = None;
+        loop {
+            {
+                let mut download = self.download().await;
+                // Evicted pieces are downloaded again
+                download.forget_evicted();
+                if download.picker().has_piece(index) {
+                    break;
+                }
+                let picker = download.picker_mut();
+                picker.set_read_position(index);
+                if picker.piece_deadline(index).is_none() {
+                    picker.set_piece_deadline(index, Instant::now());
+                    _deadline = Some(ReadDeadline {
+                        torrent: self.clone(),
+                        index,
+                    });
+                }
+            }
+            verified
+                .changed()
+                .await
+                .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "Torrent was closed"))?;
+        }
+
+        match self.disk().read(index, begin, length).await {
+            Ok(data) => Ok(data),
+            Err(error) => {
+                // The piece can be evicted between the check and the read
+                let mut download = self.download().await;
+                download.forget_evicted();
+                if download.picker().has_piece(index) {
+                    return Err(io::Error::other(error.to_string()));
+                }
+                drop(download);
+                Box::pin(self.read_at(offset, length as u64)).await
+            }
+        }
+    }
+
+    /// Changes the download outside of an async context: right away if it isn't locked, from a
+    /// task otherwise
+    fn update_download(&self, update: impl FnOnce(&mut Download) + Send + 'static) {
+        if let Ok(mut download) = self.inner.download.try_lock() {
+            update(&mut download);
+        } else if let Ok(runtime) = Handle::try_current() {
+            let torrent = self.clone();
+            runtime.spawn(async move { update(&mut *torrent.download().await) });
+        }
+    }
+}
+
+/// Drops the deadline a read asked for when the read ends, so one that was abandoned by a seek
+/// or a dropped file doesn't keep its piece urgent
+struct ReadDeadline {
+    torrent: Torrent,
+    index: u32,
+}
+
+impl Drop for ReadDeadline {
+    fn drop(&mut self) {
+        let index = self.index;
+        self.torrent
+            .update_download(move |download| download.picker_mut().clear_piece_deadline(index));
+    }
+}
+
+/// A file of a [`Torrent`], readable while the torrent is being downloaded
+pub struct TorrentFile {
+    torrent: Torrent,
+    file: usize,
+    length: u64,
+    /// Position of the next byte to read from the file
+    position: u64,
+    /// Data that was read but didn't fit in the caller's buffer
+    buffer: Bytes,
+    read: Option<BoxFuture<'static, io::Result<Bytes>>>,
+    seek: Option<BoxFuture<'static, ()>>,
+}
+
+impl TorrentFile {
+    pub const fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub const fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Offset of the next byte to read in the torrent
+    fn torrent_offset(&self) -> u64 {
+        let layout = self.torrent.disk().storage().layout();
+        layout.files()[self.file].offset + self.position
+    }
+}
+
+impl AsyncRead for TorrentFile {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.buffer.is_empty() {
+            // Seeking past the end is allowed, reading from there is the end of the file
+            let remaining = self.length.saturating_sub(self.position);
+            if remaining == 0 || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+
+            let read = match &mut self.read {
+                Some(read) => read,
+                None => {
+                    let (torrent, offset) = (self.torrent.clone(), self.torrent_offset());
+                    let length = remaining.min(buf.remaining() as u64);
+                    self.read.insert(Box::pin(
+                        async move { torrent.read_at(offset, length).await },
+                    ))
+                }
+            };
+            let result = ready!(read.as_mut().poll(cx));
+            self.read = None;
+            self.buffer = result?;
+        }
+
+        let length = self.buffer.len().min(buf.remaining());
+        buf.put_slice(&self.buffer[..length]);
+        self.buffer.advance(length);
+        let storage = self.torrent.disk().storage().clone();
+        let (previous, _) = storage.layout().locate(self.torrent_offset());
+        self.position += length as u64;
+
+        // The pieces that were read to their end aren't needed anymore
+        let (current, _) = storage.layout().locate(self.torrent_offset());
+        for index in previous..current {
+            storage.consume(index);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for TorrentFile {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let position = match position {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(offset) => self.length.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                "Invalid seek to a negative or overflowing position",
+            )
+        })?;
+
+        self.read = None;
+        self.buffer = Bytes::new();
+        self.position = position;
+
+        // Start downloading from the new position right away, not only when it's read
+        if position < self.length {
+            let torrent = self.torrent.clone();
+            let (index, _) = torrent
+                .disk()
+                .storage()
+                .layout()
+                .locate(self.torrent_offset());
+            self.seek = Some(Box::pin(async move {
+                torrent
+                    .download()
+                    .await
+                    .picker_mut()
+                    .set_read_position(index);
+            }));
+        }
+
+        Ok(())
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        if let Some(seek) = &mut self.seek {
+            ready!(seek.as_mut().poll(cx));
+            self.seek = None;
+        }
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+impl Drop for TorrentFile {
+    fn drop(&mut self) {
+        // The read-ahead window goes away with its reader, unless another one moved it since
+        let (index, _) = self
+            .torrent
+            .disk()
+            .storage()
+            .layout()
+            .locate(self.torrent_offset());
+        self.torrent.update_download(move |download| {
+            let picker = download.picker_mut();
+            if picker.read_position() == Some(index) {
+                picker.clear_read_position();
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        meta_info::{File, FileKind},
+        pipeline::BLOCK_SIZE,
+        storage::{Layout, MemoryStorage, Storage, StorageError},
+    };
+    use bitvec::{order::Msb0, vec::BitVec};
+    use std::{path::Path, sync::mpsc, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt, AsyncSeekExt},
+        sync::mpsc::{unbounded_channel, UnboundedSender},
+        time::timeout,
+    };
+
+    const PIECE_LENGTH: usize = BLOCK_SIZE as usize;
+
+    fn torrent(data: &[u8]) -> Torrent {
+        let info = info(data);
+        let storage = Arc::new(MemoryStorage::new(Layout::new(&info).unwrap()));
+        let download = Download::new(&info, storage);
+        Torrent::new([0; 20], info, download)
+    }
+
+    fn info(data: &[u8]) -> Info {
+        let mut info = Info::from_data("torrent", PIECE_LENGTH, data);
+        info.files = FileKind::MultiFile {
+            files: vec![
+                File {
+                    length: PIECE_LENGTH as u64 / 2,
+                    md5sum: None,
+                    path: vec!["a".to_string()],
+                },
+                File {
+                    length: (data.len() - PIECE_LENGTH / 2) as u64,
+                    md5sum: None,
+                    path: vec!["b".to_string()],
+                },
+            ],
+        };
+        info
+    }
+
+    async fn receive(torrent: &Torrent, data: &[u8], index: usize) {
+        let piece = &data[index * PIECE_LENGTH..data.len().min((index + 1) * PIECE_LENGTH)];
+        let peer = "10.0.0.1:6881".parse().unwrap();
+        let all: BitVec<u8, Msb0> = BitVec::repeat(true, 4);
+        let mut download = torrent.download().await;
+        download.picker_mut().peer_bitfield(&all);
+        let mut bitfield: BitVec<u8, Msb0> = BitVec::repeat(false, 4);
+        bitfield.set(index, true);
+        download.picker_mut().pick(&bitfield, 1);
+        drop(download);
+
+        let outcome = torrent
+            .block_received(peer, index as u32, 0, Bytes::copy_from_slice(piece))
+            .await
+            .unwrap();
+        assert_eq!(outcome, BlockOutcome::Verified(index as u32));
+    }
+
+    #[tokio::test]
+    async fn reads_wait_for_pieces() {
+        let data: Vec<u8> = (0..PIECE_LENGTH * 3 + 100)
+            .map(|_| rand::random())
+            .collect();
+        let torrent = torrent(&data);
+        let mut file = torrent.open_file(1).unwrap();
+        assert_eq!(file.len(), data.len() as u64 - PIECE_LENGTH as u64 / 2);
+        assert!(torrent.open_file(2).is_err());
+
+        let reader = tokio::spawn(async move {
+            let mut contents = Vec::new();
+            file.read_to_end(&mut contents).await.unwrap();
+            contents
+        });
+        tokio::task::yield_now().await;
+        // The first piece the file needs is wanted right now
+        assert!(torrent
+            .download()
+            .await
+            .picker()
+            .piece_deadline(0)
+            .is_some());
+
+        for index in [2, 0, 3, 1] {
+            receive(&torrent, &data, index).await;
+        }
+        assert_eq!(reader.await.unwrap(), &data[PIECE_LENGTH / 2..]);
+    }
+
+    #[tokio::test]
+    async fn seeking_moves_the_read_position() {
+        let data: Vec<u8> = (0..PIECE_LENGTH * 4).map(|_| rand::random()).collect();
+        let torrent = torrent(&data);
+        receive(&torrent, &data, 2).await;
+
+        let mut file = torrent.open_file(1).unwrap();
+        let position = file
+            .seek(SeekFrom::End(-(PIECE_LENGTH as i64 * 2)))
+            .await
+            .unwrap();
+        assert_eq!(position, PIECE_LENGTH as u64 * 3 / 2);
+        assert!(file.seek(SeekFrom::Current(-(1 << 20))).await.is_err());
+
+        // Past the end there's nothing left to read
+        file.seek(SeekFrom::End(10)).await.unwrap();
+        let mut rest = Vec::new();
+        assert_eq!(file.read_to_end(&mut rest).await.unwrap(), 0);
+        file.seek(SeekFrom::End(-(PIECE_LENGTH as i64 * 2)))
+            .await
+            .unwrap();
+
+        let mut piece = vec![0; PIECE_LENGTH];
+        file.read_exact(&mut piece).await.unwrap();
+        assert_eq!(piece, &data[PIECE_LENGTH * 2..PIECE_LENGTH * 3]);
+
+        // The next piece isn't there yet
+        file.seek(SeekFrom::Current(10)).await.unwrap();
+        tokio::task::yield_now().await;
+        let read = tokio::spawn(async move {
+            let mut byte = [0];
+            file.read_exact(&mut byte).await.unwrap();
+            byte[0]
+        });
+        receive(&torrent, &data, 3).await;
+        assert_eq!(read.await.unwrap(), data[PIECE_LENGTH * 3 + 10]);
+    }
+
+    #[tokio::test]
+    async fn abandoned_reads_drop_their_deadline() {
+        let data: Vec<u8> = (0..PIECE_LENGTH * 4).map(|_| rand::random()).collect();
+        let torrent = torrent(&data);
+        let mut file = torrent.open_file(1).unwrap();
+        file.seek(SeekFrom::Start(PIECE_LENGTH as u64 * 2))
+            .await
+            .unwrap();
+
+        let mut byte = [0];
+        assert!(
+            timeout(Duration::from_millis(10), file.read_exact(&mut byte))
+                .await
+                .is_err()
+        );
+        assert!(torrent
+            .download()
+            .await
+            .picker()
+            .piece_deadline(2)
+            .is_some());
+
+        file.seek(SeekFrom::Start(0)).await.unwrap();
+        let download = torrent.download().await;
+        assert_eq!(download.picker().piece_deadline(2), None);
+        assert_eq!(download.picker().read_position(), Some(0));
+        drop(download);
+
+        drop(file);
+        assert_eq!(torrent.download().await.picker().read_position(), None);
+    }
+
+    #[tokio::test]
+    async fn streaming_picks_ahead_of_the_reader() {
+        let data: Vec<u8> = (0..PIECE_LENGTH * 4).map(|_| rand::random()).collect();
+        let torrent = torrent(&data);
+        let all: BitVec<u8, Msb0> = BitVec::repeat(true, 4);
+        torrent.download().await.picker_mut().peer_bitfield(&all);
+
+        torrent.set_read_ahead(2).await;
+        torrent.set_piece_deadline(3, Instant::now()).await;
+        let mut file = torrent.open_file(1).unwrap();
+        file.seek(SeekFrom::Start(PIECE_LENGTH as u64))
+            .await
+            .unwrap();
+
+        let picked: Vec<u32> = torrent
+            .download()
+            .await
+            .picker_mut()
+            .pick(&all, 3)
+            .iter()
+            .map(|block| block.index)
+            .collect();
+        assert_eq!(picked, vec![3, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn read_pieces_are_consumed() {
+        let data: Vec<u8> = (0..PIECE_LENGTH * 4).map(|_| rand::random()).collect();
+        let info = info(&data);
+        let storage = Arc::new(MemoryStorage::with_limit(
+            Layout::new(&info).unwrap(),
+            PIECE_LENGTH * 2,
+        ));
+        let download = Download::new(&info, storage.clone());
+        let torrent = Torrent::new([0; 20], info, download);
+        let mut file = torrent.open_file(1).unwrap();
+
+        receive(&torrent, &data, 0).await;
+        receive(&torrent, &data, 1).await;
+        let mut contents = vec![0; PIECE_LENGTH * 3 / 2];
+        file.read_exact(&mut contents).await.unwrap();
+
+        // Only the pieces that were read make room for the next ones
+        receive(&torrent, &data, 2).await;
+        receive(&torrent, &data, 3).await;
+        assert!(!storage.contains(0) && !storage.contains(1));
+        file.read_to_end(&mut contents).await.unwrap();
+        assert_eq!(contents, &data[PIECE_LENGTH / 2..]);
+        assert_eq!(storage.size(), PIECE_LENGTH * 2);
+
+        // Evicted pieces aren't advertised anymore and are downloaded again when read
+        assert!(!torrent.download().await.picker().has_piece(0));
+        file.rewind().await.unwrap();
+        let read = tokio::spawn(async move {
+            let mut piece = vec![0; PIECE_LENGTH / 2];
+            file.read_exact(&mut piece).await.unwrap();
+            piece
+        });
+        tokio::task::yield_now().await;
+        assert!(torrent
+            .download()
+            .await
+            .picker()
+            .piece_deadline(0)
+            .is_some());
+        receive(&torrent, &data, 0).await;
+        assert_eq!(read.await.unwrap(), &data[PIECE_LENGTH / 2..PIECE_LENGTH]);
+    }
+
+    /// Hashes pieces only once the test lets it
+    struct GatedStorage {
+        inner: MemoryStorage,
+        started: UnboundedSender<()>,
+        gate: std::sync::Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl Storage for GatedStorage {
+        fn layout(&self) -> &Layout {
+            self.inner.layout()
+        }
+
+        fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Bytes, StorageError> {
+            self.inner.read_block(index, begin, length)
+        }
+
+        fn write_block(&self, index: u32, begin: u32, data: &[u8]) -> Result<(), StorageError> {
+            self.inner.write_block(index, begin, data)
+        }
+
+        fn hash_piece(&self, index: u32) -> Result<[u8; 20], StorageError> {
+            let _ = self.started.send(());
+            let _ = self.gate.lock().unwrap().recv();
+            self.inner.hash_piece(index)
+        }
+
+        fn flush(&self) -> Result<(), StorageError> {
+            self.inner.flush()
+        }
+
+        fn move_to(&self, root: &Path) -> Result<(), StorageError> {
+            self.inner.move_to(root)
+        }
+
+        fn delete(&self) -> Result<(), StorageError> {
+            self.inner.delete()
+        }
+    }
+
+    #[tokio::test]
+    async fn hashing_doesnt_lock_the_download() {
+        let data: Vec<u8> = (0..PIECE_LENGTH * 2).map(|_| rand::random()).collect();
+        let info = info(&data);
+        let (started, mut hashing) = unbounded_channel();
+        let (release, gate) = mpsc::channel();
+        let storage = Arc::new(GatedStorage {
+            inner: MemoryStorage::new(Layout::new(&info).unwrap()),
+            started,
+            gate: std::sync::Mutex::new(gate),
+        });
+        let torrent = Torrent::new([0; 20], info.clone(), Download::new(&info, storage));
+
+        let receiving = tokio::spawn({
+            let (torrent, data) = (torrent.clone(), data.clone());
+            async move { receive(&torrent, &data, 1).await }
+        });
+        hashing.recv().await.unwrap();
+        let download = timeout(Duration::from_secs(1), torrent.download())
+            .await
+            .unwrap();
+        assert!(!download.picker().has_piece(1));
+        drop(download);
+
+        release.send(()).unwrap();
+        receiving.await.unwrap();
+        assert!(torrent.download().await.picker().has_piece(1));
+    }
+}
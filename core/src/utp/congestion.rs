@@ -0,0 +1,220 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// The queuing delay LEDBAT tries to stay under, in microseconds
+const TARGET_DELAY: f64 = 100_000.0;
+/// How many bytes the window can grow by in one round trip
+const MAX_WINDOW_INCREASE_PER_RTT: f64 = 3000.0;
+/// The base delay is the minimum seen over this many minutes
+const BASE_DELAY_HISTORY: usize = 2;
+
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(60);
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+
+/// Tracks the minimum one way delay, bucketed per minute.
+///
+/// Clocks of the two peers are not synchronized so only the difference from this minimum is meaningful.
+#[derive(Debug)]
+struct BaseDelay {
+    buckets: VecDeque<(Instant, u32)>,
+}
+
+impl BaseDelay {
+    fn new() -> Self {
+        Self {
+            buckets: VecDeque::with_capacity(BASE_DELAY_HISTORY),
+        }
+    }
+
+    /// Records a sample and returns the current base delay
+    fn update(&mut self, delay: u32, now: Instant) -> u32 {
+        match self.buckets.back_mut() {
+            Some((start, min)) if now.duration_since(*start) < Duration::from_secs(60) => {
+                *min = (*min).min(delay);
+            }
+            _ => {
+                if self.buckets.len() == BASE_DELAY_HISTORY {
+                    self.buckets.pop_front();
+                }
+                self.buckets.push_back((now, delay));
+            }
+        }
+
+        self.buckets
+            .iter()
+            .map(|(_, min)| *min)
+            .min()
+            .unwrap_or(delay)
+    }
+}
+
+/// LEDBAT delay based congestion controller.
+///
+/// The window grows as long as the measured queuing delay stays under [`TARGET_DELAY`] and shrinks when it goes above,
+/// which makes uTP yield to other traffic on the same link.
+#[derive(Debug)]
+pub(crate) struct Ledbat {
+    /// Congestion window in bytes
+    window: f64,
+    min_window: f64,
+    max_window: f64,
+    slow_start: bool,
+    base_delay: BaseDelay,
+}
+
+impl Ledbat {
+    pub fn new(packet_size: usize, max_window: usize) -> Self {
+        Self {
+            window: (packet_size * 2) as f64,
+            min_window: packet_size as f64,
+            max_window: max_window as f64,
+            slow_start: true,
+            base_delay: BaseDelay::new(),
+        }
+    }
+
+    pub fn window(&self) -> usize {
+        self.window as usize
+    }
+
+    /// Updates the window after `bytes_acked` were acknowledged.
+    ///
+    /// `delay` is the one way delay the remote peer measured for our packets.
+    pub fn on_ack(&mut self, bytes_acked: usize, delay: u32, now: Instant) {
+        // The remote peer hasn't received anything to measure yet
+        if delay == 0 {
+            return;
+        }
+
+        let base_delay = self.base_delay.update(delay, now);
+        let queuing_delay = delay.wrapping_sub(base_delay) as f64;
+        let off_target = ((TARGET_DELAY - queuing_delay) / TARGET_DELAY).max(-1.0);
+
+        if self.slow_start && off_target > 0.0 {
+            self.window += bytes_acked as f64;
+        } else {
+            self.slow_start = false;
+            let window_factor = bytes_acked as f64 / self.window.max(bytes_acked as f64);
+            self.window += MAX_WINDOW_INCREASE_PER_RTT * off_target * window_factor;
+        }
+
+        self.window = self.window.clamp(self.min_window, self.max_window);
+    }
+
+    /// A packet was lost, detected through duplicate or selective acks
+    pub fn on_loss(&mut self) {
+        self.slow_start = false;
+        self.window = (self.window / 2.0).max(self.min_window);
+    }
+
+    /// Nothing was acknowledged for a whole retransmission timeout
+    pub fn on_timeout(&mut self) {
+        self.slow_start = false;
+        self.window = self.min_window;
+    }
+}
+
+/// Round trip time estimator, see RFC 6298
+#[derive(Debug)]
+pub(crate) struct Rtt {
+    smoothed: Option<Duration>,
+    variance: Duration,
+    timeout: Duration,
+}
+
+impl Rtt {
+    pub const fn new() -> Self {
+        Self {
+            smoothed: None,
+            variance: Duration::ZERO,
+            timeout: INITIAL_RTO,
+        }
+    }
+
+    pub fn update(&mut self, sample: Duration) {
+        let smoothed = match self.smoothed {
+            None => {
+                self.variance = sample / 2;
+                sample
+            }
+            Some(smoothed) => {
+                let difference = smoothed.abs_diff(sample);
+                self.variance = self.variance * 3 / 4 + difference / 4;
+                smoothed * 7 / 8 + sample / 8
+            }
+        };
+
+        self.smoothed = Some(smoothed);
+        self.timeout = (smoothed + self.variance * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// Doubles the timeout after it expired
+    pub fn backoff(&mut self) {
+        self.timeout = (self.timeout * 2).min(MAX_RTO);
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_follows_delay() {
+        let now = Instant::now();
+        let mut ledbat = Ledbat::new(1000, 1 << 20);
+        let initial = ledbat.window();
+
+        // The first sample becomes the base delay, so there is no queuing at all
+        ledbat.on_ack(1000, 50_000, now);
+        ledbat.on_ack(1000, 50_000, now);
+        assert!(ledbat.window() > initial);
+
+        // Queuing delay way over target shrinks the window
+        let grown = ledbat.window();
+        for _ in 0..10 {
+            ledbat.on_ack(1000, 350_000, now);
+        }
+        assert!(ledbat.window() < grown);
+    }
+
+    #[test]
+    fn losses_shrink_window() {
+        let mut ledbat = Ledbat::new(1000, 1 << 20);
+        for _ in 0..20 {
+            ledbat.on_ack(1000, 10, Instant::now());
+        }
+
+        let window = ledbat.window();
+        ledbat.on_loss();
+        assert_eq!(ledbat.window(), window / 2);
+        ledbat.on_timeout();
+        assert_eq!(ledbat.window(), 1000);
+    }
+
+    #[test]
+    fn retransmission_timeout() {
+        let mut rtt = Rtt::new();
+        assert_eq!(rtt.timeout(), INITIAL_RTO);
+
+        rtt.update(Duration::from_millis(10));
+        assert_eq!(rtt.timeout(), MIN_RTO);
+
+        rtt.update(Duration::from_secs(1));
+        assert!(rtt.timeout() > MIN_RTO);
+
+        rtt.backoff();
+        rtt.backoff();
+        rtt.backoff();
+        rtt.backoff();
+        rtt.backoff();
+        rtt.backoff();
+        assert_eq!(rtt.timeout(), MAX_RTO);
+    }
+}
@@ -0,0 +1,654 @@
+use bytes::{Bytes, BytesMut};
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use super::{
+    congestion::{Ledbat, Rtt},
+    packet::{seq_less_or_equal, Packet, PacketType, HEADER_LEN},
+};
+
+/// Largest datagram we send, small enough to avoid fragmentation on most links
+const MAX_PACKET_SIZE: usize = 1400;
+/// Payload bytes per data packet, leaves room for the header and a selective ack
+pub(crate) const MAX_PAYLOAD: usize = MAX_PACKET_SIZE - HEADER_LEN - 6;
+/// Bytes accepted from the writer before it has to wait for acks
+const SEND_BUFFER_SIZE: usize = 1 << 20;
+/// Bytes we advertise we can hold before the reader consumes them
+const RECEIVE_BUFFER_SIZE: usize = 1 << 20;
+/// Out of order packets further than this from ack_nr are dropped
+const MAX_REORDER_DISTANCE: u16 = 1024;
+/// Packets in flight are limited so the sequence space can't wrap around
+const MAX_PACKETS_IN_FLIGHT: usize = 1024;
+/// Number of bits in the selective ack bitmask we send
+const SELECTIVE_ACK_BITS: usize = 32;
+/// Duplicate acks needed to trigger a fast retransmit
+const DUPLICATE_ACK_THRESHOLD: u32 = 3;
+
+const MAX_SYN_TRANSMISSIONS: u32 = 4;
+const MAX_TRANSMISSIONS: u32 = 8;
+/// A STATE is sent when nothing was received for this long, so the remote peer knows we're still there
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(29);
+/// Connections that received nothing for this long are reset
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum State {
+    /// We sent a SYN and are waiting for the STATE reply
+    SynSent,
+    Connected,
+    /// The connection was reset or timed out
+    Closed,
+}
+
+#[derive(Debug)]
+struct InFlight {
+    packet_type: PacketType,
+    seq_nr: u16,
+    payload: Bytes,
+    sent_at: Instant,
+    transmissions: u32,
+    /// Marked as lost, has to be sent again as soon as the window allows it
+    resend: bool,
+}
+
+/// State machine of a single uTP connection.
+///
+/// It doesn't do any io by itself, every method pushes the packets that have to be sent into `out`.
+#[derive(Debug)]
+pub(crate) struct Connection {
+    pub state: State,
+    pub remote: SocketAddr,
+    pub recv_id: u16,
+    send_id: u16,
+    /// Sequence number of the next packet we send
+    seq_nr: u16,
+    /// Last sequence number received in order
+    ack_nr: u16,
+    epoch: Instant,
+
+    in_flight: VecDeque<InFlight>,
+    send_buffer: BytesMut,
+    remote_window: usize,
+    last_ack: u16,
+    duplicate_acks: u32,
+    /// Delay the remote peer's packets took to reach us, echoed back so it can measure its delay
+    reply_delay: u32,
+    last_received: Instant,
+    last_keepalive: Instant,
+    ledbat: Ledbat,
+    rtt: Rtt,
+
+    read_buffer: BytesMut,
+    reorder_buffer: HashMap<u16, (PacketType, Bytes)>,
+    /// The remote peer sent a FIN and we received everything before it
+    eof: bool,
+
+    /// Shutdown was requested, a FIN is sent once all the data is out
+    fin_queued: bool,
+    fin_seq_nr: Option<u16>,
+    fin_acked: bool,
+    /// The user facing stream was dropped
+    pub dropped: bool,
+
+    error: Option<io::ErrorKind>,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl Connection {
+    fn new(remote: SocketAddr, recv_id: u16, send_id: u16, epoch: Instant, state: State) -> Self {
+        Self {
+            state,
+            remote,
+            recv_id,
+            send_id,
+            seq_nr: 1,
+            ack_nr: 0,
+            epoch,
+            in_flight: VecDeque::new(),
+            send_buffer: BytesMut::new(),
+            remote_window: RECEIVE_BUFFER_SIZE,
+            last_ack: 0,
+            duplicate_acks: 0,
+            reply_delay: 0,
+            last_received: epoch,
+            last_keepalive: epoch,
+            ledbat: Ledbat::new(MAX_PAYLOAD, SEND_BUFFER_SIZE),
+            rtt: Rtt::new(),
+            read_buffer: BytesMut::new(),
+            reorder_buffer: HashMap::new(),
+            eof: false,
+            fin_queued: false,
+            fin_seq_nr: None,
+            fin_acked: false,
+            dropped: false,
+            error: None,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    /// Creates an outgoing connection, sending a SYN
+    pub fn connect(
+        remote: SocketAddr,
+        recv_id: u16,
+        epoch: Instant,
+        now: Instant,
+        out: &mut Vec<Packet>,
+    ) -> Self {
+        let mut connection = Self::new(
+            remote,
+            recv_id,
+            recv_id.wrapping_add(1),
+            epoch,
+            State::SynSent,
+        );
+
+        connection.in_flight.push_back(InFlight {
+            packet_type: PacketType::Syn,
+            seq_nr: connection.seq_nr,
+            payload: Bytes::new(),
+            sent_at: now,
+            transmissions: 1,
+            resend: false,
+        });
+        out.push(connection.packet(PacketType::Syn, connection.seq_nr, Bytes::new(), now));
+        connection.seq_nr = connection.seq_nr.wrapping_add(1);
+
+        connection
+    }
+
+    /// Accepts an incoming SYN, replying with a STATE
+    pub fn accept(
+        syn: &Packet,
+        remote: SocketAddr,
+        epoch: Instant,
+        now: Instant,
+        out: &mut Vec<Packet>,
+    ) -> Self {
+        let mut connection = Self::new(
+            remote,
+            syn.connection_id.wrapping_add(1),
+            syn.connection_id,
+            epoch,
+            State::Connected,
+        );
+        connection.seq_nr = rand::random();
+        connection.ack_nr = syn.seq_nr;
+        connection.last_ack = connection.seq_nr.wrapping_sub(1);
+        connection.remote_window = syn.window_size as usize;
+        connection.reply_delay = connection.timestamp(now).wrapping_sub(syn.timestamp);
+        connection.last_received = now;
+
+        out.push(connection.packet(PacketType::State, connection.seq_nr, Bytes::new(), now));
+        connection
+    }
+
+    fn timestamp(&self, now: Instant) -> u32 {
+        now.duration_since(self.epoch).as_micros() as u32
+    }
+
+    fn packet(&self, packet_type: PacketType, seq_nr: u16, payload: Bytes, now: Instant) -> Packet {
+        let selective_ack = (!self.reorder_buffer.is_empty()).then(|| {
+            let mut mask = vec![0u8; SELECTIVE_ACK_BITS / 8];
+            for bit in 0..SELECTIVE_ACK_BITS {
+                let seq_nr = self.ack_nr.wrapping_add(2 + bit as u16);
+                if self.reorder_buffer.contains_key(&seq_nr) {
+                    mask[bit / 8] |= 1 << (bit % 8);
+                }
+            }
+            mask
+        });
+
+        Packet {
+            packet_type,
+            // The SYN is the only packet that carries the id the sender receives on
+            connection_id: if packet_type == PacketType::Syn {
+                self.recv_id
+            } else {
+                self.send_id
+            },
+            timestamp: self.timestamp(now),
+            timestamp_difference: self.reply_delay,
+            window_size: RECEIVE_BUFFER_SIZE.saturating_sub(self.read_buffer.len()) as u32,
+            seq_nr,
+            ack_nr: self.ack_nr,
+            selective_ack,
+            payload,
+        }
+    }
+
+    pub fn on_packet(&mut self, packet: Packet, now: Instant, out: &mut Vec<Packet>) {
+        if self.state == State::Closed {
+            return;
+        }
+
+        if packet.packet_type == PacketType::Reset {
+            self.fail(io::ErrorKind::ConnectionReset);
+            return;
+        }
+
+        self.reply_delay = self.timestamp(now).wrapping_sub(packet.timestamp);
+        self.remote_window = packet.window_size as usize;
+        self.last_received = now;
+
+        match (self.state, packet.packet_type) {
+            (State::SynSent, PacketType::State) => {
+                self.state = State::Connected;
+                self.ack_nr = packet.seq_nr.wrapping_sub(1);
+                self.wake_writer();
+            }
+            // Data can't arrive before the connection is established
+            (State::SynSent, _) => return,
+            // Our STATE reply got lost
+            (_, PacketType::Syn) => {
+                out.push(self.packet(PacketType::State, self.seq_nr, Bytes::new(), now));
+                return;
+            }
+            _ => {}
+        }
+
+        self.process_ack(&packet, now);
+
+        if let PacketType::Data | PacketType::Fin = packet.packet_type {
+            self.receive(packet);
+            out.push(self.packet(PacketType::State, self.seq_nr, Bytes::new(), now));
+        }
+
+        self.send_pending(now, out);
+    }
+
+    fn process_ack(&mut self, packet: &Packet, now: Instant) {
+        let mut bytes_acked = 0;
+        let mut rtt_sample = None;
+
+        while let Some(front) = self.in_flight.front() {
+            if !seq_less_or_equal(front.seq_nr, packet.ack_nr) {
+                break;
+            }
+            // Karn's algorithm, retransmitted packets are ambiguous
+            if front.transmissions == 1 {
+                rtt_sample = Some(now.duration_since(front.sent_at));
+            }
+            bytes_acked += front.payload.len();
+            self.in_flight.pop_front();
+        }
+
+        let mut selectively_acked = 0;
+        for seq_nr in packet.selectively_acked() {
+            if let Some(position) = self
+                .in_flight
+                .iter()
+                .position(|in_flight| in_flight.seq_nr == seq_nr)
+            {
+                bytes_acked += self.in_flight[position].payload.len();
+                self.in_flight.remove(position);
+            }
+            selectively_acked += 1;
+        }
+
+        if let Some(fin_seq_nr) = self.fin_seq_nr {
+            if seq_less_or_equal(fin_seq_nr, packet.ack_nr) {
+                self.fin_acked = true;
+                self.wake_writer();
+            }
+        }
+
+        if bytes_acked > 0 {
+            if let Some(sample) = rtt_sample {
+                self.rtt.update(sample);
+            }
+            self.ledbat
+                .on_ack(bytes_acked, packet.timestamp_difference, now);
+            self.duplicate_acks = 0;
+        } else if packet.packet_type == PacketType::State
+            && packet.ack_nr == self.last_ack
+            && !self.in_flight.is_empty()
+        {
+            self.duplicate_acks += 1;
+        }
+        self.last_ack = packet.ack_nr;
+
+        // Packets after the first unacked one keep arriving, it's most likely lost
+        if self.duplicate_acks >= DUPLICATE_ACK_THRESHOLD
+            || selectively_acked >= DUPLICATE_ACK_THRESHOLD
+        {
+            if let Some(front) = self.in_flight.front_mut() {
+                if !front.resend && front.transmissions == 1 {
+                    front.resend = true;
+                    self.ledbat.on_loss();
+                }
+            }
+            self.duplicate_acks = 0;
+        }
+    }
+
+    fn receive(&mut self, packet: Packet) {
+        if seq_less_or_equal(packet.seq_nr, self.ack_nr)
+            || packet.seq_nr.wrapping_sub(self.ack_nr) > MAX_REORDER_DISTANCE
+            || self.eof
+        {
+            return;
+        }
+
+        self.reorder_buffer
+            .insert(packet.seq_nr, (packet.packet_type, packet.payload));
+
+        while let Some((packet_type, payload)) =
+            self.reorder_buffer.remove(&self.ack_nr.wrapping_add(1))
+        {
+            self.ack_nr = self.ack_nr.wrapping_add(1);
+            self.read_buffer.extend_from_slice(&payload);
+
+            if packet_type == PacketType::Fin {
+                self.eof = true;
+                self.reorder_buffer.clear();
+                break;
+            }
+        }
+
+        if !self.read_buffer.is_empty() || self.eof {
+            if let Some(waker) = self.read_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Sends lost packets and new data as far as the window allows
+    pub fn send_pending(&mut self, now: Instant, out: &mut Vec<Packet>) {
+        if self.state != State::Connected {
+            return;
+        }
+
+        let window = self.ledbat.window().min(self.remote_window);
+        let mut used: usize = self
+            .in_flight
+            .iter()
+            .filter(|in_flight| !in_flight.resend)
+            .map(|in_flight| in_flight.payload.len())
+            .sum();
+        // A single packet is always allowed, this also probes a closed remote window
+        let fits = |used: usize, len: usize| used == 0 || used + len <= window;
+
+        let mut resent = Vec::new();
+        for in_flight in self
+            .in_flight
+            .iter_mut()
+            .filter(|in_flight| in_flight.resend)
+        {
+            if !fits(used, in_flight.payload.len()) {
+                break;
+            }
+            in_flight.resend = false;
+            in_flight.transmissions += 1;
+            in_flight.sent_at = now;
+            used += in_flight.payload.len();
+            resent.push((
+                in_flight.packet_type,
+                in_flight.seq_nr,
+                in_flight.payload.clone(),
+            ));
+        }
+        for (packet_type, seq_nr, payload) in resent {
+            out.push(self.packet(packet_type, seq_nr, payload, now));
+        }
+
+        // Lost packets go first
+        if self.in_flight.iter().any(|in_flight| in_flight.resend) {
+            return;
+        }
+
+        let mut sent_data = false;
+        while !self.send_buffer.is_empty() && self.in_flight.len() < MAX_PACKETS_IN_FLIGHT {
+            let len = self.send_buffer.len().min(MAX_PAYLOAD);
+            if !fits(used, len) {
+                break;
+            }
+
+            let payload = self.send_buffer.split_to(len).freeze();
+            self.send_new(PacketType::Data, payload, now, out);
+            used += len;
+            sent_data = true;
+        }
+
+        if self.fin_queued && self.fin_seq_nr.is_none() && self.send_buffer.is_empty() {
+            self.fin_seq_nr = Some(self.seq_nr);
+            self.send_new(PacketType::Fin, Bytes::new(), now, out);
+        }
+
+        if sent_data {
+            self.wake_writer();
+        }
+    }
+
+    fn send_new(
+        &mut self,
+        packet_type: PacketType,
+        payload: Bytes,
+        now: Instant,
+        out: &mut Vec<Packet>,
+    ) {
+        out.push(self.packet(packet_type, self.seq_nr, payload.clone(), now));
+        self.in_flight.push_back(InFlight {
+            packet_type,
+            seq_nr: self.seq_nr,
+            payload,
+            sent_at: now,
+            transmissions: 1,
+            resend: false,
+        });
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+    }
+
+    /// Checks the retransmission timer and whether the remote peer went away
+    pub fn on_tick(&mut self, now: Instant, out: &mut Vec<Packet>) {
+        if self.state == State::Connected {
+            let idle = now.duration_since(self.last_received);
+            if idle >= IDLE_TIMEOUT {
+                out.push(self.packet(PacketType::Reset, self.seq_nr, Bytes::new(), now));
+                self.fail(io::ErrorKind::TimedOut);
+                return;
+            }
+            if idle >= KEEPALIVE_INTERVAL
+                && now.duration_since(self.last_keepalive) >= KEEPALIVE_INTERVAL
+            {
+                out.push(self.packet(PacketType::State, self.seq_nr, Bytes::new(), now));
+                self.last_keepalive = now;
+            }
+        }
+
+        let Some(oldest) = self
+            .in_flight
+            .iter()
+            .filter(|in_flight| !in_flight.resend)
+            .map(|in_flight| in_flight.sent_at)
+            .min()
+        else {
+            return;
+        };
+
+        if now.duration_since(oldest) < self.rtt.timeout() {
+            return;
+        }
+
+        let max_transmissions = if self.state == State::SynSent {
+            MAX_SYN_TRANSMISSIONS
+        } else {
+            MAX_TRANSMISSIONS
+        };
+        if self
+            .in_flight
+            .iter()
+            .any(|in_flight| in_flight.transmissions >= max_transmissions)
+        {
+            self.fail(io::ErrorKind::TimedOut);
+            return;
+        }
+
+        if self.state == State::SynSent {
+            let syn = &mut self.in_flight[0];
+            syn.transmissions += 1;
+            syn.sent_at = now;
+            let seq_nr = syn.seq_nr;
+            out.push(self.packet(PacketType::Syn, seq_nr, Bytes::new(), now));
+        } else {
+            for in_flight in &mut self.in_flight {
+                in_flight.resend = true;
+            }
+            self.ledbat.on_timeout();
+            self.send_pending(now, out);
+        }
+
+        self.rtt.backoff();
+    }
+
+    fn fail(&mut self, error: io::ErrorKind) {
+        self.state = State::Closed;
+        self.error = Some(error);
+        self.in_flight.clear();
+        self.wake_writer();
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn wake_writer(&mut self) {
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Whether the connection can be forgotten by the socket
+    pub fn is_finished(&self) -> bool {
+        self.state == State::Closed || self.dropped && self.fin_acked
+    }
+
+    pub fn poll_connected(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.state {
+            State::Connected => Poll::Ready(Ok(())),
+            State::Closed => Poll::Ready(Err(self.error())),
+            State::SynSent => {
+                self.write_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    pub fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if !self.read_buffer.is_empty() {
+            let len = self.read_buffer.len().min(buf.len());
+            buf[..len].copy_from_slice(&self.read_buffer.split_to(len));
+            return Poll::Ready(Ok(len));
+        }
+
+        if self.eof {
+            return Poll::Ready(Ok(0));
+        }
+
+        if self.error.is_some() {
+            return Poll::Ready(Err(self.error()));
+        }
+
+        self.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    pub fn poll_write(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        now: Instant,
+        out: &mut Vec<Packet>,
+    ) -> Poll<io::Result<usize>> {
+        if self.error.is_some() {
+            return Poll::Ready(Err(self.error()));
+        }
+
+        if self.fin_queued {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        let available = SEND_BUFFER_SIZE - self.send_buffer.len();
+        if available == 0 {
+            self.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let len = available.min(buf.len());
+        self.send_buffer.extend_from_slice(&buf[..len]);
+        self.send_pending(now, out);
+
+        Poll::Ready(Ok(len))
+    }
+
+    pub fn poll_shutdown(
+        &mut self,
+        cx: &mut Context<'_>,
+        now: Instant,
+        out: &mut Vec<Packet>,
+    ) -> Poll<io::Result<()>> {
+        if self.fin_acked {
+            return Poll::Ready(Ok(()));
+        }
+
+        if self.error.is_some() {
+            return Poll::Ready(Err(self.error()));
+        }
+
+        self.close(now, out);
+        self.write_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Queues a FIN after the remaining data
+    pub fn close(&mut self, now: Instant, out: &mut Vec<Packet>) {
+        if !self.fin_queued {
+            self.fin_queued = true;
+            self.send_pending(now, out);
+        }
+    }
+
+    fn error(&self) -> io::Error {
+        self.error.unwrap_or(io::ErrorKind::NotConnected).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_connections_time_out() {
+        let remote = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let now = Instant::now();
+        let syn = Packet::new(PacketType::Syn, 10);
+        let mut out = Vec::new();
+        let mut connection = Connection::accept(&syn, remote, now, now, &mut out);
+        out.clear();
+
+        connection.on_tick(now + KEEPALIVE_INTERVAL, &mut out);
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].packet_type, PacketType::State);
+        // Only one keep-alive per interval
+        let later = now + KEEPALIVE_INTERVAL * 2 - Duration::from_secs(1);
+        connection.on_tick(later, &mut out);
+        assert_eq!(out.len(), 1);
+
+        // Anything from the remote peer keeps it alive
+        connection.on_packet(Packet::new(PacketType::State, 11), later, &mut out);
+        out.clear();
+        connection.on_tick(now + IDLE_TIMEOUT, &mut out);
+        assert!(out.is_empty());
+        assert_eq!(connection.state, State::Connected);
+
+        connection.on_tick(later + IDLE_TIMEOUT, &mut out);
+        assert_eq!(out.last().unwrap().packet_type, PacketType::Reset);
+        assert_eq!(connection.state, State::Closed);
+        assert!(connection.is_finished());
+    }
+}
@@ -0,0 +1,536 @@
+//! uTorrent transport protocol, see [BEP 29](https://www.bittorrent.org/beps/bep_0029.html)
+//!
+//! A single [`UtpSocket`] owns the udp socket and demultiplexes packets to every [`UtpStream`] by connection id.
+
+mod congestion;
+mod connection;
+mod packet;
+
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{ToSocketAddrs, UdpSocket},
+    sync::{mpsc, Mutex as AsyncMutex},
+    task::JoinHandle,
+    time::interval,
+};
+use tracing::trace;
+
+use connection::{Connection, State};
+use packet::{Packet, PacketType};
+
+/// How often retransmission timers are checked
+const TICK_INTERVAL: Duration = Duration::from_millis(50);
+/// Incoming connections waiting to be accepted, new ones are reset once it's full
+const BACKLOG: usize = 32;
+
+type ConnectionKey = (SocketAddr, u16);
+
+struct Shared {
+    socket: UdpSocket,
+    /// Origin of the microsecond timestamps in the packet headers
+    epoch: Instant,
+    connections: Mutex<HashMap<ConnectionKey, Arc<Mutex<Connection>>>>,
+}
+
+impl Shared {
+    fn send(&self, packets: Vec<Packet>, remote: SocketAddr) {
+        for packet in packets {
+            // Packets that can't be sent right away are treated as lost and retransmitted later
+            if let Err(error) = self.socket.try_send_to(&packet.to_bytes(), remote) {
+                trace!("Dropped utp packet to {}: {}", remote, error);
+            }
+        }
+    }
+
+    fn dispatch(
+        &self,
+        datagram: &[u8],
+        remote: SocketAddr,
+        incoming: &mpsc::Sender<Arc<Mutex<Connection>>>,
+    ) {
+        let Ok(packet) = Packet::from_bytes(datagram) else {
+            return;
+        };
+        let now = Instant::now();
+
+        // A SYN carries the id the remote peer receives on, we receive on the next one
+        let recv_id = if packet.packet_type == PacketType::Syn {
+            packet.connection_id.wrapping_add(1)
+        } else {
+            packet.connection_id
+        };
+
+        let existing = self
+            .connections
+            .lock()
+            .unwrap()
+            .get(&(remote, recv_id))
+            .cloned();
+        let mut out = Vec::new();
+
+        match existing {
+            Some(connection) => connection.lock().unwrap().on_packet(packet, now, &mut out),
+            None if packet.packet_type == PacketType::Syn
+                && !incoming.is_closed()
+                && incoming.capacity() > 0 =>
+            {
+                let connection = Arc::new(Mutex::new(Connection::accept(
+                    &packet, remote, self.epoch, now, &mut out,
+                )));
+                self.connections
+                    .lock()
+                    .unwrap()
+                    .insert((remote, recv_id), connection.clone());
+                // Only this task sends, so there is still room
+                let _ = incoming.try_send(connection);
+            }
+            // Also resets SYNs while the backlog is full or nobody is accepting anymore
+            None if packet.packet_type != PacketType::Reset => {
+                out.push(Packet {
+                    ack_nr: packet.seq_nr,
+                    ..Packet::new(PacketType::Reset, packet.connection_id)
+                });
+            }
+            None => {}
+        }
+
+        self.send(out, remote);
+    }
+
+    fn tick(&self) {
+        let now = Instant::now();
+        let connections: Vec<_> = self.connections.lock().unwrap().values().cloned().collect();
+
+        for connection in connections {
+            let mut connection = connection.lock().unwrap();
+            let mut out = Vec::new();
+            connection.on_tick(now, &mut out);
+            self.send(out, connection.remote);
+
+            if connection.is_finished() {
+                self.connections
+                    .lock()
+                    .unwrap()
+                    .remove(&(connection.remote, connection.recv_id));
+            }
+        }
+    }
+}
+
+/// Stops the driver task once the socket and every stream are gone
+struct Driver(JoinHandle<()>);
+
+impl Drop for Driver {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+async fn drive(shared: Arc<Shared>, incoming: mpsc::Sender<Arc<Mutex<Connection>>>) {
+    let mut buffer = vec![0u8; 65535];
+    let mut ticker = interval(TICK_INTERVAL);
+
+    loop {
+        tokio::select! {
+            received = shared.socket.recv_from(&mut buffer) => match received {
+                Ok((len, remote)) => shared.dispatch(&buffer[..len], remote, &incoming),
+                // Icmp errors for a single remote peer show up here, they don't affect the other connections
+                Err(error) => trace!("Utp socket error: {}", error),
+            },
+            _ = ticker.tick() => shared.tick(),
+        }
+    }
+}
+
+/// A udp socket multiplexing any number of uTP connections
+pub struct UtpSocket {
+    shared: Arc<Shared>,
+    driver: Arc<Driver>,
+    incoming: AsyncMutex<mpsc::Receiver<Arc<Mutex<Connection>>>>,
+}
+
+impl UtpSocket {
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let shared = Arc::new(Shared {
+            socket: UdpSocket::bind(addr).await?,
+            epoch: Instant::now(),
+            connections: Mutex::new(HashMap::new()),
+        });
+        let (sender, receiver) = mpsc::channel(BACKLOG);
+        let driver = Arc::new(Driver(tokio::spawn(drive(shared.clone(), sender))));
+
+        Ok(Self {
+            shared,
+            driver,
+            incoming: AsyncMutex::new(receiver),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.socket.local_addr()
+    }
+
+    /// Opens a new connection to `remote`
+    pub async fn connect(&self, remote: SocketAddr) -> io::Result<UtpStream> {
+        let connection = {
+            let mut connections = self.shared.connections.lock().unwrap();
+
+            // The id we receive on and the one after it, used by the remote peer, must both be free
+            let recv_id = loop {
+                let id: u16 = rand::random();
+                if !connections.contains_key(&(remote, id))
+                    && !connections.contains_key(&(remote, id.wrapping_add(1)))
+                {
+                    break id;
+                }
+            };
+
+            let mut out = Vec::new();
+            let connection = Arc::new(Mutex::new(Connection::connect(
+                remote,
+                recv_id,
+                self.shared.epoch,
+                Instant::now(),
+                &mut out,
+            )));
+            connections.insert((remote, recv_id), connection.clone());
+            self.shared.send(out, remote);
+
+            connection
+        };
+
+        let stream = self.stream(connection);
+        std::future::poll_fn(|cx| stream.connection.lock().unwrap().poll_connected(cx)).await?;
+
+        Ok(stream)
+    }
+
+    /// Waits for the next incoming connection
+    pub async fn accept(&self) -> io::Result<UtpStream> {
+        let connection = self
+            .incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+
+        Ok(self.stream(connection))
+    }
+
+    fn stream(&self, connection: Arc<Mutex<Connection>>) -> UtpStream {
+        UtpStream {
+            connection,
+            shared: self.shared.clone(),
+            _driver: self.driver.clone(),
+        }
+    }
+}
+
+/// A reliable ordered byte stream over uTP
+pub struct UtpStream {
+    connection: Arc<Mutex<Connection>>,
+    shared: Arc<Shared>,
+    _driver: Arc<Driver>,
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.connection.lock().unwrap().remote
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connection.lock().unwrap().state == State::Connected
+    }
+
+    /// Runs `f` on the connection and sends whatever packets it produced
+    fn with_connection<T>(
+        &self,
+        f: impl FnOnce(&mut Connection, Instant, &mut Vec<Packet>) -> T,
+    ) -> T {
+        let mut connection = self.connection.lock().unwrap();
+        let mut out = Vec::new();
+        let result = f(&mut connection, Instant::now(), &mut out);
+        self.shared.send(out, connection.remote);
+        result
+    }
+}
+
+impl fmt::Debug for UtpStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UtpStream")
+            .field("peer_addr", &self.peer_addr())
+            .finish()
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut connection = self.connection.lock().unwrap();
+        let len = std::task::ready!(connection.poll_read(cx, buf.initialize_unfilled()))?;
+        buf.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        self.with_connection(|connection, now, out| connection.poll_write(cx, buf, now, out))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        // Everything written is already queued and will be retransmitted until acked
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.with_connection(|connection, now, out| connection.poll_shutdown(cx, now, out))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        self.with_connection(|connection, now, out| {
+            connection.dropped = true;
+            if connection.state == State::Connected {
+                connection.close(now, out);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use std::net::Ipv4Addr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn socket() -> UtpSocket {
+        UtpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap()
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    /// Forwards datagrams between a single client and `server`, dropping and delaying some of them
+    async fn lossy_link(server: SocketAddr, loss: f64, max_delay: Duration) -> SocketAddr {
+        let relay = Arc::new(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap());
+        let addr = relay.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut client = None;
+            let mut buffer = vec![0u8; 65535];
+
+            while let Ok((len, from)) = relay.recv_from(&mut buffer).await {
+                let to = if from == server {
+                    match client {
+                        Some(client) => client,
+                        None => continue,
+                    }
+                } else {
+                    client = Some(from);
+                    server
+                };
+
+                let (dropped, delay) = {
+                    let mut rng = rand::thread_rng();
+                    (
+                        rng.gen_bool(loss),
+                        rng.gen_range(Duration::ZERO..=max_delay),
+                    )
+                };
+                if dropped {
+                    continue;
+                }
+
+                // Random delays also reorder packets
+                let datagram = buffer[..len].to_vec();
+                let relay = relay.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let _ = relay.send_to(&datagram, to).await;
+                });
+            }
+        });
+
+        addr
+    }
+
+    #[tokio::test]
+    async fn loopback_transfer() {
+        let server = socket().await;
+        let client = socket().await;
+        let payload = data(200_000);
+
+        let server_addr = server.local_addr().unwrap();
+        let (accepted, connected) = tokio::join!(server.accept(), client.connect(server_addr));
+        let mut accepted = accepted.unwrap();
+        let mut connected = connected.unwrap();
+
+        let sent = payload.clone();
+        let writer = tokio::spawn(async move {
+            connected.write_all(&sent).await.unwrap();
+            connected.shutdown().await.unwrap();
+            connected
+        });
+
+        let mut received = Vec::new();
+        accepted.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, payload);
+
+        // The other direction still works after the first one is closed
+        accepted.write_all(b"bye").await.unwrap();
+        let mut connected = writer.await.unwrap();
+        let mut buffer = [0u8; 3];
+        connected.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"bye");
+    }
+
+    #[tokio::test]
+    async fn lossy_transfer() {
+        let server = socket().await;
+        let client = socket().await;
+        let payload = data(300_000);
+
+        let link = lossy_link(server.local_addr().unwrap(), 0.1, Duration::from_millis(20)).await;
+        let (accepted, connected) = tokio::join!(server.accept(), client.connect(link));
+        let mut accepted = accepted.unwrap();
+        let mut connected = connected.unwrap();
+
+        let sent = payload.clone();
+        tokio::spawn(async move {
+            connected.write_all(&sent).await.unwrap();
+            connected.shutdown().await.unwrap();
+        });
+
+        let mut received = Vec::new();
+        tokio::time::timeout(Duration::from_secs(60), accepted.read_to_end(&mut received))
+            .await
+            .expect("transfer timed out")
+            .unwrap();
+        assert_eq!(received.len(), payload.len());
+        assert!(received == payload);
+    }
+
+    #[tokio::test]
+    async fn shared_socket() {
+        let server = socket().await;
+        let client = socket().await;
+        let server_addr = server.local_addr().unwrap();
+
+        let mut streams = Vec::new();
+        for _ in 0..3 {
+            let (accepted, connected) = tokio::join!(server.accept(), client.connect(server_addr));
+            streams.push((accepted.unwrap(), connected.unwrap()));
+        }
+
+        for (index, (_, connected)) in streams.iter_mut().enumerate() {
+            connected.write_all(&[index as u8; 10]).await.unwrap();
+        }
+
+        for (index, (accepted, _)) in streams.iter_mut().enumerate() {
+            let mut buffer = [0u8; 10];
+            accepted.read_exact(&mut buffer).await.unwrap();
+            assert_eq!(buffer, [index as u8; 10]);
+        }
+    }
+
+    #[tokio::test]
+    async fn reset() {
+        let client = socket().await;
+        let remote = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let remote_addr = remote.local_addr().unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let mut buffer = [0u8; 1500];
+
+        // Packets for connections that don't exist are answered with a RESET
+        remote
+            .send_to(&Packet::new(PacketType::Data, 1234).to_bytes(), client_addr)
+            .await
+            .unwrap();
+        let (len, _) = remote.recv_from(&mut buffer).await.unwrap();
+        let packet = Packet::from_bytes(&buffer[..len]).unwrap();
+        assert_eq!(packet.packet_type, PacketType::Reset);
+
+        let connect = tokio::spawn(async move {
+            let stream = client.connect(remote_addr).await;
+            (client, stream)
+        });
+
+        let (len, _) = remote.recv_from(&mut buffer).await.unwrap();
+        let syn = Packet::from_bytes(&buffer[..len]).unwrap();
+        assert_eq!(syn.packet_type, PacketType::Syn);
+
+        let state = Packet {
+            seq_nr: 100,
+            ack_nr: syn.seq_nr,
+            ..Packet::new(PacketType::State, syn.connection_id)
+        };
+        remote
+            .send_to(&state.to_bytes(), client_addr)
+            .await
+            .unwrap();
+        let (_client, stream) = connect.await.unwrap();
+        let mut stream = stream.unwrap();
+        assert!(stream.is_connected());
+
+        remote
+            .send_to(
+                &Packet::new(PacketType::Reset, syn.connection_id).to_bytes(),
+                client_addr,
+            )
+            .await
+            .unwrap();
+        let error = stream.read(&mut buffer).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn full_backlog() {
+        let server = socket().await;
+        let remote = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let mut buffer = [0u8; 1500];
+
+        // Nobody accepts, so only the first connections are kept
+        for id in 0..=BACKLOG as u16 {
+            remote
+                .send_to(
+                    &Packet::new(PacketType::Syn, id * 2).to_bytes(),
+                    server_addr,
+                )
+                .await
+                .unwrap();
+            let (len, _) = remote.recv_from(&mut buffer).await.unwrap();
+            let reply = Packet::from_bytes(&buffer[..len]).unwrap();
+            let expected = if (id as usize) < BACKLOG {
+                PacketType::State
+            } else {
+                PacketType::Reset
+            };
+            assert_eq!(reply.packet_type, expected);
+        }
+
+        let stream = server.accept().await.unwrap();
+        assert_eq!(stream.peer_addr(), remote.local_addr().unwrap());
+    }
+}
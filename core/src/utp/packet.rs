@@ -0,0 +1,229 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use thiserror::Error;
+
+/// Size of the fixed packet header
+pub(crate) const HEADER_LEN: usize = 20;
+
+const VERSION: u8 = 1;
+const EXTENSION_SELECTIVE_ACK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PacketType {
+    /// Regular data packet
+    Data = 0,
+    /// Finalize the connection, this is the last packet
+    Fin = 1,
+    /// Acknowledges a packet without carrying data
+    State = 2,
+    /// Terminates the connection forcefully
+    Reset = 3,
+    /// Initiates a new connection
+    Syn = 4,
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = PacketError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(PacketType::Data),
+            1 => Ok(PacketType::Fin),
+            2 => Ok(PacketType::State),
+            3 => Ok(PacketType::Reset),
+            4 => Ok(PacketType::Syn),
+            x => Err(PacketError::Type(x)),
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub(crate) enum PacketError {
+    #[error("Packet is too short")]
+    TooShort,
+    #[error("Unsupported version {0}")]
+    Version(u8),
+    #[error("Unknown packet type {0}")]
+    Type(u8),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Packet {
+    pub packet_type: PacketType,
+    pub connection_id: u16,
+    /// Microseconds timestamp of when the packet was sent
+    pub timestamp: u32,
+    /// Difference between the local time and the timestamp of the last received packet
+    pub timestamp_difference: u32,
+    /// Bytes the sender is still willing to receive
+    pub window_size: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    /// Bitmask of received packets starting at ack_nr + 2, least significant bit first
+    pub selective_ack: Option<Vec<u8>>,
+    pub payload: Bytes,
+}
+
+impl Packet {
+    pub fn new(packet_type: PacketType, connection_id: u16) -> Self {
+        Self {
+            packet_type,
+            connection_id,
+            timestamp: 0,
+            timestamp_difference: 0,
+            window_size: 0,
+            seq_nr: 0,
+            ack_nr: 0,
+            selective_ack: None,
+            payload: Bytes::new(),
+        }
+    }
+
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self, PacketError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(PacketError::TooShort);
+        }
+
+        let type_version = bytes.get_u8();
+        if type_version & 0x0F != VERSION {
+            return Err(PacketError::Version(type_version & 0x0F));
+        }
+        let packet_type = PacketType::try_from(type_version >> 4)?;
+        let mut extension = bytes.get_u8();
+
+        let mut packet = Self {
+            packet_type,
+            connection_id: bytes.get_u16(),
+            timestamp: bytes.get_u32(),
+            timestamp_difference: bytes.get_u32(),
+            window_size: bytes.get_u32(),
+            seq_nr: bytes.get_u16(),
+            ack_nr: bytes.get_u16(),
+            selective_ack: None,
+            payload: Bytes::new(),
+        };
+
+        // Extensions are a linked list of (next extension, length, data)
+        while extension != 0 {
+            if bytes.len() < 2 {
+                return Err(PacketError::TooShort);
+            }
+            let next = bytes.get_u8();
+            let len = bytes.get_u8() as usize;
+            if bytes.len() < len {
+                return Err(PacketError::TooShort);
+            }
+            if extension == EXTENSION_SELECTIVE_ACK {
+                packet.selective_ack = Some(bytes[..len].to_vec());
+            }
+            bytes.advance(len);
+            extension = next;
+        }
+
+        packet.payload = Bytes::copy_from_slice(bytes);
+
+        Ok(packet)
+    }
+
+    pub fn len(&self) -> usize {
+        HEADER_LEN
+            + self.selective_ack.as_ref().map_or(0, |mask| mask.len() + 2)
+            + self.payload.len()
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        let mut bytes = BytesMut::with_capacity(self.len());
+
+        bytes.put_u8((self.packet_type as u8) << 4 | VERSION);
+        bytes.put_u8(if self.selective_ack.is_some() {
+            EXTENSION_SELECTIVE_ACK
+        } else {
+            0
+        });
+        bytes.put_u16(self.connection_id);
+        bytes.put_u32(self.timestamp);
+        bytes.put_u32(self.timestamp_difference);
+        bytes.put_u32(self.window_size);
+        bytes.put_u16(self.seq_nr);
+        bytes.put_u16(self.ack_nr);
+
+        if let Some(mask) = &self.selective_ack {
+            bytes.put_u8(0); // No further extensions
+            bytes.put_u8(mask.len() as u8);
+            bytes.extend_from_slice(mask);
+        }
+
+        bytes.extend_from_slice(&self.payload);
+        bytes.freeze()
+    }
+
+    /// Sequence numbers acknowledged by the selective ack extension
+    pub fn selectively_acked(&self) -> impl Iterator<Item = u16> + '_ {
+        let ack_nr = self.ack_nr;
+        self.selective_ack
+            .iter()
+            .flat_map(|mask| mask.iter().enumerate())
+            .flat_map(move |(index, byte)| {
+                (0..8)
+                    .filter(move |bit| byte & (1 << bit) != 0)
+                    .map(move |bit| ack_nr.wrapping_add(2 + (index * 8 + bit) as u16))
+            })
+    }
+}
+
+/// Compares two wrapping sequence numbers
+pub(crate) fn seq_less_than(a: u16, b: u16) -> bool {
+    a != b && b.wrapping_sub(a) < 0x8000
+}
+
+pub(crate) fn seq_less_or_equal(a: u16, b: u16) -> bool {
+    a == b || seq_less_than(a, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let packet = Packet {
+            packet_type: PacketType::Data,
+            connection_id: 4242,
+            timestamp: 123456,
+            timestamp_difference: 789,
+            window_size: 1 << 20,
+            seq_nr: 65535,
+            ack_nr: 12,
+            selective_ack: Some(vec![0b0000_0101, 0, 0, 0b1000_0000]),
+            payload: Bytes::from_static(b"payload"),
+        };
+
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes.len(), packet.len());
+        assert_eq!(bytes[0], 0x01);
+        assert_eq!(Packet::from_bytes(&bytes), Ok(packet.clone()));
+
+        assert_eq!(
+            packet.selectively_acked().collect::<Vec<_>>(),
+            vec![14, 16, 45]
+        );
+    }
+
+    #[test]
+    fn invalid_packets() {
+        assert_eq!(Packet::from_bytes(&[0; 10]), Err(PacketError::TooShort));
+
+        let mut bytes = Packet::new(PacketType::Syn, 1).to_bytes().to_vec();
+        bytes[0] = 0x42;
+        assert_eq!(Packet::from_bytes(&bytes), Err(PacketError::Version(2)));
+        bytes[0] = 0x71;
+        assert_eq!(Packet::from_bytes(&bytes), Err(PacketError::Type(7)));
+    }
+
+    #[test]
+    fn wrapping_sequence_numbers() {
+        assert!(seq_less_than(1, 2));
+        assert!(seq_less_than(65535, 0));
+        assert!(!seq_less_than(0, 65535));
+        assert!(seq_less_or_equal(7, 7));
+    }
+}
@@ -1,4 +1,4 @@
-use color_eyre::eyre::{bail, Result};
+use color_eyre::eyre::{bail, eyre, Result};
 use data_encoding::BASE32;
 use std::convert::TryInto;
 use url::Url;
@@ -29,22 +29,12 @@ pub fn parse(url: &str) -> Result<Magnet> {
         }
     }
 
-    let xt = xt.expect("Missing xt");
-    let mut xt: Vec<&str> = xt.split(':').collect();
-
-    dbg!(&xt);
-
-    if xt.len() == 2 {
-        bail!("Invalid magnet uri")
-    }
-
-    // This should never panic since we just checked the lenght
-    let info_hash = match xt.remove(1) {
-        "btih" => {
-            let btih = xt.remove(1);
-            parse_btih(btih)?
-        }
-        "btmh" => todo!(),
+    let Some(xt) = xt else {
+        bail!("Magnet uri without an exact topic")
+    };
+    let info_hash = match xt.split(':').collect::<Vec<&str>>()[..] {
+        ["urn", "btih", btih] => parse_btih(btih)?,
+        ["urn", "btmh", _] => bail!("Version 2 magnet uris aren't supported"),
         _ => bail!("Invalid magnet uri"),
     };
 
@@ -56,15 +46,39 @@ pub fn parse(url: &str) -> Result<Magnet> {
     })
 }
 
-// TODO: errors
+/// Info hashes are either 40 hex digits or 32 base32 characters
 fn parse_btih(btih: &str) -> Result<[u8; 20]> {
     let info_hash = if btih.len() == 40 {
         hex::decode(btih)?
-    } else if btih.len() == 30 {
-        BASE32.decode(btih.as_bytes())?
+    } else if btih.len() == 32 {
+        BASE32.decode(btih.to_ascii_uppercase().as_bytes())?
     } else {
-        bail!("Invalid magnet uri");
+        bail!("Invalid info hash in magnet uri");
     };
 
-    Ok(info_hash.try_into().unwrap())
+    info_hash
+        .try_into()
+        .map_err(|_| eyre!("Invalid info hash in magnet uri"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_info_hashes() {
+        let hex =
+            parse("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=file").unwrap();
+        let base32 = parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").unwrap();
+        assert_eq!(hex.info_hash, base32.info_hash);
+        assert_eq!(hex.display_name.as_deref(), Some("file"));
+    }
+
+    #[test]
+    fn invalid_uris_are_errors() {
+        assert!(parse("magnet:?dn=file").is_err());
+        assert!(parse("magnet:?xt=urn:btih").is_err());
+        assert!(parse("magnet:?xt=urn:btih:c12fe1c06bba").is_err());
+        assert!(parse("magnet:?xt=urn:btmh:1220caf1e1c30e81cb361b9ee167c4aa64228a7fa4fa9f6105232b28ad099f3a302e").is_err());
+    }
 }
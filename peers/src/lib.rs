@@ -81,3 +81,25 @@ pub fn peer_id(client_id: &[u8; 2], version: &[u8; 4]) -> [u8; 20] {
         array_utils::build_array([b"-", client_id, version, b"-", &rand::random::<[u8; 12]>()])
     }
 }
+
+/// Guesses the client from its peer id, using either the Azureus or the Shad0w convention
+pub fn client_name(peer_id: &[u8; 20]) -> Option<String> {
+    if peer_id[0] == b'-' && peer_id[7] == b'-' {
+        let client = std::str::from_utf8(&peer_id[1..3]).ok()?;
+        let name = STANDARD_PEERS.get(client)?;
+        let version: Vec<String> = peer_id[3..7]
+            .iter()
+            .map(|digit| (*digit as char).to_string())
+            .collect();
+        return Some(format!("{} {}", name, version.join(".")));
+    }
+
+    let client = std::str::from_utf8(&peer_id[..1]).ok()?;
+    let name = SHAD0W_PEERS.get(client)?;
+    let version: Vec<String> = peer_id[1..6]
+        .iter()
+        .take_while(|digit| **digit != b'-')
+        .map(|digit| (*digit as char).to_string())
+        .collect();
+    Some(format!("{} {}", name, version.join(".")))
+}
@@ -4,6 +4,12 @@ pub struct Torrent {
     info_hash: InfoHash,
 }
 
+impl Torrent {
+    pub const fn info_hash(&self) -> &InfoHash {
+        &self.info_hash
+    }
+}
+
 pub enum InfoHash {
     V1([u8; 20]),
     V2,
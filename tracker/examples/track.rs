@@ -1,6 +1,6 @@
 use color_eyre::eyre::Result;
 use form_urlencoded::byte_serialize;
-use hyper::{client::HttpConnector, Body, Method, Request as HttpRequest, Uri};
+use hyper::{body::Bytes, client::HttpConnector, Body, Method, Request as HttpRequest, Uri};
 use hyper_tls::HttpsConnector;
 use std::net::{Ipv4Addr, SocketAddr};
 use url::Url;
@@ -71,8 +71,48 @@ pub struct Tracker {
     http_client: HttpClient,
 }
 
+impl Tracker {
+    pub fn new() -> Self {
+        Self {
+            http_client: hyper::Client::builder().build(HttpsConnector::new()),
+        }
+    }
+
+    /// Sends the announce and returns the raw bencoded response
+    pub async fn announce(&self, url: &mut Url, request: &AnnounceRequest) -> Result<Bytes> {
+        let response = self
+            .http_client
+            .request(request.into_http_request(url))
+            .await?;
+        Ok(hyper::body::to_bytes(response.into_body()).await?)
+    }
+}
+
+impl Default for Tracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
+
+    // Announces a random torrent to the tracker given as the first argument
+    let Some(url) = std::env::args().nth(1) else {
+        return Ok(());
+    };
+    let request = AnnounceRequest {
+        info_hash: rand::random(),
+        peer_id: rand::random(),
+        ip: None,
+        port: 6881,
+        uploaded: 0,
+        downloaded: 0,
+        left: 0,
+    };
+    let response = Tracker::new().announce(&mut url.parse()?, &request).await?;
+    println!("{}", String::from_utf8_lossy(&response));
+
     Ok(())
 }
@@ -32,14 +32,29 @@ pub struct AnnounceRequest {
     pub numwant: Option<u16>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Event {
     Started,
     Completed,
     Stopped,
+    /// Sent by partial seeds, which have every piece they want but not the whole torrent
+    Paused,
     Empty, // Same as None
 }
 
+impl Event {
+    /// The value of the event parameter, `None` for regular announces
+    pub const fn as_str(&self) -> Option<&'static str> {
+        match self {
+            Event::Started => Some("started"),
+            Event::Completed => Some("completed"),
+            Event::Stopped => Some("stopped"),
+            Event::Paused => Some("paused"),
+            Event::Empty => None,
+        }
+    }
+}
+
 impl AnnounceRequest {
     pub fn into_http_request(&self, url: &mut Url) -> HttpRequest<Body> {
         // To send the info hash we first need to encode it in a http friendly format
@@ -64,6 +79,10 @@ impl AnnounceRequest {
             url_query.append_pair("numwant", &numwant.to_string());
         }
 
+        if let Some(event) = self.event.as_ref().and_then(Event::as_str) {
+            url_query.append_pair("event", event);
+        }
+
         let mut url = url_query.finish().to_string();
 
         // Manually add info hash and peer id
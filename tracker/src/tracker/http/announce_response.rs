@@ -13,6 +13,10 @@ use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV
 pub struct AnnounceResponse {
     pub interval: u64,
     pub peers: Vec<SocketAddr>,
+    /// Number of seeds in the swarm
+    pub complete: Option<u64>,
+    /// Number of peers in the swarm that are still downloading
+    pub incomplete: Option<u64>,
 }
 
 #[derive(Debug)]
@@ -27,7 +31,7 @@ fn parse_peers(value: Object) -> Result<Vec<SocketAddr>> {
     } else if value.is_byte_string() {
         parse_compact_peers_v4(unsafe { value.byte_string().unwrap_unchecked() })
     } else {
-        bail!("")
+        bail!("Peers are neither a list nor a compact string")
     }
 }
 
@@ -76,15 +80,21 @@ impl FromBencode for AnnounceResponse {
     {
         let mut interval = None;
         let mut peers = Vec::new();
+        let mut complete = None;
+        let mut incomplete = None;
 
         let mut dictionary_decoder = object.try_dictionary()?;
         while let Some((key, value)) = dictionary_decoder.next_pair()? {
             match key {
                 b"interval" => interval = value.decode()?,
-                b"peers" => peers.extend(parse_peers(value).unwrap()),
-                b"peers6" => {
-                    peers.extend(parse_compact_peers_v6(AsString::decode(value)?).unwrap())
-                }
+                b"complete" => complete = value.decode()?,
+                b"incomplete" => incomplete = value.decode()?,
+                // A malformed peer list fails the announce instead of taking the client down
+                b"peers" => peers.extend(parse_peers(value).map_err(|_| DecodingError::Unknown)?),
+                b"peers6" => peers.extend(
+                    parse_compact_peers_v6(AsString::decode(value)?)
+                        .map_err(|_| DecodingError::Unknown)?,
+                ),
                 _unknown_field => value.skip()?,
             }
         }
@@ -92,6 +102,8 @@ impl FromBencode for AnnounceResponse {
         Ok(Self {
             interval: interval.ok_or_else(|| DecodingError::missing_field("interval"))?,
             peers,
+            complete,
+            incomplete,
         })
     }
 }